@@ -0,0 +1,17 @@
+; The program mayo_bin runs when it isn't given one
+%include "screen.asm"
+
+start:
+    write_char ' ', CLEAR, 0
+
+    write_char 'H', BOLD, 0
+    write_char 'i', REGULAR, 1
+    write_char ' ', BOLD, 2
+    write_char 'w', REGULAR, 3
+    write_char 'o', BOLD, 4
+    write_char 'r', REGULAR, 5
+    write_char 'l', BOLD, 6
+    write_char 'd', REGULAR, 7
+    write_char '!', BOLD, 8
+
+    hlt
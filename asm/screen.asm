@@ -0,0 +1,14 @@
+; Helpers for the screen device mapped at SCREEN by machines/default.mayo.
+; Each word written to it is a command in the high byte and a character in the low byte
+.equ SCREEN, 0x3000
+
+; Commands
+.equ CLEAR, 0xFF
+.equ BOLD, 0x01
+.equ REGULAR, 0x02
+
+; Draws `char` at `position` (0 is the top left, 16 per row). Overwrites r1
+%macro write_char char, command, position
+    mov (%command << 8) | %char, r1
+    mov r1, &SCREEN + %position
+%endmacro
@@ -0,0 +1,186 @@
+; Routines every program ends up needing. Link asm/std.o (`mayo_asm -c asm/std.asm -o asm/std.o`) with a program
+; and declare the ones it uses with `.extern`. They follow docs/abi.md: push the arguments in order and the count,
+; then `cal`, and the result comes back in acc. Strings are zero terminated and lengths are in bytes
+%include "screen.asm"
+
+.global memcpy, memset, strlen, utoa
+.global umulh, udiv, umod
+.global print_at
+
+; Loads the byte at the address in `address` into `to`. Overwrites r8
+%macro load_byte address, to
+    mov &%address, %to
+    mov $0008, r8
+    rsf %to, r8
+    mov acc, %to
+%endmacro
+
+; Stores the low byte of `value` at the address in `address`, keeping the byte after it. Overwrites r7 and r8
+%macro store_byte address, value
+    mov &%address, r7
+    mov $00FF, r8
+    and r7, r8
+    mov acc, r7
+    mov $0008, r8
+    lsf %value, r8
+    or acc, r7
+    mov acc, &%address
+%endmacro
+
+; memcpy(dest, src, n): copies n bytes from src to dest, first to last, and returns dest
+memcpy:
+    arg $0000, r1
+    arg $0001, r2
+    arg $0002, r3
+    mcpy r2, r1, r3
+    arg $0000, acc
+    ret
+
+; memset(dest, byte, n): sets n bytes from dest to byte and returns dest
+memset:
+    arg $0000, r1
+    arg $0001, r2
+    arg $0002, r3
+    mset r2, r1, r3
+    arg $0000, acc
+    ret
+
+; strlen(string): the number of bytes before the terminating zero
+strlen:
+    arg $0000, r1
+    mov $0000, r3
+    mov $0001, r6
+strlen.loop:
+    load_byte r1, r4
+    mov r4, acc
+    jeq $0000, &strlen.done
+    add r1, r6
+    mov acc, r1
+    add r3, r6
+    mov acc, r3
+    jmp &strlen.loop
+strlen.done:
+    mov r3, acc
+    ret
+
+; utoa(value, buffer): writes value in decimal to buffer as a string (at most 6 bytes) and returns its length
+utoa:
+    arg $0000, r1
+    arg $0001, r2
+    mov $0000, r3
+    mov $0001, r6
+    ; The digits come out last first, so they're pushed and then popped into the buffer
+utoa.digit:
+    div r1, $000A, r5
+    mov acc, r1
+    mov '0', r7
+    add r5, r7
+    psh acc
+    add r3, r6
+    mov acc, r3
+    mov r1, acc
+    jne $0000, &utoa.digit
+
+    mov r3, r5
+utoa.store:
+    pop r4
+    store_byte r2, r4
+    add r2, r6
+    mov acc, r2
+    sub r5, r6
+    mov acc, r5
+    jne $0000, &utoa.store
+    mov $0000, r4
+    store_byte r2, r4
+    mov r3, acc
+    ret
+
+; umulh(a, b): the high word of the 32-bit product a * b, the `mul` instruction gives the low word
+umulh:
+    arg $0000, r1
+    arg $0001, r2
+    mov $0000, r3
+    mov $0000, r4
+    mov $0010, r5
+    mov $0001, r6
+    mov $000F, r7
+    ; Shift and add from the top bit of b down, with the product in r3:r4
+umulh.bit:
+    lsf r3, r6
+    mov acc, r3
+    rsf r4, r7
+    or acc, r3
+    mov acc, r3
+    lsf r4, r6
+    mov acc, r4
+    rsf r2, r7
+    jeq $0000, &umulh.next
+    add r4, r1
+    mov acc, r4
+    ; Carry into the high word when the low word wrapped around
+    jge r1, &umulh.next
+    add r3, r6
+    mov acc, r3
+umulh.next:
+    lsf r2, r6
+    mov acc, r2
+    sub r5, r6
+    mov acc, r5
+    jne $0000, &umulh.bit
+    mov r3, acc
+    ret
+
+; udiv(a, b): a / b rounded down, 0xFFFF when b is 0 rather than the divide by zero fault `div` gives
+udiv:
+    arg $0000, r1
+    arg $0001, r2
+    mov r2, acc
+    jeq $0000, &udiv.by_zero
+    div r1, r2, r3
+    ret
+udiv.by_zero:
+    mov $FFFF, acc
+    ret
+
+; umod(a, b): the remainder of a / b, a when b is 0
+umod:
+    arg $0000, r1
+    arg $0001, r2
+    mov r2, acc
+    jeq $0000, &umod.by_zero
+    mod r1, r2
+    ret
+umod.by_zero:
+    mov r1, acc
+    ret
+
+; print_at(string, position, command): draws string on the screen from position on (16 per row), each character
+; sent with command (like BOLD or REGULAR). Returns the number of characters drawn
+print_at:
+    arg $0000, r1
+    arg $0001, r2
+    mov SCREEN, r3
+    add r2, r3
+    mov acc, r2
+    arg $0002, r3
+    mov $0008, r8
+    lsf r3, r8
+    mov acc, r3
+    mov $0000, r5
+    mov $0001, r6
+print_at.loop:
+    load_byte r1, r4
+    mov r4, acc
+    jeq $0000, &print_at.done
+    or r3, r4
+    mov acc, &r2
+    add r1, r6
+    mov acc, r1
+    add r2, r6
+    mov acc, r2
+    add r5, r6
+    mov acc, r5
+    jmp &print_at.loop
+print_at.done:
+    mov r5, acc
+    ret
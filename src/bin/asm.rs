@@ -0,0 +1,78 @@
+use std::{env, fs};
+use std::path::PathBuf;
+use mayo_lib::assembler::{AssembleError, Assembler};
+use mayo_lib::assembler::expression::{evaluate, EvalError};
+
+const USAGE: &str = "usage: mayo_asm [-c] [-D NAME[=value]]... [-o program.bin] [--symbols program.sym] [--debug program.dbg] program.asm\n\
+    -c  write an object for mayo_ld instead of a program";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    std::process::exit(1);
+}
+
+fn exit_with(error: AssembleError) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut assembler = Assembler::new();
+    let mut output = None;
+    let mut symbols = None;
+    let mut debug = None;
+    let mut input = None;
+    let mut object = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-D" => {
+                let define = args.next()
+                    .unwrap_or_else(|| fail("-D needs a value"));
+                let (name, value) = define.split_once('=').unwrap_or((&define, "1"));
+                let value = evaluate(value, &|n| Err(EvalError::Undefined(n.to_string())))
+                    .unwrap_or_else(|_| fail(&format!("invalid value for {}", name)));
+                assembler.define(name, value);
+            }
+            "-c" => object = true,
+            "-o" => output = Some(args.next().unwrap_or_else(|| fail("-o needs a value"))),
+            "--symbols" => symbols = Some(args.next().unwrap_or_else(|| fail("--symbols needs a value"))),
+            "--debug" => debug = Some(args.next().unwrap_or_else(|| fail("--debug needs a value"))),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => fail("only one input file can be assembled at a time"),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| fail("no input file"));
+    let output = output.map_or_else(|| input.with_extension(if object { "o" } else { "bin" }), PathBuf::from);
+
+    if object {
+        if symbols.is_some() || debug.is_some() {
+            fail("objects carry their symbols and debug info to mayo_ld");
+        }
+        let object = assembler.assemble_object(&input)
+            .unwrap_or_else(|e| exit_with(e));
+        object.save(&output)
+            .expect("write object");
+        return;
+    }
+
+    let assembly = assembler.assemble_file(&input)
+        .unwrap_or_else(|e| exit_with(e));
+
+    fs::write(&output, &assembly.bytes)
+        .expect("write program");
+    if let Some(path) = symbols {
+        fs::write(path, assembly.debug_info.symbols.to_string())
+            .expect("write symbols");
+    }
+    if let Some(path) = debug {
+        fs::write(path, assembly.debug_info.to_string())
+            .expect("write debug info");
+    }
+}
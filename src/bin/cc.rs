@@ -0,0 +1,42 @@
+use std::{env, fs};
+use std::path::PathBuf;
+use mayo_lib::compiler::compile;
+
+const USAGE: &str = "usage: mayo_cc [-o program.asm] program.mc";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    std::process::exit(1);
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut output = None;
+    let mut input = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().unwrap_or_else(|| fail("-o needs a value"))),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => fail("only one input file can be compiled at a time"),
+        }
+    }
+
+    let input = input.unwrap_or_else(|| fail("no input file"));
+    let output = output.map_or_else(|| input.with_extension("asm"), PathBuf::from);
+
+    let source = fs::read_to_string(&input)
+        .expect("read source");
+    let assembly = compile(&source)
+        .unwrap_or_else(|e| {
+            eprintln!("{}:{}: {}", input.display(), e.line, e.message);
+            std::process::exit(1);
+        });
+
+    fs::write(&output, assembly)
+        .expect("write assembly");
+}
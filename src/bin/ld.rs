@@ -0,0 +1,68 @@
+use std::env;
+use mayo_lib::linker::{LinkerScript, Linker};
+use mayo_lib::machine::MachineConfig;
+use mayo_lib::object::Object;
+
+const USAGE: &str = "usage: mayo_ld [--script layout.ld] [--machine machine.mayo] [-o program.bin] [--symbols program.sym] object.o...";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    std::process::exit(1);
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut linker = Linker::new();
+    let mut output = "a.bin".to_string();
+    let mut symbols = None;
+    let mut objects = 0;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next()
+            .unwrap_or_else(|| fail(&format!("{} needs a value", name)));
+
+        match arg.as_str() {
+            "--script" => {
+                let script = LinkerScript::from_file(value("--script"))
+                    .unwrap_or_else(|e| fail(&e.to_string()));
+                linker.set_script(script);
+            }
+            "--machine" => {
+                let config = MachineConfig::from_file(value("--machine"))
+                    .expect("load machine config");
+                linker.set_machine(&config);
+            }
+            "-o" => output = value("-o"),
+            "--symbols" => symbols = Some(value("--symbols")),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => {
+                let object = Object::load(&arg)
+                    .unwrap_or_else(|e| fail(&format!("{}: {}", arg, e)));
+                linker.add_object(object);
+                objects += 1;
+            }
+        }
+    }
+
+    if objects == 0 {
+        fail("no objects to link");
+    }
+
+    let linked = match linker.link() {
+        Ok(linked) => linked,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    linked.executable.save(&output)
+        .expect("write program");
+    if let Some(path) = symbols {
+        std::fs::write(path, linked.debug_info.symbols.to_string())
+            .expect("write symbols");
+    }
+}
@@ -1,9 +1,21 @@
+use std::{env, fs, io};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::BufWriter;
+use std::rc::Rc;
+use mayo_lib::coverage::Coverage;
 use mayo_lib::cpu::CPU;
 use mayo_lib::cpu::instructions::*;
-use mayo_lib::create_memory::create_memory;
-use mayo_lib::devices::memory::Memory;
+use mayo_lib::cpu::snapshot::Snapshot;
+use mayo_lib::cpu::trace::{BinaryTracer, Tee, TextTracer, Tracer};
 use mayo_lib::devices::memory_mapper::MemoryMapper;
-use mayo_lib::devices::screen_device::ScreenDevice;
+use mayo_lib::debug_info::DebugInfo;
+use mayo_lib::debugger::{Debugger, HISTORY_CAPACITY};
+use mayo_lib::gdb_stub;
+use mayo_lib::machine::{DEFAULT_MACHINE, MachineBuilder, MachineConfig};
+use mayo_lib::object::{Executable, Segment};
+use mayo_lib::profiler::Profiler;
+use mayo_lib::symbols::SymbolTable;
 
 /*const IP: u8  = 0;
 const ACC: u8 = 1;
@@ -19,13 +31,23 @@ const SP: u8 = 10;
 const FP: u8 = 11;*/
 const R1: u8  = 2;
 
-fn main() {
-    let mut memory = create_memory(256*256);
+const USAGE: &str = "usage: mayo_bin [run|debug|gdb|profile|coverage] [--trace log.txt] [--trace-bin trace.bin] [--restore snapshot] [--stats] [--symbols program.sym] [--debug program.dbg] [--folded stacks.folded] [machine.mayo] [program.bin]";
+
+const GDB_ADDRESS: &str = "127.0.0.1:1234";
+
+enum Mode {
+    Run,
+    Debug,
+    Gdb,
+    Profile,
+    Coverage,
+}
+
+fn demo_program() -> Vec<u8> {
+    let mut program = vec![];
 
-    let mut i = 0;
     let mut add = |n: u8| {
-        memory[i] = n;
-        i += 1;
+        program.push(n);
     };
 
     let mut write_char = |char: char, command: u8, pos: u8| {
@@ -43,7 +65,7 @@ fn main() {
     // Clear screen
     write_char(' ', 0xFF, 0);
 
-    for (i, char) in "Hi world!".chars().into_iter().enumerate() {
+    for (i, char) in "Hi world!".chars().enumerate() {
         let command = if i % 2 == 0 {
             0x01
         } else {
@@ -55,16 +77,193 @@ fn main() {
 
     add(HLT);
 
+    program
+}
+
+// Removes `name value` from args, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == name)?;
+    args.remove(i);
+    if i < args.len() {
+        Some(args.remove(i))
+    } else {
+        eprintln!("{} needs a value\n{}", name, USAGE);
+        std::process::exit(1);
+    }
+}
+
+// Runs until the program stops, printing a backtrace if it faults
+fn run(cpu: &mut CPU<MemoryMapper>) -> bool {
+    match cpu.run() {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("fault in {}: {:?}", cpu.describe_address(cpu.instruction_address()), e);
+            eprint!("{}", cpu.backtrace().format_with(&|address| cpu.describe_address(address)));
+            false
+        }
+    }
+}
+
+// Profiling and coverage see every entry alongside any trace being written
+fn add_tracer(cpu: &mut CPU<MemoryMapper>, tracer: Box<dyn Tracer>) {
+    let tracer = match cpu.take_tracer() {
+        Some(trace) => Box::new(Tee::new(trace, tracer)),
+        None => tracer,
+    };
+    cpu.set_tracer(tracer);
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let text_trace = take_option(&mut args, "--trace");
+    let binary_trace = take_option(&mut args, "--trace-bin");
+    let restore = take_option(&mut args, "--restore");
+    let symbols = take_option(&mut args, "--symbols")
+        .map(|path| SymbolTable::from_file(path).expect("load symbols"));
+    let debug = take_option(&mut args, "--debug")
+        .map(|path| DebugInfo::from_file(path).expect("load debug info"));
+    let folded = take_option(&mut args, "--folded");
+    let stats = match args.iter().position(|a| a == "--stats") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+
+    let mode = match args.first().map(String::as_str) {
+        Some("run") => Mode::Run,
+        Some("debug") => Mode::Debug,
+        Some("gdb") => Mode::Gdb,
+        Some("profile") => Mode::Profile,
+        Some("coverage") => Mode::Coverage,
+        Some("-h" | "--help") => {
+            println!("{}", USAGE);
+            return;
+        }
+        _ => {
+            args.insert(0, "run".to_string());
+            Mode::Run
+        }
+    };
+    args.remove(0);
+
+    if args.len() > 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    }
+
+    let config = match args.first() {
+        Some(path) => MachineConfig::from_file(path),
+        None => DEFAULT_MACHINE.parse(),
+    }.expect("load machine config");
+
+    let program = match args.get(1) {
+        Some(path) => fs::read(path).expect("read program"),
+        None => demo_program(),
+    };
+
+    // Linked executables say where each segment goes, anything else is loaded at 0
+    let executable = match Executable::is_executable(&program) {
+        true => Executable::from_bytes(&program)
+            .expect("load executable"),
+        false => Executable {
+            segments: vec![Segment { address: 0, bytes: program }],
+            debug_info: None,
+        },
+    };
+
+    // --symbols replaces the symbols in the debug info, which otherwise come from --debug or the executable
+    let mut debug_info = debug.or_else(|| executable.debug_info.clone());
+    if let Some(symbols) = symbols {
+        debug_info.get_or_insert_with(DebugInfo::new).symbols = symbols;
+    }
+    let symbols = debug_info.as_ref().map(|info| info.symbols.clone());
 
-    let memory = Box::new(Memory::from_vec(memory));
+    let mut builder = MachineBuilder::new(config);
+    for segment in &executable.segments {
+        builder = builder.load(segment.address as usize, &segment.bytes);
+    }
+    let mut cpu = builder.build_cpu()
+        .expect("build machine");
+    cpu.set_debug_info(debug_info.clone());
+
+    if let Some(path) = restore {
+        let snapshot = Snapshot::load(path)
+            .expect("load snapshot");
+        cpu.restore(&snapshot)
+            .expect("snapshot doesn't match the machine");
+    }
+
+    if let Some(path) = text_trace {
+        let file = BufWriter::new(File::create(path).expect("create trace file"));
+        match debug_info.clone() {
+            Some(info) => cpu.set_tracer(Box::new(TextTracer::with_debug_info(file, info))),
+            None => cpu.set_tracer(Box::new(TextTracer::new(file))),
+        }
+    } else if let Some(path) = binary_trace {
+        let file = BufWriter::new(File::create(path).expect("create trace file"));
+        cpu.set_tracer(Box::new(BinaryTracer::new(file)));
+    }
 
-    let mut mm = MemoryMapper::new();
-    mm.map(memory, 0, 0xFFFF, true);
+    let ok = match mode {
+        Mode::Run => {
+            let ok = run(&mut cpu);
 
-    let screen_device = Box::new(ScreenDevice::new());
-    mm.map(screen_device, 0x3000, 0x30FF, true);
+            if stats {
+                eprintln!("{} instructions, {} cycles", cpu.instructions_executed(), cpu.cycles());
+            }
+            ok
+        }
+        Mode::Debug => {
+            let mut debugger = Debugger::new(cpu);
+            debugger.run(io::stdin().lock());
+            cpu = debugger.into_cpu();
+            true
+        }
+        Mode::Gdb => {
+            cpu.enable_history(HISTORY_CAPACITY);
+            println!("waiting for gdb on {} (target remote {})", GDB_ADDRESS, GDB_ADDRESS);
+            gdb_stub::listen(&mut cpu, GDB_ADDRESS)
+                .expect("serve gdb");
+            true
+        }
+        Mode::Profile => {
+            let profiler = Rc::new(RefCell::new(Profiler::new()));
+            add_tracer(&mut cpu, Box::new(profiler.clone()));
+            let ok = run(&mut cpu);
 
-    let mut cpu = CPU::new(mm);
+            let profiler = profiler.borrow();
+            println!("{}", profiler.report(symbols.as_ref()));
+            if let Some(path) = folded {
+                fs::write(path, profiler.folded(symbols.as_ref()))
+                    .expect("write folded stacks");
+            }
+            ok
+        }
+        Mode::Coverage => {
+            let coverage = Rc::new(RefCell::new(Coverage::new()));
+            add_tracer(&mut cpu, Box::new(coverage.clone()));
+            let ok = run(&mut cpu);
 
-    cpu.run();
+            for segment in &executable.segments {
+                println!("{}", coverage.borrow().report(&segment.bytes, segment.address, debug_info.as_ref()));
+            }
+            ok
+        }
+    };
+
+    // A trace that couldn't be written doesn't stop the run, but it's still a failure
+    let trace_written = match cpu.take_tracer().map(|mut tracer| tracer.finish()) {
+        Some(Err(e)) => {
+            eprintln!("can't write the trace: {}", e);
+            false
+        }
+        _ => true,
+    };
+
+    if !ok || !trace_written {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,237 @@
+// Integer expressions in operands and directives: numbers (`42`, `0x2A`, `0b101010`, `'*'`), names of labels and
+// constants, parentheses and C's unary and binary operators
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EvalError {
+    // A name that isn't defined (yet)
+    Undefined(String),
+    Invalid(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+// Longest first so `<<` isn't read as two `<`
+const OPERATORS: &[&str] = &[
+    "<<", ">>", "==", "!=", "<=", ">=", "&&", "||",
+    "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "<", ">",
+];
+
+pub fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+pub fn is_name(text: &str) -> bool {
+    text.starts_with(is_name_start) && text.chars().all(is_name_char)
+}
+
+pub fn parse_char(text: &str) -> Option<(char, usize)> {
+    let mut chars = text.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            c @ ('\\' | '\'' | '"') => c,
+            _ => return None,
+        },
+        c => c,
+    };
+    Some((c, text.len() - chars.as_str().len()))
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, EvalError> {
+    let mut tokens = vec![];
+    let mut rest = text.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            let number = parse_number(&rest[..end])
+                .ok_or_else(|| EvalError::Invalid(format!("invalid number `{}`", &rest[..end])))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if is_name_start(c) {
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if c == '\'' {
+            let (value, len) = parse_char(&rest[1..])
+                .filter(|(_, len)| rest[1 + len..].starts_with('\''))
+                .ok_or_else(|| EvalError::Invalid(format!("invalid character literal in `{}`", text)))?;
+            tokens.push(Token::Number(value as i64));
+            rest = &rest[len + 2..];
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            rest = &rest[1..];
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            rest = &rest[1..];
+        } else {
+            let operator = OPERATORS.iter().find(|o| rest.starts_with(**o))
+                .ok_or_else(|| EvalError::Invalid(format!("unexpected `{}` in `{}`", c, text)))?;
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len()..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn precedence(operator: &str) -> u8 {
+    match operator {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        _ => 10,
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    lookup: &'a dyn Fn(&str) -> Result<i64, EvalError>,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn primary(&mut self) -> Result<i64, EvalError> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Name(name)) => (self.lookup)(&name),
+            Some(Token::Open) => {
+                let value = self.expression(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    _ => Err(EvalError::Invalid("missing `)`".to_string())),
+                }
+            }
+            Some(Token::Operator("-")) => Ok(self.primary()?.wrapping_neg()),
+            Some(Token::Operator("+")) => self.primary(),
+            Some(Token::Operator("~")) => Ok(!self.primary()?),
+            Some(Token::Operator("!")) => Ok((self.primary()? == 0) as i64),
+            Some(token) => Err(EvalError::Invalid(format!("unexpected {:?}", token))),
+            None => Err(EvalError::Invalid("expression ends too early".to_string())),
+        }
+    }
+
+    // Precedence climbing, all binary operators are left associative
+    fn expression(&mut self, min_precedence: u8) -> Result<i64, EvalError> {
+        let mut left = self.primary()?;
+
+        while let Some(Token::Operator(operator)) = self.peek() {
+            let operator = *operator;
+            let precedence = precedence(operator);
+            if precedence <= min_precedence {
+                break;
+            }
+            self.position += 1;
+
+            let right = self.expression(precedence)?;
+            left = match operator {
+                "||" => (left != 0 || right != 0) as i64,
+                "&&" => (left != 0 && right != 0) as i64,
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                ">" => (left > right) as i64,
+                "<=" => (left <= right) as i64,
+                ">=" => (left >= right) as i64,
+                "<<" => left.checked_shl(right as u32).unwrap_or(0),
+                ">>" => left.checked_shr(right as u32).unwrap_or(0),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                "/" | "%" if right == 0 => return Err(EvalError::Invalid("division by zero".to_string())),
+                "/" => left.wrapping_div(right),
+                "%" => left.wrapping_rem(right),
+                _ => return Err(EvalError::Invalid(format!("`{}` isn't a binary operator", operator))),
+            };
+        }
+
+        Ok(left)
+    }
+}
+
+pub fn evaluate(text: &str, lookup: &dyn Fn(&str) -> Result<i64, EvalError>) -> Result<i64, EvalError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+        lookup,
+    };
+
+    let value = parser.expression(0)?;
+    if let Some(token) = parser.next() {
+        return Err(EvalError::Invalid(format!("unexpected {:?} in `{}`", token, text)));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::expression::{evaluate, EvalError};
+
+    #[test]
+    fn expressions() {
+        let lookup = |name: &str| match name {
+            "SCREEN" => Ok(0x3000),
+            _ => Err(EvalError::Undefined(name.to_string())),
+        };
+        let eval = |text| evaluate(text, &lookup);
+
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("SCREEN + 0x10"), Ok(0x3010));
+        assert_eq!(eval("0b1010 | 1 << 4"), Ok(0x1A));
+        assert_eq!(eval("10 - 2 - 3"), Ok(5));
+        assert_eq!(eval("-1 & 0xFF"), Ok(0xFF));
+        assert_eq!(eval("'A' + 1"), Ok(66));
+        assert_eq!(eval("'\\n'"), Ok(10));
+        assert_eq!(eval("SCREEN == 0x3000 && !0"), Ok(1));
+        assert_eq!(eval("MISSING + 1"), Err(EvalError::Undefined("MISSING".to_string())));
+        assert!(matches!(eval("1 +"), Err(EvalError::Invalid(_))));
+        assert!(matches!(eval("(1"), Err(EvalError::Invalid(_))));
+        assert!(matches!(eval("1 / 0"), Err(EvalError::Invalid(_))));
+    }
+}
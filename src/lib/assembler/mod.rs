@@ -0,0 +1,983 @@
+// Turns source like `mov $1234, r1` (the syntax the disassembler prints) into a program for the CPU.
+// Statements are one per line, optionally after a `label:`. Comments start with `;`.
+// Besides instructions there are data directives: `.byte 1, "text"`, `.word TABLE + 2` (big endian like the CPU
+// reads them), `.ascii "text"`, `.asciz "text"` (followed by a zero byte), `.fill count[, byte]` and `.org offset`.
+// Code and data go in sections, `.text` unless `.section name` says otherwise, which the linker places. Labels can be
+// made visible to other objects with `.global name` and ones from other objects used after `.extern name`
+pub mod expression;
+pub mod preprocessor;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use crate::assembler::expression::{evaluate, is_name, is_name_char, is_name_start, parse_char, EvalError};
+use crate::assembler::preprocessor::{Preprocessor, SourceLine};
+use crate::cpu::instructions::{InstructionInfo, Operand, INSTRUCTIONS};
+use crate::cpu::register::Register;
+use crate::debug_info::{DebugInfo, LineInfo};
+use crate::linker::{LinkError, Linker};
+use crate::object::{self, Object, Relocation, RelocationSize, RelocationTarget};
+
+pub const DEFAULT_SECTION: &str = ".text";
+
+// How far each section or extern is moved to see whether an expression moves with it, see Evaluator
+const SHIFTS: [i64; 2] = [1, 0x10000];
+
+#[derive(Debug)]
+pub enum AssembleError {
+    Io(PathBuf, io::Error),
+    Syntax { file: PathBuf, line: usize, message: String },
+    // Only from assembling straight to a program, when the one object can't be linked on its own
+    Link(LinkError),
+}
+
+impl std::fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssembleError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            AssembleError::Syntax { file, line, message } => write!(f, "{}:{}: {}", file.display(), line, message),
+            AssembleError::Link(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+pub struct Assembly {
+    pub bytes: Vec<u8>,
+    // Every label and statement and the address it ended up at
+    pub debug_info: DebugInfo,
+}
+
+enum Data {
+    String(Vec<u8>),
+    Value(String),
+}
+
+enum Item {
+    Instruction(&'static InstructionInfo, Vec<String>),
+    Bytes(Vec<Data>),
+    Words(Vec<String>),
+    Fill(usize, String),
+}
+
+impl Item {
+    fn size(&self) -> usize {
+        match self {
+            Item::Instruction(info, _) => info.size(),
+            Item::Bytes(data) => data.iter()
+                .map(|d| match d {
+                    Data::String(bytes) => bytes.len(),
+                    Data::Value(_) => 1,
+                })
+                .sum(),
+            Item::Words(values) => values.len() * 2,
+            Item::Fill(count, _) => *count,
+        }
+    }
+}
+
+struct Statement {
+    source: SourceLine,
+    section: usize,
+    // Offset in the section
+    address: usize,
+    item: Item,
+}
+
+struct Equ {
+    name: String,
+    value: String,
+    source: SourceLine,
+}
+
+// Every name pass one found
+#[derive(Default)]
+struct Names {
+    sections: Vec<String>,
+    // The section and offset of each label
+    labels: HashMap<String, (usize, u16)>,
+    externs: Vec<String>,
+    equs: Vec<Equ>,
+}
+
+impl Names {
+    fn contains(&self, name: &str) -> bool {
+        self.labels.contains_key(name) || self.externs.iter().any(|e| e == name) || self.equs.iter().any(|e| e.name == name)
+    }
+
+    // The value of every name if each section and then each extern was at `shifts[i]`, or 0 past its end
+    fn values(&self, defines: &HashMap<String, i64>, shifts: &[i64]) -> (HashMap<String, i64>, Option<AssembleError>) {
+        let shift = |i: usize| shifts.get(i).copied().unwrap_or(0);
+
+        let mut values = defines.clone();
+        for (name, (section, offset)) in &self.labels {
+            values.insert(name.clone(), shift(*section) + *offset as i64);
+        }
+        for (i, name) in self.externs.iter().enumerate() {
+            values.insert(name.clone(), shift(self.sections.len() + i));
+        }
+
+        resolve_constants(values, &self.equs)
+    }
+}
+
+// A value an operand or `.word` ends up with, which may depend on where the linker puts things
+enum Value {
+    Absolute(i64),
+    Relocated(RelocationTarget, i64),
+}
+
+// Works out which section or extern (if any) an expression is relative to, by evaluating it with each one moved
+struct Evaluator {
+    values: HashMap<String, i64>,
+    // For each section then each extern, the values with it moved by each of SHIFTS
+    shifted: Vec<Vec<HashMap<String, i64>>>,
+    sections: usize,
+    externs: Vec<String>,
+}
+
+impl Evaluator {
+    fn new(names: &Names, defines: &HashMap<String, i64>) -> Result<Self, AssembleError> {
+        let values = match names.values(defines, &[]) {
+            (_, Some(e)) => return Err(e),
+            (values, None) => values,
+        };
+
+        let bases = names.sections.len() + names.externs.len();
+        let shifted = (0..bases)
+            .map(|base| SHIFTS.iter()
+                .map(|shift| {
+                    let mut shifts = vec![0; bases];
+                    shifts[base] = *shift;
+                    names.values(defines, &shifts).0
+                })
+                .collect())
+            .collect();
+
+        Ok(Self {
+            values,
+            shifted,
+            sections: names.sections.len(),
+            externs: names.externs.clone(),
+        })
+    }
+
+    fn evaluate(&self, source: &SourceLine, text: &str) -> Result<Value, AssembleError> {
+        let eval = |values: &HashMap<String, i64>| {
+            let lookup = |name: &str| values.get(name).copied()
+                .ok_or_else(|| EvalError::Undefined(name.to_string()));
+            evaluate(text, &lookup)
+        };
+        let value = eval(&self.values)
+            .map_err(|e| eval_error(source, e))?;
+
+        let mut target = None;
+        for (base, shifted) in self.shifted.iter().enumerate() {
+            // How many times the shift the value moved by, if it's a whole number
+            let moves: Vec<Option<i64>> = shifted.iter().zip(SHIFTS)
+                .map(|(values, shift)| eval(values).ok()
+                    .filter(|moved| (moved - value) % shift == 0)
+                    .map(|moved| (moved - value) / shift))
+                .collect();
+
+            match moves.as_slice() {
+                [Some(0), Some(0)] => {}
+                [Some(1), Some(1)] if target.is_none() => target = Some(base),
+                _ => return Err(source.error(format!("`{}` can't be relocated, only `label + constant` and \
+                    the difference of two labels in the same section can", text))),
+            }
+        }
+
+        Ok(match target {
+            None => Value::Absolute(value),
+            Some(base) if base < self.sections => Value::Relocated(RelocationTarget::Section(base), value),
+            Some(base) => Value::Relocated(RelocationTarget::Symbol(self.externs[base - self.sections].clone()), value),
+        })
+    }
+
+    // `$1234` and `&3000` are hexadecimal like the disassembler prints them, anything else is an expression
+    fn operand(&self, source: &SourceLine, text: &str) -> Result<Value, AssembleError> {
+        let expression = text.strip_prefix('$').or_else(|| text.strip_prefix('&')).unwrap_or(text).trim();
+
+        if expression != text && !expression.is_empty() && expression.chars().all(|c| c.is_ascii_hexdigit()) {
+            i64::from_str_radix(expression, 16)
+                .map(Value::Absolute)
+                .map_err(|_| source.error(format!("invalid number `{}`", text)))
+        } else {
+            self.evaluate(source, expression)
+        }
+    }
+}
+
+fn emit_word(section: &mut object::Section, source: &SourceLine, value: Value) -> Result<(), AssembleError> {
+    let value = match value {
+        Value::Absolute(value) => value,
+        Value::Relocated(target, addend) => {
+            section.relocations.push(Relocation {
+                offset: section.bytes.len() as u16,
+                size: RelocationSize::Word,
+                target,
+                addend: addend as i32,
+            });
+            addend
+        }
+    };
+
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(source.error(format!("{} doesn't fit in 16 bits", value)));
+    }
+    section.bytes.extend((value as u16).to_be_bytes());
+    Ok(())
+}
+
+fn emit_byte(section: &mut object::Section, source: &SourceLine, value: Value) -> Result<(), AssembleError> {
+    let value = match value {
+        Value::Absolute(value) => value,
+        Value::Relocated(target, addend) => {
+            section.relocations.push(Relocation {
+                offset: section.bytes.len() as u16,
+                size: RelocationSize::Byte,
+                target,
+                addend: addend as i32,
+            });
+            addend
+        }
+    };
+
+    if !(-0x80..=0xFF).contains(&value) {
+        return Err(source.error(format!("{} doesn't fit in a byte", value)));
+    }
+    section.bytes.push(value as u8);
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Assembler {
+    // Sources used instead of reading the file at that path, for includes that don't exist on disk
+    files: HashMap<PathBuf, String>,
+    // Constants set from outside the program, like `-D DEBUG=1` on the command line
+    defines: HashMap<String, i64>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P, source: &str) {
+        self.files.insert(path.as_ref().to_path_buf(), source.to_string());
+    }
+
+    pub fn define(&mut self, name: &str, value: i64) {
+        self.defines.insert(name.to_string(), value);
+    }
+
+    fn read(&self, path: &Path) -> Result<String, AssembleError> {
+        match self.files.get(path) {
+            Some(source) => Ok(source.clone()),
+            None => fs::read_to_string(path)
+                .map_err(|e| AssembleError::Io(path.to_path_buf(), e)),
+        }
+    }
+
+    // Assembles `source` as if it was the file at `path`, includes are relative to its directory
+    pub fn assemble<P: AsRef<Path>>(&mut self, path: P, source: &str) -> Result<Assembly, AssembleError> {
+        self.add_file(&path, source);
+        self.assemble_file(path)
+    }
+
+    // Assembles a program that runs on its own, with its sections one after the other from address 0
+    pub fn assemble_file<P: AsRef<Path>>(&self, path: P) -> Result<Assembly, AssembleError> {
+        let mut linker = Linker::new();
+        linker.add_object(self.assemble_object(path)?);
+        let linked = linker.link()
+            .map_err(AssembleError::Link)?;
+
+        Ok(Assembly {
+            bytes: linked.executable.image(),
+            debug_info: linked.debug_info,
+        })
+    }
+
+    pub fn assemble_object<P: AsRef<Path>>(&self, path: P) -> Result<Object, AssembleError> {
+        let read = |path: &Path| self.read(path);
+        let lines = Preprocessor::new(&read, &self.defines)
+            .process_file(path.as_ref())?;
+
+        // Pass one: where every label and statement goes
+        let mut names = Names {
+            sections: vec![DEFAULT_SECTION.to_string()],
+            ..Names::default()
+        };
+        let mut sizes = vec![0usize];
+        let mut section = 0;
+        let mut globals = vec![];
+        let mut statements = vec![];
+
+        for source in lines {
+            let (label, text) = split_label(&source.text);
+            if let Some(label) = label {
+                self.check_unused(&source, label, &names)?;
+                if sizes[section] > 0xFFFF {
+                    return Err(source.error(format!("label `{}` is past the end of memory", label)));
+                }
+                names.labels.insert(label.to_string(), (section, sizes[section] as u16));
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (mnemonic, rest) = text.split_once(char::is_whitespace)
+                .map_or((text, ""), |(m, r)| (m, r.trim()));
+            let operands = split_arguments(rest);
+
+            // What `.org` and `.fill` need is known straight away, from labels before them (as offsets in their
+            // section) and constants
+            let known = |text: &str| {
+                let (values, _) = names.values(&self.defines, &[]);
+                let lookup = |name: &str| values.get(name).copied()
+                    .ok_or_else(|| EvalError::Undefined(name.to_string()));
+                evaluate(text, &lookup)
+                    .map_err(|e| eval_error(&source, e))
+            };
+
+            let item = match mnemonic {
+                ".equ" => {
+                    let [name, value] = operands.as_slice() else {
+                        return Err(source.error("expected `.equ NAME, value`"));
+                    };
+                    if !is_name(name) {
+                        return Err(source.error(format!("invalid constant name `{}`", name)));
+                    }
+                    self.check_unused(&source, name, &names)?;
+                    names.equs.push(Equ {
+                        name: name.clone(),
+                        value: value.clone(),
+                        source,
+                    });
+                    continue;
+                }
+                ".section" => {
+                    let [name] = operands.as_slice() else {
+                        return Err(source.error("expected `.section name`"));
+                    };
+                    if name.is_empty() || name.contains(char::is_whitespace) {
+                        return Err(source.error(format!("invalid section name `{}`", name)));
+                    }
+                    section = match names.sections.iter().position(|s| s == name) {
+                        Some(i) => i,
+                        None => {
+                            names.sections.push(name.clone());
+                            sizes.push(0);
+                            names.sections.len() - 1
+                        }
+                    };
+                    continue;
+                }
+                ".global" | ".extern" => {
+                    if operands.is_empty() {
+                        return Err(source.error(format!("expected `{} name`", mnemonic)));
+                    }
+                    for name in operands {
+                        if !is_name(&name) {
+                            return Err(source.error(format!("invalid name `{}`", name)));
+                        }
+                        if mnemonic == ".extern" {
+                            self.check_unused(&source, &name, &names)?;
+                            names.externs.push(name);
+                        } else {
+                            globals.push((name, source.clone()));
+                        }
+                    }
+                    continue;
+                }
+                ".org" => {
+                    let [origin] = operands.as_slice() else {
+                        return Err(source.error("expected `.org offset`"));
+                    };
+                    let origin = known(origin)?;
+                    if !(0..=0xFFFF).contains(&origin) {
+                        return Err(source.error(format!("0x{:X} isn't an address", origin)));
+                    }
+                    if (origin as usize) < sizes[section] {
+                        return Err(source.error(format!("`.org 0x{:04X}` can't go back from 0x{:04X}",
+                            origin, sizes[section])));
+                    }
+                    sizes[section] = origin as usize;
+                    continue;
+                }
+                ".byte" => Item::Bytes(operands.iter()
+                    .map(|o| match o.starts_with('"') {
+                        true => parse_string(&source, o).map(Data::String),
+                        false => Ok(Data::Value(o.clone())),
+                    })
+                    .collect::<Result<_, _>>()?),
+                ".ascii" | ".asciz" => Item::Bytes(operands.iter()
+                    .map(|o| {
+                        let mut bytes = parse_string(&source, o)?;
+                        if mnemonic == ".asciz" {
+                            bytes.push(0);
+                        }
+                        Ok(Data::String(bytes))
+                    })
+                    .collect::<Result<_, _>>()?),
+                ".word" => Item::Words(operands),
+                ".fill" => {
+                    let (count, value) = match operands.as_slice() {
+                        [count] => (count, "0"),
+                        [count, value] => (count, value.as_str()),
+                        _ => return Err(source.error("expected `.fill count[, byte]`")),
+                    };
+                    let count = known(count)?;
+                    if !(0..=0x10000).contains(&count) {
+                        return Err(source.error(format!("can't fill {} bytes", count)));
+                    }
+                    Item::Fill(count as usize, value.to_string())
+                }
+                _ if mnemonic.starts_with('.') => {
+                    return Err(source.error(format!("unknown directive `{}`", mnemonic)));
+                }
+                _ => Item::Instruction(find_instruction(&source, mnemonic, &operands)?, operands),
+            };
+
+            let address = sizes[section];
+            if address + item.size() > 0x10000 {
+                return Err(source.error("section doesn't fit in memory"));
+            }
+            sizes[section] += item.size();
+            statements.push(Statement {
+                source,
+                section,
+                address,
+                item,
+            });
+        }
+
+        let evaluator = Evaluator::new(&names, &self.defines)?;
+
+        // Pass two: encode each statement now that every name has a value
+        let mut sections: Vec<object::Section> = names.sections.iter()
+            .map(|name| object::Section {
+                name: name.clone(),
+                ..object::Section::default()
+            })
+            .collect();
+
+        for statement in statements {
+            let section = &mut sections[statement.section];
+            let source = &statement.source;
+            // Skipped over by `.org`
+            section.bytes.resize(statement.address, 0);
+
+            if statement.item.size() > 0 {
+                section.lines.push(LineInfo {
+                    address: statement.address as u16,
+                    size: statement.item.size().min(0xFFFF) as u16,
+                    file: source.file.display().to_string(),
+                    line: source.line,
+                });
+            }
+
+            match &statement.item {
+                Item::Instruction(info, operands) => {
+                    section.bytes.push(info.opcode);
+                    for (operand, text) in info.operands.iter().zip(operands) {
+                        match operand {
+                            Operand::Register | Operand::Pointer => {
+                                let register = Register::from_str(text.trim_start_matches('&'))
+                                    .map_err(|_| source.error(format!("unknown register `{}`", text)))?;
+                                section.bytes.push(register.index());
+                            }
+                            Operand::Literal | Operand::Address => {
+                                emit_word(section, source, evaluator.operand(source, text)?)?;
+                            }
+                        }
+                    }
+                }
+                Item::Bytes(data) => {
+                    for data in data {
+                        match data {
+                            Data::String(string) => section.bytes.extend(string),
+                            Data::Value(text) => emit_byte(section, source, evaluator.evaluate(source, text)?)?,
+                        }
+                    }
+                }
+                Item::Words(values) => {
+                    for text in values {
+                        emit_word(section, source, evaluator.evaluate(source, text)?)?;
+                    }
+                }
+                Item::Fill(count, text) => {
+                    let value = match evaluator.evaluate(source, text)? {
+                        Value::Absolute(value) if (-0x80..=0xFF).contains(&value) => value as u8,
+                        _ => return Err(source.error(format!("`{}` isn't a byte", text))),
+                    };
+                    section.bytes.extend(std::iter::repeat_n(value, *count));
+                }
+            }
+            debug_assert_eq!(section.bytes.len(), statement.address + statement.item.size());
+        }
+
+        // A section can end with a `.org`
+        for (section, size) in sections.iter_mut().zip(sizes) {
+            section.bytes.resize(size, 0);
+        }
+
+        for (name, source) in &globals {
+            if !names.labels.contains_key(name) {
+                return Err(source.error(format!("`{}` needs to be a label to be global", name)));
+            }
+        }
+
+        let mut symbols: Vec<object::Symbol> = names.labels.iter()
+            .map(|(name, (section, offset))| object::Symbol {
+                name: name.clone(),
+                section: *section,
+                offset: *offset,
+                global: globals.iter().any(|(g, _)| g == name),
+            })
+            .collect();
+        symbols.sort_by(|a, b| (a.section, a.offset, &a.name).cmp(&(b.section, b.offset, &b.name)));
+
+        Ok(Object {
+            name: path.as_ref().display().to_string(),
+            sections,
+            symbols,
+        })
+    }
+
+    fn check_unused(&self, source: &SourceLine, name: &str, names: &Names) -> Result<(), AssembleError> {
+        if names.contains(name) || self.defines.contains_key(name) {
+            return Err(source.error(format!("`{}` is already defined", name)));
+        }
+        if Register::from_str(name).is_ok() {
+            return Err(source.error(format!("`{}` is a register", name)));
+        }
+        Ok(())
+    }
+}
+
+// Evaluates `.equ`s in whatever order their values become known, so they can use labels and each other.
+// Returns `values` with the constants added and why the first one that couldn't be evaluated failed
+fn resolve_constants(mut values: HashMap<String, i64>, equs: &[Equ]) -> (HashMap<String, i64>, Option<AssembleError>) {
+    let mut equs: Vec<&Equ> = equs.iter().collect();
+
+    while !equs.is_empty() {
+        let before = equs.len();
+        let mut error = None;
+
+        equs.retain(|equ| {
+            let lookup = |n: &str| values.get(n).copied()
+                .ok_or_else(|| EvalError::Undefined(n.to_string()));
+
+            match evaluate(&equ.value, &lookup) {
+                Ok(value) => {
+                    values.insert(equ.name.clone(), value);
+                    false
+                }
+                Err(e) => {
+                    error.get_or_insert_with(|| eval_error(&equ.source, e));
+                    true
+                }
+            }
+        });
+
+        if equs.len() == before {
+            return (values, error);
+        }
+    }
+
+    (values, None)
+}
+
+fn eval_error(source: &SourceLine, error: EvalError) -> AssembleError {
+    source.error(match error {
+        EvalError::Undefined(name) => format!("`{}` isn't defined", name),
+        EvalError::Invalid(message) => message,
+    })
+}
+
+fn operand_kind(text: &str) -> Operand {
+    if text.strip_prefix('&').is_some_and(|r| Register::from_str(r).is_ok()) {
+        Operand::Pointer
+    } else if text.starts_with('&') {
+        Operand::Address
+    } else if Register::from_str(text).is_ok() {
+        Operand::Register
+    } else {
+        Operand::Literal
+    }
+}
+
+fn operand_name(operand: Operand) -> &'static str {
+    match operand {
+        Operand::Literal => "literal",
+        Operand::Register => "register",
+        Operand::Address => "address",
+        Operand::Pointer => "pointer",
+    }
+}
+
+fn find_instruction(source: &SourceLine, mnemonic: &str, operands: &[String]) -> Result<&'static InstructionInfo, AssembleError> {
+    let mnemonic = mnemonic.to_lowercase();
+    let kinds: Vec<Operand> = operands.iter().map(|o| operand_kind(o)).collect();
+
+    let forms: Vec<&InstructionInfo> = INSTRUCTIONS.iter().filter(|i| i.mnemonic == mnemonic).collect();
+    if forms.is_empty() {
+        return Err(source.error(format!("unknown instruction `{}`", mnemonic)));
+    }
+
+    forms.iter().find(|i| i.operands == kinds.as_slice()).copied()
+        .ok_or_else(|| {
+            let forms: Vec<String> = forms.iter()
+                .map(|i| {
+                    let operands: Vec<&str> = i.operands.iter().map(|o| operand_name(*o)).collect();
+                    format!("`{}`", [mnemonic.as_str(), &operands.join(", ")].join(" ").trim())
+                })
+                .collect();
+            source.error(format!("`{}` doesn't take those operands, expected one of {}", mnemonic, forms.join(", ")))
+        })
+}
+
+// The bytes of a quoted string, with the same escapes as character literals
+fn parse_string(source: &SourceLine, text: &str) -> Result<Vec<u8>, AssembleError> {
+    let invalid = || source.error(format!("invalid string {}", text));
+    let mut rest = text.strip_prefix('"').and_then(|t| t.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+
+    let mut bytes = vec![];
+    while !rest.is_empty() {
+        let (c, len) = parse_char(rest)
+            .filter(|(c, _)| c.is_ascii())
+            .ok_or_else(invalid)?;
+        if c == '"' && len == 1 {
+            return Err(invalid());
+        }
+        bytes.push(c as u8);
+        rest = &rest[len..];
+    }
+
+    Ok(bytes)
+}
+
+// Everything before a `;` that isn't in quotes
+pub fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        match quote {
+            _ if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => return &text[..i],
+            None => {}
+        }
+    }
+
+    text
+}
+
+// Splits on commas that aren't in quotes or parentheses
+pub fn split_arguments(text: &str) -> Vec<String> {
+    let mut arguments = vec![];
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        match quote {
+            _ if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    arguments.push(text[start..i].trim().to_string());
+                    start = i + 1;
+                }
+                _ => {}
+            },
+        }
+    }
+
+    let last = text[start..].trim();
+    if !last.is_empty() || !arguments.is_empty() {
+        arguments.push(last.to_string());
+    }
+    arguments
+}
+
+// Splits `label: rest` into the label and the rest
+pub fn split_label(text: &str) -> (Option<&str>, &str) {
+    let text = text.trim();
+    match text.split_once(':') {
+        Some((label, rest)) if label.starts_with(is_name_start) && label.chars().all(is_name_char) => {
+            (Some(label), rest.trim())
+        }
+        _ => (None, text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{split_arguments, strip_comment, AssembleError, Assembler};
+    use crate::cpu::instructions::*;
+    use crate::object::{Relocation, RelocationSize, RelocationTarget};
+
+    const R1: u8 = 2;
+
+    fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+        Assembler::new().assemble("test.asm", source)
+            .map(|a| a.bytes)
+    }
+
+    fn error_line(result: Result<Vec<u8>, AssembleError>) -> usize {
+        match result {
+            Err(AssembleError::Syntax { line, .. }) => line,
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn splitting() {
+        assert_eq!(strip_comment("mov $1, r1 ; comment"), "mov $1, r1 ");
+        assert_eq!(strip_comment("mov ';', r1"), "mov ';', r1");
+        assert_eq!(split_arguments("a, (b, c), ','"), vec!["a", "(b, c)", "','"]);
+        assert!(split_arguments("  ").is_empty());
+    }
+
+    #[test]
+    fn instructions_and_labels() {
+        let bytes = assemble("
+            start:
+                mov $1234, r1       ; hexadecimal like the disassembler
+                mov 10 + 2, acc
+                mov r1, &3000
+            loop: add r1, acc
+                jne $0000, &loop
+                cal start
+                hlt
+        ").unwrap();
+
+        assert_eq!(bytes, vec![
+            MOV_LIT_REG, 0x12, 0x34, R1,
+            MOV_LIT_REG, 0x00, 0x0C, 1,
+            MOV_REG_MEM, R1, 0x30, 0x00,
+            ADD_REG_REG, R1, 1,
+            JMP_NOT_EQ, 0x00, 0x00, 0x00, 0x0C,
+            CAL_LIT, 0x00, 0x00,
+            HLT,
+        ]);
+
+        let assembly = Assembler::new().assemble("test.asm", "a: hlt\nb: hlt").unwrap();
+        assert_eq!(assembly.debug_info.symbols.address_of("b"), Some(1));
+    }
+
+    #[test]
+    fn constants() {
+        let mut assembler = Assembler::new();
+        assembler.define("BASE", 0x3000);
+        let bytes = assembler.assemble("test.asm", "
+            .equ OFFSET, END - 1      ; labels and later constants work too
+            .equ SCREEN, BASE + OFFSET
+            mov r1, &SCREEN
+            END: hlt
+        ").unwrap().bytes;
+        assert_eq!(bytes, vec![MOV_REG_MEM, R1, 0x30, 0x03, HLT]);
+
+        assert_eq!(assemble("mov -1, r1").unwrap(), vec![MOV_LIT_REG, 0xFF, 0xFF, R1]);
+        assert_eq!(error_line(assemble("hlt\nmov 0x10000, r1")), 2);
+        assert_eq!(error_line(assemble(".equ A, B\n.equ B, A")), 1);
+        assert_eq!(error_line(assemble("a: hlt\n.equ a, 1")), 2);
+        assert_eq!(error_line(assemble("r1: hlt")), 1);
+        assert_eq!(error_line(assemble("\nmov $1, $2")), 2);
+        assert_eq!(error_line(assemble("\n\nfoo r1")), 3);
+        assert_eq!(error_line(assemble("jne $0, &missing")), 1);
+    }
+
+    #[test]
+    fn data() {
+        let assembly = Assembler::new().assemble("test.asm", r#"
+                mov &message, r1
+                hlt
+            message: .asciz "Hi\n"
+            table:   .word 0x1234, table, -1
+                     .byte 1, "ab", 'c', -1
+                     .ascii "x;y", "\"z\""
+                     .fill 3, 0xAA
+                     .fill table - 5        ; only labels before it
+            .org 0x30
+            END: .byte END
+        "#).unwrap();
+
+        assert_eq!(assembly.debug_info.symbols.address_of("table"), Some(0x09));
+        let mut expected = vec![MOV_MEM_REG, 0x00, 0x05, R1, HLT, b'H', b'i', b'\n', 0];
+        expected.extend([0x12, 0x34, 0x00, 0x09, 0xFF, 0xFF]);
+        expected.extend([1, b'a', b'b', b'c', 0xFF]);
+        expected.extend(b"x;y\"z\"");
+        expected.extend([0xAA, 0xAA, 0xAA, 0, 0, 0, 0]);
+        expected.resize(0x30, 0);
+        expected.push(0x30);
+        assert_eq!(assembly.bytes, expected);
+
+        assert_eq!(error_line(assemble(".byte 256")), 1);
+        assert_eq!(error_line(assemble(".word 0x10000")), 1);
+        assert_eq!(error_line(assemble(".ascii 'a'")), 1);
+        assert_eq!(error_line(assemble(".asciz \"open")), 1);
+        assert_eq!(error_line(assemble(".fill LATER\nLATER: hlt")), 1);
+        assert_eq!(error_line(assemble(".org 4\n.org 2")), 2);
+        assert_eq!(error_line(assemble(".org 0xFFFF\n.word 1")), 2);
+        assert_eq!(error_line(assemble("\n.dw 1")), 2);
+    }
+
+    #[test]
+    fn objects() {
+        let mut assembler = Assembler::new();
+        assembler.add_file("test.asm", "
+            .extern print
+            .global start
+            start: cal print + 2
+                mov &data, r1
+                mov end - data, r1
+            .section .data
+            data: .word start, 0x1234
+            end:
+        ");
+        let object = assembler.assemble_object("test.asm")
+            .unwrap();
+
+        assert_eq!(object.sections.len(), 2);
+        let text = &object.sections[0];
+        assert_eq!(text.bytes, vec![CAL_LIT, 0x00, 0x02, MOV_MEM_REG, 0x00, 0x00, R1, MOV_LIT_REG, 0x00, 0x04, R1]);
+        assert_eq!(text.relocations, vec![
+            Relocation { offset: 1, size: RelocationSize::Word, target: RelocationTarget::Symbol("print".to_string()), addend: 2 },
+            Relocation { offset: 4, size: RelocationSize::Word, target: RelocationTarget::Section(1), addend: 0 },
+        ]);
+        assert_eq!(object.sections[1].relocations, vec![
+            Relocation { offset: 0, size: RelocationSize::Word, target: RelocationTarget::Section(0), addend: 0 },
+        ]);
+        assert!(object.symbols.iter().any(|s| s.name == "start" && s.global));
+        assert!(object.symbols.iter().any(|s| s.name == "data" && !s.global && s.section == 1));
+
+        // Sections follow each other when assembling a whole program
+        let bytes = assemble(".section .data\n.byte 1\n.section .text\nmov &here, r1\n.section .data\nhere: .byte 2").unwrap();
+        assert_eq!(bytes, vec![MOV_MEM_REG, 0x00, 0x05, R1, 1, 2]);
+
+        assert_eq!(error_line(assemble("a: hlt\nmov a * 2, r1")), 2);
+        assert_eq!(error_line(assemble(".section .data\na: hlt\n.section .text\nb: mov b - a, r1")), 4);
+        assert_eq!(error_line(assemble(".equ A, 1\n.global A")), 2);
+        assert!(matches!(assemble(".extern missing\ncal missing"), Err(AssembleError::Link(_))));
+    }
+
+    #[test]
+    fn macros() {
+        let bytes = assemble("
+            %macro set value, register
+                mov %value, %register
+            %endmacro
+            %macro spin
+            %%again: jne $0, &%%again
+            %endmacro
+
+            set 0x10, r1
+            spin
+            spin
+        ").unwrap();
+
+        assert_eq!(bytes, vec![
+            MOV_LIT_REG, 0x00, 0x10, R1,
+            JMP_NOT_EQ, 0x00, 0x00, 0x00, 0x04,
+            JMP_NOT_EQ, 0x00, 0x00, 0x00, 0x09,
+        ]);
+
+        assert_eq!(error_line(assemble("%macro m a\nhlt\n%endmacro\n\nm")), 5);
+        assert_eq!(error_line(assemble("%macro m\nm\n%endmacro\nm")), 4);
+        assert_eq!(error_line(assemble("%macro m\nhlt")), 2);
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = "
+            .equ LEVEL, 2
+            %if LEVEL > 1 && !defined_later
+            %else
+            %endif
+        ";
+        assert!(assemble(source).is_err());
+
+        let source = "
+            .equ LEVEL, 2
+            %ifdef DEBUG
+                hlt
+            %elif LEVEL == 1
+                ret
+            %elif LEVEL == 2
+                %if 0
+                    ret
+                %else
+                    sret
+                %endif
+            %else
+                ret
+            %endif
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![SRET]);
+
+        let mut assembler = Assembler::new();
+        assembler.define("DEBUG", 1);
+        assert_eq!(assembler.assemble("test.asm", source).unwrap().bytes, vec![HLT]);
+
+        assert_eq!(error_line(assemble("%if 1\nhlt")), 2);
+        assert_eq!(error_line(assemble("hlt\n%endif")), 2);
+        assert_eq!(error_line(assemble("%if 1\n%else\n%else\n%endif")), 3);
+    }
+
+    #[test]
+    fn includes() {
+        let mut assembler = Assembler::new();
+        assembler.add_file("lib/consts.asm", ".equ VALUE, 7\n%include \"more.asm\"");
+        assembler.add_file("lib/more.asm", "%macro done\nhlt\n%endmacro");
+        let bytes = assembler.assemble("lib/main.asm", "%include \"consts.asm\"\nmov VALUE, r1\ndone")
+            .unwrap().bytes;
+        assert_eq!(bytes, vec![MOV_LIT_REG, 0x00, 0x07, R1, HLT]);
+
+        let mut assembler = Assembler::new();
+        assembler.add_file("a.asm", "%include \"b.asm\"");
+        assembler.add_file("b.asm", "%include \"a.asm\"");
+        match assembler.assemble_file("a.asm") {
+            Err(AssembleError::Syntax { file, line: 1, .. }) => assert_eq!(file.to_str(), Some("b.asm")),
+            other => panic!("expected an include loop, got {:?}", other.map(|a| a.bytes)),
+        }
+
+        assert!(matches!(assemble("%include \"missing.asm\""), Err(AssembleError::Io(..))));
+    }
+
+    // asm/hello.asm should assemble to the same program as the one built into mayo_bin
+    #[test]
+    fn hello() {
+        let mut assembler = Assembler::new();
+        assembler.add_file("asm/screen.asm", include_str!("../../../asm/screen.asm"));
+        let bytes = assembler.assemble("asm/hello.asm", include_str!("../../../asm/hello.asm"))
+            .unwrap().bytes;
+
+        let mut expected = vec![];
+        let mut write_char = |char: char, command: u8, pos: u8| {
+            expected.extend([MOV_LIT_REG, command, char as u8, R1, MOV_REG_MEM, R1, 0x30, pos]);
+        };
+        write_char(' ', 0xFF, 0);
+        for (i, char) in "Hi world!".chars().enumerate() {
+            write_char(char, if i % 2 == 0 { 0x01 } else { 0x02 }, i as u8);
+        }
+        expected.push(HLT);
+
+        assert_eq!(bytes, expected);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use crate::assembler::{split_arguments, split_label, strip_comment, AssembleError};
+use crate::assembler::expression::{evaluate, is_name, is_name_char, EvalError};
+
+// Deep enough for any sensible nesting, shallow enough to catch a macro that expands to itself
+const MAX_DEPTH: usize = 64;
+
+// A line of source after includes, macros and conditionals have been dealt with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: PathBuf,
+    pub line: usize,
+    pub text: String,
+}
+
+impl SourceLine {
+    pub fn error(&self, message: impl Into<String>) -> AssembleError {
+        AssembleError::Syntax {
+            file: self.file.clone(),
+            line: self.line,
+            message: message.into(),
+        }
+    }
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<SourceLine>,
+}
+
+struct Conditional {
+    // Lines are kept while this is true
+    active: bool,
+    // Some branch of this %if has been taken, so later %elif and %else branches are skipped
+    taken: bool,
+    seen_else: bool,
+}
+
+pub struct Preprocessor<'a> {
+    read: &'a dyn Fn(&Path) -> Result<String, AssembleError>,
+    // `.equ` constants that could be evaluated as they were seen, and the assembler's defines, for %if
+    constants: HashMap<String, i64>,
+    macros: HashMap<String, Macro>,
+    // Numbers each expansion so `%%label`s in different expansions don't collide
+    expansions: usize,
+    includes: Vec<PathBuf>,
+    conditionals: Vec<Conditional>,
+    output: Vec<SourceLine>,
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new(read: &'a dyn Fn(&Path) -> Result<String, AssembleError>, defines: &HashMap<String, i64>) -> Self {
+        Self {
+            read,
+            constants: defines.clone(),
+            macros: HashMap::new(),
+            expansions: 0,
+            includes: vec![],
+            conditionals: vec![],
+            output: vec![],
+        }
+    }
+
+    pub fn process_file(mut self, path: &Path) -> Result<Vec<SourceLine>, AssembleError> {
+        let source = (self.read)(path)?;
+        self.include(path, &source)?;
+        Ok(self.output)
+    }
+
+    fn include(&mut self, path: &Path, source: &str) -> Result<(), AssembleError> {
+        if self.includes.len() >= MAX_DEPTH {
+            return Err(AssembleError::Syntax {
+                file: path.to_path_buf(),
+                line: 0,
+                message: "includes nested too deeply".to_string(),
+            });
+        }
+
+        let lines: Vec<SourceLine> = source.lines().enumerate()
+            .map(|(i, text)| SourceLine {
+                file: path.to_path_buf(),
+                line: i + 1,
+                text: text.to_string(),
+            })
+            .collect();
+
+        self.includes.push(path.to_path_buf());
+        let result = self.process(&lines);
+        self.includes.pop();
+        result
+    }
+
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+
+    fn process(&mut self, lines: &[SourceLine]) -> Result<(), AssembleError> {
+        let depth = self.conditionals.len();
+        let mut defining: Option<(String, Macro)> = None;
+
+        for source in lines {
+            let text = strip_comment(&source.text).trim();
+            let (directive, rest) = text.split_once(char::is_whitespace)
+                .map_or((text, ""), |(d, r)| (d, r.trim()));
+
+            if let Some((name, mut definition)) = defining.take() {
+                match directive {
+                    "%endmacro" => {
+                        self.macros.insert(name, definition);
+                    }
+                    "%macro" => return Err(source.error("macros can't be defined inside macros")),
+                    _ => {
+                        definition.body.push(source.clone());
+                        defining = Some((name, definition));
+                    }
+                }
+                continue;
+            }
+
+            match directive {
+                "%if" | "%ifdef" | "%ifndef" => {
+                    let condition = self.active() && self.condition(source, directive, rest)?;
+                    self.conditionals.push(Conditional {
+                        active: condition,
+                        taken: condition || !self.active(),
+                        seen_else: false,
+                    });
+                }
+                "%elif" | "%else" => {
+                    if self.conditionals.len() <= depth {
+                        return Err(source.error(format!("{} without %if", directive)));
+                    }
+                    let current = self.conditionals.last()
+                        .unwrap();
+                    if current.seen_else {
+                        return Err(source.error(format!("{} after %else", directive)));
+                    }
+
+                    let condition = !current.taken && match directive {
+                        "%elif" => self.condition(source, "%if", rest)?,
+                        _ => true,
+                    };
+                    let current = self.conditionals.last_mut()
+                        .unwrap();
+                    current.active = condition;
+                    current.taken |= condition;
+                    current.seen_else = directive == "%else";
+                }
+                "%endif" => {
+                    if self.conditionals.len() <= depth {
+                        return Err(source.error("%endif without %if"));
+                    }
+                    self.conditionals.pop();
+                }
+                _ if !self.active() => {}
+                "%include" => {
+                    let name = rest.strip_prefix('"').and_then(|r| r.strip_suffix('"'))
+                        .ok_or_else(|| source.error("expected `%include \"file\"`"))?;
+                    let path = source.file.parent().unwrap_or(Path::new("")).join(name);
+                    if self.includes.contains(&path) {
+                        return Err(source.error(format!("{} includes itself", path.display())));
+                    }
+
+                    let text = (self.read)(&path)?;
+                    self.include(&path, &text)?;
+                }
+                "%macro" => {
+                    let (name, parameters) = rest.split_once(char::is_whitespace)
+                        .unwrap_or((rest, ""));
+                    if !is_name(name) {
+                        return Err(source.error(format!("invalid macro name `{}`", name)));
+                    }
+                    let parameters = split_arguments(parameters);
+                    if let Some(p) = parameters.iter().find(|p| !is_name(p)) {
+                        return Err(source.error(format!("invalid macro parameter `{}`", p)));
+                    }
+
+                    defining = Some((name.to_string(), Macro {
+                        parameters,
+                        body: vec![],
+                    }));
+                }
+                "%endmacro" => return Err(source.error("%endmacro without %macro")),
+                _ if directive.starts_with('%') => {
+                    return Err(source.error(format!("unknown directive `{}`", directive)));
+                }
+                _ => self.statement(source, text)?,
+            }
+        }
+
+        if let Some((name, _)) = defining {
+            let last = lines.last()
+                .unwrap();
+            return Err(last.error(format!("macro `{}` is missing %endmacro", name)));
+        }
+        if self.conditionals.len() > depth {
+            self.conditionals.truncate(depth);
+            if let Some(last) = lines.last() {
+                return Err(last.error("%if is missing %endif"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn condition(&self, source: &SourceLine, directive: &str, rest: &str) -> Result<bool, AssembleError> {
+        match directive {
+            "%ifdef" => Ok(self.constants.contains_key(rest)),
+            "%ifndef" => Ok(!self.constants.contains_key(rest)),
+            _ => {
+                let lookup = |name: &str| self.constants.get(name).copied()
+                    .ok_or_else(|| EvalError::Undefined(name.to_string()));
+                evaluate(rest, &lookup)
+                    .map(|value| value != 0)
+                    .map_err(|e| source.error(match e {
+                        EvalError::Undefined(name) => format!("`{}` isn't a constant defined before this %if", name),
+                        EvalError::Invalid(message) => message,
+                    }))
+            }
+        }
+    }
+
+    fn statement(&mut self, source: &SourceLine, text: &str) -> Result<(), AssembleError> {
+        let (label, text) = split_label(text);
+        if let Some(label) = label {
+            self.output.push(SourceLine {
+                text: format!("{}:", label),
+                ..source.clone()
+            });
+        }
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let (name, rest) = text.split_once(char::is_whitespace)
+            .map_or((text, ""), |(n, r)| (n, r.trim()));
+
+        if name == ".equ" {
+            // Remembered so %if can test it, the assembler evaluates it again once labels are known
+            if let [constant, value] = split_arguments(rest).as_slice() {
+                let lookup = |name: &str| self.constants.get(name).copied()
+                    .ok_or_else(|| EvalError::Undefined(name.to_string()));
+                if let Ok(value) = evaluate(value, &lookup) {
+                    self.constants.insert(constant.clone(), value);
+                }
+            }
+        } else if self.macros.contains_key(name) {
+            return self.expand(source, name, rest);
+        }
+
+        self.output.push(SourceLine {
+            text: text.to_string(),
+            ..source.clone()
+        });
+        Ok(())
+    }
+
+    fn expand(&mut self, source: &SourceLine, name: &str, arguments: &str) -> Result<(), AssembleError> {
+        if self.conditionals.len() + self.includes.len() >= MAX_DEPTH {
+            return Err(source.error(format!("macro `{}` expands too deeply", name)));
+        }
+
+        self.expansions += 1;
+        let definition = &self.macros[name];
+        let arguments = split_arguments(arguments);
+        if arguments.len() != definition.parameters.len() {
+            return Err(source.error(format!("macro `{}` takes {} arguments but {} were given",
+                name, definition.parameters.len(), arguments.len())));
+        }
+
+        let lines: Vec<SourceLine> = definition.body.iter()
+            .map(|line| SourceLine {
+                text: substitute(&line.text, &definition.parameters, &arguments, self.expansions),
+                // Errors in an expansion point at the line that used the macro
+                ..source.clone()
+            })
+            .collect();
+
+        self.includes.push(source.file.clone());
+        let result = self.process(&lines);
+        self.includes.pop();
+        result
+    }
+}
+
+// Replaces `%parameter` with its argument and `%%label` with a label unique to this expansion
+fn substitute(text: &str, parameters: &[String], arguments: &[String], expansion: usize) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(i) = rest.find('%') {
+        result.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        let local = rest.starts_with('%');
+        let start = local as usize;
+        let end = rest[start..].find(|c| !is_name_char(c)).map_or(rest.len(), |e| start + e);
+        let name = &rest[start..end];
+
+        if local && !name.is_empty() {
+            result.push_str(&format!("{}.{}", name, expansion));
+        } else if let Some(p) = parameters.iter().position(|p| p == name) {
+            result.push_str(&arguments[p]);
+        } else {
+            result.push('%');
+            result.push_str(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+
+    result.push_str(rest);
+    result
+}
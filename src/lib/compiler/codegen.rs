@@ -0,0 +1,598 @@
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use crate::compiler::CompileError;
+use crate::compiler::parser::{Expression, Function, Item, Statement, StatementKind};
+use crate::cpu::register::Register;
+
+// Where the program starts when it has a `main`
+const ENTRY: &str = "_start";
+
+#[derive(Copy, Clone, Debug)]
+enum Global {
+    Var,
+    Const(u16),
+    // The number of parameters
+    Function(usize),
+}
+
+// Expressions leave their value in acc. Intermediate values are pushed, and r1 and r2 are scratch registers for
+// combining them, which the hardware saves across calls. Parameters and locals live in the frame: slot `n` is the
+// word at `fp - n * 2`, parameters first
+pub struct Generator<'a> {
+    source: Vec<&'a str>,
+    output: Vec<String>,
+    data: Vec<String>,
+    externs: BTreeSet<String>,
+    globals: HashMap<String, Global>,
+    labels: usize,
+    // Of the function being generated
+    function: String,
+    scopes: Vec<HashMap<String, usize>>,
+    slots: usize,
+    // The labels `continue` and `break` jump to
+    loops: Vec<(String, String)>,
+    line: usize,
+}
+
+// A label as an operand, in parentheses when it would otherwise read as a hex number
+fn reference(name: &str) -> String {
+    if name.chars().all(|c| c.is_ascii_hexdigit()) {
+        format!("({})", name)
+    } else {
+        name.to_string()
+    }
+}
+
+// Slots needed for the `var`s in a block and the blocks inside it
+fn count_vars(statements: &[Statement]) -> usize {
+    statements.iter()
+        .map(|s| match &s.kind {
+            StatementKind::Var(..) => 1,
+            StatementKind::If(_, then, otherwise) => count_vars(then) + count_vars(otherwise),
+            StatementKind::While(_, body) => count_vars(body),
+            _ => 0,
+        })
+        .sum()
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+impl<'a> Generator<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source: source.lines().collect(),
+            output: vec![],
+            data: vec![],
+            externs: BTreeSet::new(),
+            globals: HashMap::new(),
+            labels: 0,
+            function: String::new(),
+            scopes: vec![],
+            slots: 0,
+            loops: vec![],
+            line: 0,
+        }
+    }
+
+    fn error(&self, message: String) -> CompileError {
+        CompileError {
+            line: self.line,
+            message,
+        }
+    }
+
+    fn emit(&mut self, instruction: String) {
+        self.output.push(format!("    {}", instruction));
+    }
+
+    fn emit_label(&mut self, label: &str) {
+        self.output.push(format!("{}:", label));
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("{}.{}", self.function, self.labels)
+    }
+
+    fn declare(&mut self, name: &str, global: Global) -> Result<(), CompileError> {
+        // Globals become labels, which can't be named like registers
+        if Register::from_str(name).is_ok() {
+            return Err(self.error(format!("`{}` is the name of a register", name)));
+        }
+        if self.globals.insert(name.to_string(), global).is_some() {
+            return Err(self.error(format!("`{}` is already defined", name)));
+        }
+        Ok(())
+    }
+
+    pub fn generate(mut self, items: &[Item]) -> Result<String, CompileError> {
+        // Functions can be called before they're defined, constants have to come first
+        for item in items {
+            if let Item::Function(function) = item {
+                self.line = function.line;
+                self.declare(&function.name, Global::Function(function.parameters.len()))?;
+            }
+        }
+
+        for item in items {
+            match item {
+                Item::Var { line, name, value } => {
+                    self.line = *line;
+                    let value = match value {
+                        Some(value) => self.constant(value)
+                            .ok_or_else(|| self.error(format!("the value of `{}` must be constant", name)))?,
+                        None => 0,
+                    };
+                    self.declare(name, Global::Var)?;
+                    self.data.push(format!("{}:", name));
+                    self.data.push(format!("    .word 0x{:04X}", value));
+                }
+                Item::Const { line, name, value } => {
+                    self.line = *line;
+                    let value = self.constant(value)
+                        .ok_or_else(|| self.error(format!("the value of `{}` must be constant", name)))?;
+                    self.declare(name, Global::Const(value))?;
+                }
+                Item::Function(_) => {}
+            }
+        }
+
+        let main = items.iter().find_map(|item| match item {
+            Item::Function(function) if function.name == "main" => Some(function),
+            _ => None,
+        });
+        if let Some(main) = main {
+            if !main.parameters.is_empty() {
+                self.line = main.line;
+                return Err(self.error("`main` can't take parameters".to_string()));
+            }
+            self.emit_label(ENTRY);
+            self.emit("psh $0000".to_string());
+            self.emit(format!("cal ${}", reference("main")));
+            self.emit("hlt".to_string());
+        }
+
+        for item in items {
+            if let Item::Function(function) = item {
+                self.function(function)?;
+            }
+        }
+
+        let mut lines = vec![];
+        for item in items {
+            if let Item::Function(function) = item {
+                lines.push(format!(".global {}", function.name));
+            }
+        }
+        for name in &self.externs {
+            lines.push(format!(".extern {}", name));
+        }
+        lines.append(&mut self.output);
+        if !self.data.is_empty() {
+            lines.push(String::new());
+            lines.push(".section .data".to_string());
+            lines.append(&mut self.data);
+        }
+
+        Ok(lines.join("\n") + "\n")
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), CompileError> {
+        self.line = function.line;
+        self.function = function.name.clone();
+        self.scopes = vec![HashMap::new()];
+        self.slots = 0;
+
+        self.output.push(String::new());
+        self.emit_label(&function.name);
+
+        // Copy the arguments into slots so they can be assigned to like locals
+        for (i, parameter) in function.parameters.iter().enumerate() {
+            self.declare_local(parameter)?;
+            self.emit(format!("arg ${:04X}, r1", i));
+            self.emit("psh r1".to_string());
+        }
+        for _ in 0..count_vars(&function.body) {
+            self.emit("psh $0000".to_string());
+        }
+
+        for statement in &function.body {
+            self.statement(statement)?;
+        }
+
+        self.emit("mov $0000, acc".to_string());
+        self.emit("ret".to_string());
+        Ok(())
+    }
+
+    fn declare_local(&mut self, name: &str) -> Result<usize, CompileError> {
+        let slot = self.slots;
+        let scope = self.scopes.last_mut()
+            .expect("there's always a scope inside a function");
+        if scope.insert(name.to_string(), slot).is_some() {
+            return Err(self.error(format!("`{}` is already declared", name)));
+        }
+        self.slots += 1;
+        Ok(slot)
+    }
+
+    fn local(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    fn block(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        self.scopes.push(HashMap::new());
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        self.line = statement.line;
+        if let Some(text) = self.source.get(statement.line - 1) {
+            self.output.push(format!("    ; {}: {}", statement.line, text.trim()));
+        }
+
+        match &statement.kind {
+            StatementKind::Var(name, value) => {
+                match value {
+                    Some(value) => self.expression(value)?,
+                    None => self.emit("mov $0000, acc".to_string()),
+                }
+                let slot = self.declare_local(name)?;
+                self.store_local(slot);
+            }
+
+            StatementKind::Assign(target, value) => self.assign(target, value)?,
+
+            StatementKind::Expression(expression) => self.expression(expression)?,
+
+            StatementKind::If(condition, then, otherwise) => {
+                let otherwise_label = self.new_label();
+                self.expression(condition)?;
+                self.emit(format!("jeq $0000, &{}", otherwise_label));
+                self.block(then)?;
+
+                if otherwise.is_empty() {
+                    self.emit_label(&otherwise_label);
+                } else {
+                    let end = self.new_label();
+                    self.emit(format!("jmp &{}", end));
+                    self.emit_label(&otherwise_label);
+                    self.block(otherwise)?;
+                    self.emit_label(&end);
+                }
+            }
+
+            StatementKind::While(condition, body) => {
+                let start = self.new_label();
+                let end = self.new_label();
+                self.emit_label(&start);
+                self.expression(condition)?;
+                self.emit(format!("jeq $0000, &{}", end));
+
+                self.loops.push((start.clone(), end.clone()));
+                self.block(body)?;
+                self.loops.pop();
+
+                self.emit(format!("jmp &{}", start));
+                self.emit_label(&end);
+            }
+
+            StatementKind::Return(value) => {
+                match value {
+                    Some(value) => self.expression(value)?,
+                    None => self.emit("mov $0000, acc".to_string()),
+                }
+                self.emit("ret".to_string());
+            }
+
+            StatementKind::Break | StatementKind::Continue => {
+                let (keyword, label) = match (&statement.kind, self.loops.last()) {
+                    (StatementKind::Break, Some((_, end))) => ("break", Some(end.clone())),
+                    (StatementKind::Break, None) => ("break", None),
+                    (_, loop_labels) => ("continue", loop_labels.map(|(start, _)| start.clone())),
+                };
+                let label = label
+                    .ok_or_else(|| self.error(format!("`{}` outside of a loop", keyword)))?;
+                self.emit(format!("jmp &{}", label));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Leaves the address of slot `slot` in acc, overwriting r1
+    fn local_address(&mut self, slot: usize) {
+        if slot == 0 {
+            self.emit("mov fp, acc".to_string());
+        } else {
+            self.emit(format!("mov ${:04X}, r1", slot * 2));
+            self.emit("sub fp, r1".to_string());
+        }
+    }
+
+    // Stores acc in slot `slot`, overwriting r1 and r2
+    fn store_local(&mut self, slot: usize) {
+        self.emit("mov acc, r2".to_string());
+        self.local_address(slot);
+        self.emit("mov r2, &acc".to_string());
+    }
+
+    fn assign(&mut self, target: &Expression, value: &Expression) -> Result<(), CompileError> {
+        if let Expression::Name(name) = target {
+            if let Some(slot) = self.local(name) {
+                self.expression(value)?;
+                self.store_local(slot);
+                return Ok(());
+            }
+            if let Some(Global::Var) = self.globals.get(name) {
+                self.expression(value)?;
+                self.emit(format!("mov acc, &{}", reference(name)));
+                return Ok(());
+            }
+        }
+
+        self.address(target)?;
+        self.emit("psh acc".to_string());
+        self.expression(value)?;
+        self.emit("pop r1".to_string());
+        self.emit("mov acc, &r1".to_string());
+        Ok(())
+    }
+
+    // Leaves the address of what `expression` names in acc
+    fn address(&mut self, expression: &Expression) -> Result<(), CompileError> {
+        match expression {
+            Expression::Name(name) => {
+                if let Some(slot) = self.local(name) {
+                    self.local_address(slot);
+                    return Ok(());
+                }
+                match self.globals.get(name) {
+                    Some(Global::Var) => self.emit(format!("mov ${}, acc", reference(name))),
+                    Some(_) => return Err(self.error("can't assign to this".to_string())),
+                    None => return Err(self.error(format!("`{}` isn't defined", name))),
+                }
+            }
+            Expression::Unary("*", pointer) => self.expression(pointer)?,
+            Expression::Index(pointer, index) => {
+                self.expression(pointer)?;
+                match self.constant(index) {
+                    Some(index) => {
+                        self.emit("mov acc, r1".to_string());
+                        self.emit(format!("mov ${:04X}, r2", index.wrapping_mul(2)));
+                    }
+                    None => {
+                        self.emit("psh acc".to_string());
+                        self.expression(index)?;
+                        self.emit("add acc, acc".to_string());
+                        self.emit("mov acc, r2".to_string());
+                        self.emit("pop r1".to_string());
+                    }
+                }
+                self.emit("add r1, r2".to_string());
+            }
+            _ => return Err(self.error("can't assign to this".to_string())),
+        }
+        Ok(())
+    }
+
+    // The value of an expression that doesn't depend on anything at runtime
+    fn constant(&self, expression: &Expression) -> Option<u16> {
+        Some(match expression {
+            Expression::Number(n) => *n,
+            Expression::Name(name) if self.local(name).is_none() => match self.globals.get(name)? {
+                Global::Const(value) => *value,
+                _ => return None,
+            },
+            Expression::Unary(operator, operand) => {
+                let value = self.constant(operand)?;
+                match *operator {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as u16,
+                    "~" => !value,
+                    _ => return None,
+                }
+            }
+            Expression::Binary(operator, left, right) => {
+                let (left, right) = (self.constant(left)?, self.constant(right)?);
+                let (signed_left, signed_right) = (left as i16, right as i16);
+                match *operator {
+                    "||" => (left != 0 || right != 0) as u16,
+                    "&&" => (left != 0 && right != 0) as u16,
+                    "|" => left | right,
+                    "^" => left ^ right,
+                    "&" => left & right,
+                    "==" => (left == right) as u16,
+                    "!=" => (left != right) as u16,
+                    "<" => (signed_left < signed_right) as u16,
+                    ">" => (signed_left > signed_right) as u16,
+                    "<=" => (signed_left <= signed_right) as u16,
+                    ">=" => (signed_left >= signed_right) as u16,
+                    "<<" => left.checked_shl(right as u32).unwrap_or(0),
+                    ">>" => (signed_left >> right.min(15)) as u16,
+                    "+" => left.wrapping_add(right),
+                    "-" => left.wrapping_sub(right),
+                    "*" => left.wrapping_mul(right),
+                    // Left for the CPU when it would divide by zero
+                    "/" if right != 0 => signed_left.wrapping_div(signed_right) as u16,
+                    "%" if right != 0 => signed_left.wrapping_rem(signed_right) as u16,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Result<(), CompileError> {
+        if let Some(value) = self.constant(expression) {
+            self.emit(format!("mov ${:04X}, acc", value));
+            return Ok(());
+        }
+
+        match expression {
+            Expression::Number(_) => unreachable!("numbers are constant"),
+
+            Expression::Name(name) => {
+                if let Some(slot) = self.local(name) {
+                    self.local_address(slot);
+                    self.emit("mov &acc, acc".to_string());
+                    return Ok(());
+                }
+                match self.globals.get(name) {
+                    Some(Global::Var) => self.emit(format!("mov &{}, acc", reference(name))),
+                    Some(Global::Function(_)) => self.emit(format!("mov ${}, acc", reference(name))),
+                    Some(Global::Const(_)) => unreachable!("constants are constant"),
+                    None => return Err(self.error(format!("`{}` isn't defined", name))),
+                }
+            }
+
+            Expression::Unary("&", operand) => {
+                if matches!(**operand, Expression::Name(_) | Expression::Unary("*", _) | Expression::Index(..)) {
+                    self.address(operand)?;
+                } else {
+                    return Err(self.error("can't take the address of this".to_string()));
+                }
+            }
+
+            Expression::Unary(operator, operand) => {
+                self.expression(operand)?;
+                match *operator {
+                    "-" => self.emit("neg acc".to_string()),
+                    "~" => self.emit("not acc".to_string()),
+                    "!" => {
+                        self.emit("mov $0000, r2".to_string());
+                        self.compare("jeq");
+                    }
+                    "*" => self.emit("mov &acc, acc".to_string()),
+                    _ => unreachable!("the parser only makes these unary operators"),
+                }
+            }
+
+            Expression::Binary(operator @ ("&&" | "||"), left, right) => {
+                // Short circuits to `done` with acc at 0 for `&&` or 1 for `||`
+                let (skip_on, done_value) = match *operator {
+                    "&&" => ("jeq", 0),
+                    _ => ("jne", 1),
+                };
+                let done = self.new_label();
+                let end = self.new_label();
+
+                for operand in [left, right] {
+                    self.expression(operand)?;
+                    self.emit(format!("{} $0000, &{}", skip_on, done));
+                }
+                self.emit(format!("mov ${:04X}, acc", 1 - done_value));
+                self.emit(format!("jmp &{}", end));
+                self.emit_label(&done);
+                self.emit(format!("mov ${:04X}, acc", done_value));
+                self.emit_label(&end);
+            }
+
+            Expression::Binary(operator, left, right) => {
+                let instruction = match *operator {
+                    "+" => "add",
+                    "-" => "sub",
+                    "*" => "mul",
+                    "/" => "sdiv",
+                    "%" => "smod",
+                    "&" => "and",
+                    "|" => "or",
+                    "^" => "xor",
+                    "<<" => "lsf",
+                    ">>" => "asr",
+                    "==" => "jeq",
+                    "!=" => "jne",
+                    "<" => "jlts",
+                    ">" => "jgts",
+                    "<=" => "jles",
+                    ">=" => "jges",
+                    _ => return Err(self.error(format!("`{}` isn't supported", operator))),
+                };
+
+                self.expression(left)?;
+                match self.constant(right) {
+                    Some(value) => {
+                        self.emit("mov acc, r1".to_string());
+                        self.emit(format!("mov ${:04X}, r2", value));
+                    }
+                    None => {
+                        self.emit("psh acc".to_string());
+                        self.expression(right)?;
+                        self.emit("mov acc, r2".to_string());
+                        self.emit("pop r1".to_string());
+                    }
+                }
+
+                if instruction.starts_with('j') {
+                    self.emit("mov r1, acc".to_string());
+                    self.compare(instruction);
+                } else if instruction == "sdiv" {
+                    // The remainder has to go somewhere
+                    self.emit("sdiv r1, r2, r2".to_string());
+                } else {
+                    self.emit(format!("{} r1, r2", instruction));
+                }
+            }
+
+            Expression::Index(..) => {
+                self.address(expression)?;
+                self.emit("mov &acc, acc".to_string());
+            }
+
+            Expression::Call(callee, arguments) => {
+                for argument in arguments {
+                    self.expression(argument)?;
+                    self.emit("psh acc".to_string());
+                }
+                self.emit(format!("psh ${:04X}", arguments.len()));
+
+                match &**callee {
+                    Expression::Name(name) if self.local(name).is_none() => match self.globals.get(name) {
+                        Some(Global::Function(parameters)) => {
+                            if *parameters != arguments.len() {
+                                return Err(self.error(format!(
+                                    "`{}` takes {} argument{}, not {}",
+                                    name, parameters, plural(*parameters), arguments.len(),
+                                )));
+                            }
+                            self.emit(format!("cal ${}", reference(name)));
+                        }
+                        Some(Global::Var) => {
+                            self.emit(format!("mov &{}, acc", reference(name)));
+                            self.emit("cal acc".to_string());
+                        }
+                        Some(Global::Const(value)) => self.emit(format!("cal ${:04X}", value)),
+                        None => {
+                            self.externs.insert(name.clone());
+                            self.emit(format!("cal ${}", reference(name)));
+                        }
+                    },
+                    callee => {
+                        self.expression(callee)?;
+                        self.emit("cal acc".to_string());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Sets acc to 1 if `jump acc, r2` would jump and 0 if it wouldn't
+    fn compare(&mut self, jump: &str) {
+        let yes = self.new_label();
+        let end = self.new_label();
+        self.emit(format!("{} r2, &{}", jump, yes));
+        self.emit("mov $0000, acc".to_string());
+        self.emit(format!("jmp &{}", end));
+        self.emit_label(&yes);
+        self.emit("mov $0001, acc".to_string());
+        self.emit_label(&end);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::assembler::expression::parse_char;
+use crate::compiler::CompileError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+    Number(u16),
+    Name(String),
+    Keyword(&'static str),
+    // Operators and punctuation
+    Symbol(&'static str),
+    End,
+}
+
+const KEYWORDS: &[&str] = &["fn", "var", "const", "if", "else", "while", "return", "break", "continue"];
+
+// Longest first so `<<` isn't read as two `<`
+const SYMBOLS: &[&str] = &[
+    "<<", ">>", "==", "!=", "<=", ">=", "&&", "||",
+    "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "<", ">", "=",
+    "(", ")", "{", "}", "[", "]", ",", ";",
+];
+
+// Tokens with the line each starts on, ending with Token::End
+pub fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let mut tokens = vec![];
+
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let error = |message: String| CompileError { line, message };
+        let mut rest = text.trim_start();
+
+        while let Some(c) = rest.chars().next() {
+            if rest.starts_with("//") {
+                break;
+            }
+
+            if c.is_ascii_digit() {
+                let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                let number = parse_number(&rest[..end])
+                    .ok_or_else(|| error(format!("invalid number `{}`", &rest[..end])))?;
+                tokens.push((Token::Number(number), line));
+                rest = &rest[end..];
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                let name = &rest[..end];
+                let token = match KEYWORDS.iter().find(|k| **k == name) {
+                    Some(keyword) => Token::Keyword(keyword),
+                    None => Token::Name(name.to_string()),
+                };
+                tokens.push((token, line));
+                rest = &rest[end..];
+            } else if c == '\'' {
+                let (value, len) = parse_char(&rest[1..])
+                    .filter(|(c, len)| c.is_ascii() && rest[1 + len..].starts_with('\''))
+                    .ok_or_else(|| error("invalid character literal".to_string()))?;
+                tokens.push((Token::Number(value as u16), line));
+                rest = &rest[len + 2..];
+            } else {
+                let symbol = SYMBOLS.iter().find(|s| rest.starts_with(**s))
+                    .ok_or_else(|| error(format!("unexpected `{}`", c)))?;
+                tokens.push((Token::Symbol(symbol), line));
+                rest = &rest[symbol.len()..];
+            }
+
+            rest = rest.trim_start();
+        }
+    }
+
+    tokens.push((Token::End, source.lines().count().max(1)));
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
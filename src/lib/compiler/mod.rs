@@ -0,0 +1,227 @@
+// A compiler for a tiny C-like language, producing assembly for the assembler. Every value is a 16-bit word:
+//
+//     const SCREEN = 0x3000;
+//     var count = 3;
+//
+//     fn main() {
+//         var i = 0;
+//         while (i < count) {
+//             *(SCREEN + i) = 'A' + i;
+//             i = i + 1;
+//         }
+//     }
+//
+// Globals are `var name [= constant];` and `const name = constant;`, functions are `fn name(a, b) { ... }`. Inside
+// a function there are `var` declarations scoped to their block, assignments, `if`/`else`, `while` with `break`
+// and `continue`, and `return [value];`. Expressions have C's operators and precedence, with division, `>>` and
+// comparisons being signed like C's `int`. `*p` is the word at address `p`, `p[i]` the word at `p + i * 2` and `&x` the
+// address of a variable.
+//
+// Functions follow the calling convention in docs/abi.md, so they can call and be called from assembly. Calling
+// a name that isn't defined in the file calls a label from another object. When there's a `main` function the
+// program starts by calling it and halts once it returns
+mod codegen;
+pub mod lexer;
+pub mod parser;
+
+use crate::compiler::codegen::Generator;
+use crate::compiler::lexer::tokenize;
+use crate::compiler::parser::Parser;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// The assembly for a whole source file
+pub fn compile(source: &str) -> Result<String, CompileError> {
+    let items = Parser::new(tokenize(source)?).program()?;
+    Generator::new(source).generate(&items)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+    use crate::compiler::{compile, CompileError};
+    use crate::cpu::CPU;
+    use crate::cpu::register::Register;
+    use crate::create_memory::create_memory;
+    use crate::devices::device::Device;
+    use crate::devices::memory::Memory;
+
+    // Runs a program whose `main` leaves its result in the global `result`
+    fn run(source: &str) -> u16 {
+        let assembly = compile(source)
+            .unwrap();
+        let program = Assembler::new().assemble("test.asm", &assembly)
+            .unwrap();
+
+        let mut memory = create_memory(256 * 256);
+        memory[..program.bytes.len()].copy_from_slice(&program.bytes);
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.run()
+            .unwrap();
+
+        let result = program.debug_info.symbols.address_of("result")
+            .expect("the program has a `result` global");
+        let value = cpu.memory().read_at_u16(result as usize);
+        assert_eq!(cpu.get_register(Register::Sp), 0xFFFE);
+        value.unwrap()
+    }
+
+    #[test]
+    fn expressions() {
+        let result = |expression: &str| run(&format!("var result; fn main() {{ result = {}; }}", expression));
+
+        assert_eq!(result("1 + 2 * 3"), 7);
+        assert_eq!(result("(1 + 2) * 3"), 9);
+        assert_eq!(result("3 - 5"), 0xFFFE);
+        assert_eq!(result("-1 & 0xFF"), 0xFF);
+        assert_eq!(result("'A' | 1 << 8"), 0x0141);
+        assert_eq!(result("0x8000 >> 15"), 0xFFFF);
+        assert_eq!(result("2 < 3 && 3 <= 3 && 4 > 3 && 4 >= 4 && 1 == 1 && 1 != 2"), 1);
+        assert_eq!(result("0 || !5 || ~0xFFFF"), 0);
+    }
+
+    #[test]
+    fn runtime_expressions() {
+        // Variables so nothing is worked out by the compiler
+        let result = |expression: &str| run(&format!("
+            var result;
+            var zero = 0;
+            var one = 1;
+            var two = 2;
+            var three = 3;
+            var big = 0x8000;
+
+            fn main() {{ result = {}; }}
+        ", expression));
+
+        assert_eq!(result("one + two * three"), 7);
+        assert_eq!(result("(one + two) * three"), 9);
+        assert_eq!(result("three - 5"), 0xFFFE);
+        assert_eq!(result("-one & 0xFF"), 0xFF);
+        assert_eq!(result("'A' | one << 8"), 0x0141);
+        assert_eq!(result("big >> 15"), 0xFFFF);
+        assert_eq!(result("100 / (three + 4) * 10 + 100 % (three + 4)"), 142);
+        assert_eq!(result("two < three && three <= 3 && 4 > three && three >= three && one == 1 && one != two"), 1);
+        assert_eq!(result("three < two || 4 <= three || zero > 0 || two >= three || one == two || one != 1"), 0);
+        assert_eq!(result("zero || !three || ~(big | 0x7FFF)"), 0);
+        assert_eq!(result("!zero + (1 + 2) * 3"), 10);
+    }
+
+    #[test]
+    fn signed_arithmetic() {
+        let folded = |expression: &str| run(&format!("var result; fn main() {{ result = {}; }}", expression));
+        let result = |expression: &str| run(&format!("
+            var result;
+            var zero = 0;
+            var two = 2;
+            var minus_one = -1;
+            var minus_seven = -7;
+
+            fn main() {{ result = {}; }}
+        ", expression));
+
+        assert_eq!(folded("-1 < 0 && 0 > -1 && -7 <= -1 && -1 >= -7 && !(-7 > 2)"), 1);
+        assert_eq!(result("minus_one < zero && zero > minus_one && minus_seven <= minus_one && minus_one >= minus_seven"), 1);
+        assert_eq!(result("minus_seven > two"), 0);
+
+        assert_eq!(folded("-7 / 2"), (-3i16) as u16);
+        assert_eq!(result("minus_seven / two"), (-3i16) as u16);
+        assert_eq!(folded("-7 % 2"), (-1i16) as u16);
+        assert_eq!(result("minus_seven % two"), (-1i16) as u16);
+        assert_eq!(folded("-7 >> 2"), (-2i16) as u16);
+        assert_eq!(result("minus_seven >> two"), (-2i16) as u16);
+    }
+
+    #[test]
+    fn functions_and_loops() {
+        let source = "
+            const N = 10;
+            var result;
+
+            // Sums 1..=n without a multiply
+            fn sum(n) {
+                var total = 0;
+                var i = 1;
+                while (1) {
+                    if (i > n) {
+                        break;
+                    }
+                    total = total + i;
+                    i = i + 1;
+                }
+                return total;
+            }
+
+            fn fib(n) {
+                if (n < 2) {
+                    return n;
+                }
+                return fib(n - 1) + fib(n - 2);
+            }
+
+            fn main() {
+                result = sum(N) * 1000 + fib(N);
+            }
+        ";
+        assert_eq!(run(source), 55 * 1000 + 55);
+    }
+
+    #[test]
+    fn pointers() {
+        let source = "
+            var table = 0x8000;
+            var result;
+
+            fn fill(p, n, value) {
+                var i = 0;
+                while (i < n) {
+                    p[i] = value + i;
+                    i = i + 1;
+                }
+            }
+
+            fn swap(a, b) {
+                var t = *a;
+                *a = *b;
+                *b = t;
+            }
+
+            fn main() {
+                fill(table, 4, 10);
+                var x = 1;
+                var y = 2;
+                swap(&x, &y);
+                if (x == 2 && y == 1) {
+                    result = table[0] + table[3] + *(table + 2);
+                } else {
+                    result = 0xBAD;
+                }
+            }
+        ";
+        assert_eq!(run(source), 10 + 13 + 11);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source: &str| compile(source).unwrap_err();
+
+        assert_eq!(error("fn main() {\n  x = 1;\n}"), CompileError { line: 2, message: "`x` isn't defined".to_string() });
+        assert_eq!(error("fn main() { break; }").message, "`break` outside of a loop");
+        assert_eq!(error("fn f(a) {}\nfn main() { f(); }").message, "`f` takes 1 argument, not 0");
+        assert_eq!(error("var x = y;").message, "the value of `x` must be constant");
+        assert_eq!(error("fn main() { var a; var a; }").message, "`a` is already declared");
+        assert_eq!(error("var acc;").message, "`acc` is the name of a register");
+        assert_eq!(error("fn main() { 1 = 2; }").message, "can't assign to this");
+        assert_eq!(error("fn main() {\n  if (1) {\n").line, 2);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::compiler::CompileError;
+use crate::compiler::lexer::Token;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+    Number(u16),
+    Name(String),
+    // `-`, `!`, `~`, `*` (the word at an address) and `&` (the address of a variable)
+    Unary(&'static str, Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+    // `pointer[index]`, the word `index * 2` bytes after `pointer`
+    Index(Box<Expression>, Box<Expression>),
+    Call(Box<Expression>, Vec<Expression>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StatementKind {
+    Var(String, Option<Expression>),
+    Assign(Expression, Expression),
+    Expression(Expression),
+    If(Expression, Vec<Statement>, Vec<Statement>),
+    While(Expression, Vec<Statement>),
+    Return(Option<Expression>),
+    Break,
+    Continue,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Statement {
+    pub line: usize,
+    pub kind: StatementKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub line: usize,
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Item {
+    Var { line: usize, name: String, value: Option<Expression> },
+    Const { line: usize, name: String, value: Expression },
+    Function(Function),
+}
+
+fn precedence(operator: &str) -> Option<u8> {
+    Some(match operator {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    })
+}
+
+pub struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<(Token, usize)>) -> Self {
+        Self {
+            tokens,
+            position: 0,
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.position].1
+    }
+
+    fn error(&self, message: String) -> CompileError {
+        CompileError {
+            line: self.line(),
+            message,
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> CompileError {
+        let found = match self.peek() {
+            Token::Number(n) => n.to_string(),
+            Token::Name(name) => name.clone(),
+            Token::Keyword(keyword) => keyword.to_string(),
+            Token::Symbol(symbol) => symbol.to_string(),
+            Token::End => return self.error(format!("expected {}, found the end of the file", expected)),
+        };
+        self.error(format!("expected {}, found `{}`", expected, found))
+    }
+
+    fn accept(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Token::Symbol(s) if *s == symbol) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), CompileError> {
+        if self.accept(symbol) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", symbol)))
+        }
+    }
+
+    fn accept_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Token::Keyword(k) if *k == keyword) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn name(&mut self) -> Result<String, CompileError> {
+        match self.peek() {
+            Token::Name(name) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    pub fn program(&mut self) -> Result<Vec<Item>, CompileError> {
+        let mut items = vec![];
+
+        while *self.peek() != Token::End {
+            let line = self.line();
+            if self.accept_keyword("fn") {
+                items.push(Item::Function(self.function(line)?));
+            } else if self.accept_keyword("var") {
+                let name = self.name()?;
+                let value = match self.accept("=") {
+                    true => Some(self.expression(0)?),
+                    false => None,
+                };
+                self.expect(";")?;
+                items.push(Item::Var { line, name, value });
+            } else if self.accept_keyword("const") {
+                let name = self.name()?;
+                self.expect("=")?;
+                let value = self.expression(0)?;
+                self.expect(";")?;
+                items.push(Item::Const { line, name, value });
+            } else {
+                return Err(self.unexpected("`fn`, `var` or `const`"));
+            }
+        }
+
+        Ok(items)
+    }
+
+    fn function(&mut self, line: usize) -> Result<Function, CompileError> {
+        let name = self.name()?;
+
+        self.expect("(")?;
+        let mut parameters = vec![];
+        if !self.accept(")") {
+            loop {
+                parameters.push(self.name()?);
+                if self.accept(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+
+        Ok(Function {
+            line,
+            name,
+            parameters,
+            body: self.block()?,
+        })
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, CompileError> {
+        self.expect("{")?;
+        let mut statements = vec![];
+        while !self.accept("}") {
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, CompileError> {
+        let line = self.line();
+
+        let kind = if self.accept_keyword("var") {
+            let name = self.name()?;
+            let value = match self.accept("=") {
+                true => Some(self.expression(0)?),
+                false => None,
+            };
+            self.expect(";")?;
+            StatementKind::Var(name, value)
+        } else if self.accept_keyword("if") {
+            return self.if_statement(line);
+        } else if self.accept_keyword("while") {
+            self.expect("(")?;
+            let condition = self.expression(0)?;
+            self.expect(")")?;
+            StatementKind::While(condition, self.block()?)
+        } else if self.accept_keyword("return") {
+            let value = match self.accept(";") {
+                true => return Ok(Statement { line, kind: StatementKind::Return(None) }),
+                false => Some(self.expression(0)?),
+            };
+            self.expect(";")?;
+            StatementKind::Return(value)
+        } else if self.accept_keyword("break") {
+            self.expect(";")?;
+            StatementKind::Break
+        } else if self.accept_keyword("continue") {
+            self.expect(";")?;
+            StatementKind::Continue
+        } else {
+            let expression = self.expression(0)?;
+            let kind = match self.accept("=") {
+                true => StatementKind::Assign(expression, self.expression(0)?),
+                false => StatementKind::Expression(expression),
+            };
+            self.expect(";")?;
+            kind
+        };
+
+        Ok(Statement { line, kind })
+    }
+
+    // After the `if`
+    fn if_statement(&mut self, line: usize) -> Result<Statement, CompileError> {
+        self.expect("(")?;
+        let condition = self.expression(0)?;
+        self.expect(")")?;
+        let then = self.block()?;
+
+        let otherwise = if !self.accept_keyword("else") {
+            vec![]
+        } else if self.accept_keyword("if") {
+            let line = self.line();
+            vec![self.if_statement(line)?]
+        } else {
+            self.block()?
+        };
+
+        Ok(Statement {
+            line,
+            kind: StatementKind::If(condition, then, otherwise),
+        })
+    }
+
+    // Precedence climbing, all binary operators are left associative
+    pub fn expression(&mut self, min_precedence: u8) -> Result<Expression, CompileError> {
+        let mut left = self.unary()?;
+
+        while let Token::Symbol(operator) = *self.peek() {
+            let precedence = match precedence(operator) {
+                Some(precedence) if precedence > min_precedence => precedence,
+                _ => break,
+            };
+            self.position += 1;
+
+            let right = self.expression(precedence)?;
+            left = Expression::Binary(operator, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, CompileError> {
+        match *self.peek() {
+            Token::Symbol(operator @ ("-" | "!" | "~" | "*" | "&")) => {
+                self.position += 1;
+                Ok(Expression::Unary(operator, Box::new(self.unary()?)))
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expression, CompileError> {
+        let mut expression = self.primary()?;
+
+        loop {
+            if self.accept("(") {
+                let mut arguments = vec![];
+                if !self.accept(")") {
+                    loop {
+                        arguments.push(self.expression(0)?);
+                        if self.accept(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                expression = Expression::Call(Box::new(expression), arguments);
+            } else if self.accept("[") {
+                let index = self.expression(0)?;
+                self.expect("]")?;
+                expression = Expression::Index(Box::new(expression), Box::new(index));
+            } else {
+                return Ok(expression);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expression, CompileError> {
+        match self.peek().clone() {
+            Token::Number(n) => {
+                self.position += 1;
+                Ok(Expression::Number(n))
+            }
+            Token::Name(name) => {
+                self.position += 1;
+                Ok(Expression::Name(name))
+            }
+            Token::Symbol("(") => {
+                self.position += 1;
+                let expression = self.expression(0)?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use crate::cpu::disassemble::{decode, Disassembled};
+use crate::cpu::instructions::{instruction_info, is_conditional_jump};
+use crate::cpu::trace::{TraceEntry, Tracer};
+use crate::debug_info::DebugInfo;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+// A Tracer recording how often each instruction ran and which ways each conditional jump went
+#[derive(Default)]
+pub struct Coverage {
+    executed: BTreeMap<u16, u64>,
+    branches: BTreeMap<u16, BranchCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn executed(&self) -> &BTreeMap<u16, u64> {
+        &self.executed
+    }
+
+    pub fn branches(&self) -> &BTreeMap<u16, BranchCoverage> {
+        &self.branches
+    }
+
+    // The instructions in `code` (loaded at `base`) with the source line of each. Line info only lists statements, so
+    // with it the ones that aren't a single instruction are data and left out. Without it every byte that starts an
+    // instruction is taken for one and the rest are skipped
+    fn instructions<'a>(code: &[u8], base: u16, debug_info: Option<&'a DebugInfo>) -> Vec<(Disassembled, Option<(&'a str, usize)>)> {
+        let end = base as usize + code.len();
+        let decode_at = |address: usize| decode(address, &code[address - base as usize..])
+            .filter(|instruction| instruction_info(instruction.bytes[0]).is_some());
+
+        match debug_info.filter(|info| !info.lines().is_empty()) {
+            Some(info) => info.lines().iter()
+                .filter(|line| (base as usize..end).contains(&(line.address as usize)))
+                .filter_map(|line| {
+                    let instruction = decode_at(line.address as usize)
+                        .filter(|instruction| instruction.bytes.len() == line.size as usize)?;
+                    Some((instruction, Some((line.file.as_str(), line.line))))
+                })
+                .collect(),
+            None => {
+                let mut instructions = vec![];
+                let mut address = base as usize;
+                while address < end {
+                    match decode_at(address) {
+                        Some(instruction) => {
+                            address += instruction.bytes.len();
+                            instructions.push((instruction, None));
+                        }
+                        None => address += 1,
+                    }
+                }
+                instructions
+            }
+        }
+    }
+
+    // Lists every instruction in `code` (loaded at `base`) with its execution count and source line, marking the
+    // ones that never ran with ##### and the conditional jumps that only ever went one way
+    pub fn report(&self, code: &[u8], base: u16, debug_info: Option<&DebugInfo>) -> String {
+        let mut lines = vec![];
+        let mut instructions = 0;
+        let mut covered = 0;
+        let mut directions = 0;
+        let mut covered_directions = 0;
+
+        for (instruction, location) in Self::instructions(code, base, debug_info) {
+            let address = instruction.address as u16;
+
+            if let Some(info) = debug_info {
+                if let Some((name, 0)) = info.symbols.lookup(address) {
+                    lines.push(format!("{}:", name));
+                }
+            }
+
+            instructions += 1;
+            let count = match self.executed.get(&address) {
+                Some(count) => {
+                    covered += 1;
+                    count.to_string()
+                }
+                None => "#####".to_string(),
+            };
+
+            let mut line = match location {
+                Some((file, line)) => format!("{:>9}  {}:{}  {}", count, file, line, instruction),
+                None => format!("{:>9}  {}", count, instruction),
+            };
+
+            if is_conditional_jump(instruction.bytes[0]) {
+                let branch = self.branches.get(&address).copied().unwrap_or_default();
+                directions += 2;
+                covered_directions += (branch.taken > 0) as usize + (branch.not_taken > 0) as usize;
+                line.push_str(&format!("    [taken {}, not taken {}]", branch.taken, branch.not_taken));
+            }
+
+            lines.push(line);
+        }
+
+        let percent = |part: usize, whole: usize| if whole == 0 { 100.0 } else { part as f64 * 100.0 / whole as f64 };
+        let mut report = format!(
+            "instructions: {}/{} ({:.1}%)\nbranch directions: {}/{} ({:.1}%)\n\n",
+            covered, instructions, percent(covered, instructions),
+            covered_directions, directions, percent(covered_directions, directions),
+        );
+        report.push_str(&lines.join("\n"));
+        report.push('\n');
+        report
+    }
+}
+
+impl Tracer for Coverage {
+    fn trace(&mut self, entry: &TraceEntry) {
+        // Taking an interrupt doesn't run the instruction at ip, and a block instruction's later chunks aren't
+        // another run of it
+        if entry.is_interrupt() || entry.continued {
+            return;
+        }
+
+        *self.executed.entry(entry.ip).or_default() += 1;
+
+        if is_conditional_jump(entry.opcode) {
+            let branch = self.branches.entry(entry.ip).or_default();
+            let fall_through = entry.ip as usize + 1 + entry.operands.len();
+            if entry.next_ip as usize == fall_through {
+                branch.not_taken += 1;
+            } else {
+                branch.taken += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::assembler::Assembler;
+    use crate::coverage::Coverage;
+    use crate::cpu::CPU;
+    use crate::cpu::instructions::*;
+    use crate::debug_info::DebugInfo;
+    use crate::devices::memory::Memory;
+
+    fn covered_run(program: &[u8]) -> Coverage {
+        let mut memory = program.to_vec();
+        memory.resize(0x100, 0);
+
+        let coverage = Rc::new(RefCell::new(Coverage::new()));
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_tracer(Box::new(coverage.clone()));
+        cpu.run().unwrap();
+
+        drop(cpu);
+        Rc::try_unwrap(coverage)
+            .ok()
+            .expect("the CPU let go of the tracer")
+            .into_inner()
+    }
+
+    #[test]
+    fn instructions_and_branches() {
+        let program = [
+            MOV_LIT_REG, 0x00, 0x01, 1,
+            JMP_NOT_EQ, 0x00, 0x01, 0x00, 0x12,
+            JMP_NOT_EQ, 0x00, 0x02, 0x00, 0x12,
+            MOV_LIT_REG, 0x00, 0x02, 1,
+            HLT,
+        ];
+        let coverage = covered_run(&program);
+        assert_eq!(coverage.branches()[&0x04].not_taken, 1);
+        assert_eq!(coverage.branches()[&0x09].taken, 1);
+
+        let mut debug_info = DebugInfo::new();
+        debug_info.symbols = "0x0000 start\n0x000E unreachable".parse().unwrap();
+        let report = coverage.report(&program, 0, Some(&debug_info));
+        assert!(report.starts_with("instructions: 4/5 (80.0%)\nbranch directions: 2/4 (50.0%)\n"));
+        assert!(report.contains("unreachable:\n    #####  0x000E"));
+        assert!(report.contains("[taken 0, not taken 1]"));
+
+        // Bytes that aren't instructions are skipped rather than ending the report
+        let mut with_junk = program.to_vec();
+        with_junk.extend([0xEE, HLT, MOV_LIT_REG]);
+        let report = coverage.report(&with_junk, 0, None);
+        assert!(report.starts_with("instructions: 4/6"));
+        assert!(report.ends_with("#####  0x0014: FF             hlt\n"));
+    }
+
+    #[test]
+    fn block_instructions() {
+        // Setting this many bytes takes several chunks, but it's one execution of mset
+        let length = (BLOCK_CHUNK_SIZE * 2 + 8) as u8;
+        let coverage = covered_run(&[
+            MOV_LIT_REG, 0x00, 0x80, 2,
+            MOV_LIT_REG, 0x00, length, 3,
+            MSET_REG_REG_REG, 1, 2, 3,
+            HLT,
+        ]);
+        assert_eq!(coverage.executed()[&0x08], 1);
+    }
+
+    #[test]
+    fn source_lines() {
+        let program = Assembler::new().assemble("test.asm", "
+            mov &value, r1
+            jmp &done
+            hlt
+            value: .word 0x1000
+            done: hlt
+        ").unwrap();
+        let coverage = covered_run(&program.bytes);
+
+        // The word at `value` would decode as an instruction, the line info says it's data
+        let report = coverage.report(&program.bytes, 0, Some(&program.debug_info));
+        assert!(report.starts_with("instructions: 3/4 (75.0%)\n"));
+        assert!(report.contains("        1  test.asm:2  0x0000"));
+        assert!(report.contains("    #####  test.asm:4  0x0007"));
+        assert!(report.contains("done:\n        1  test.asm:6  0x000A"));
+    }
+}
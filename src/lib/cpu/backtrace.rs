@@ -0,0 +1,72 @@
+use std::fmt;
+use crate::cpu::register::Register;
+use crate::symbols::SymbolTable;
+
+// Offsets from a frame's Fp to what push_state saved, see CPU::push_state
+pub const FRAME_SIZE_OFFSET: u16 = 2;
+pub const RETURN_ADDRESS_OFFSET: u16 = 4;
+pub const SAVED_REGISTERS_OFFSET: u16 = 6;
+pub const ARGUMENT_COUNT_OFFSET: u16 = 22;
+pub const ARGUMENTS_OFFSET: u16 = 24;
+
+// Bytes push_state adds to the stack: R1-R8, Ip and the frame size
+pub const SAVED_STATE_SIZE: u16 = 20;
+
+// push_state saves R8 closest to Fp and R1 furthest from it
+pub const SAVED_REGISTERS: [Register; 8] = [
+    Register::R8,
+    Register::R7,
+    Register::R6,
+    Register::R5,
+    Register::R4,
+    Register::R3,
+    Register::R2,
+    Register::R1,
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_pointer: u16,
+    pub return_address: u16,
+    // The caller's registers, as they'll be restored by RET
+    pub saved_registers: Vec<(Register, u16)>,
+    // In the order the caller pushed them
+    pub arguments: Vec<u16>,
+}
+
+// The current ip followed by the frames of every subroutine call it's nested in, innermost first
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backtrace {
+    pub ip: u16,
+    pub frames: Vec<Frame>,
+}
+
+impl Backtrace {
+    pub fn format(&self, symbols: Option<&SymbolTable>) -> String {
+        self.format_with(&|address| match symbols {
+            Some(symbols) => symbols.format_address(address),
+            None => format!("0x{:04X}", address),
+        })
+    }
+
+    // Like format, with `name` giving each address's name, see CPU::describe_address
+    pub fn format_with(&self, name: &dyn Fn(u16) -> String) -> String {
+        let mut text = format!("#0  {}\n", name(self.ip));
+        for (i, frame) in self.frames.iter().enumerate() {
+            let arguments: Vec<String> = frame.arguments.iter().map(|a| format!("0x{:04X}", a)).collect();
+            text.push_str(&format!("#{:<2} {} (fp 0x{:04X}, args [{}])\n", i + 1, name(frame.return_address), frame.frame_pointer, arguments.join(", ")));
+
+            let registers: Vec<String> = frame.saved_registers.iter().rev()
+                .map(|(r, v)| format!("{}=0x{:04X}", r.name(), v))
+                .collect();
+            text.push_str(&format!("    {}\n", registers.join(" ")));
+        }
+        text
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format(None))
+    }
+}
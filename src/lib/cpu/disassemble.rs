@@ -0,0 +1,77 @@
+use crate::cpu::instructions::{instruction_info, Operand};
+use crate::cpu::register::Register;
+use crate::devices::device::Device;
+
+pub struct Disassembled {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+// Decodes the instruction at the start of `bytes`, or returns None if `bytes` is too short to hold it
+pub fn decode(address: usize, bytes: &[u8]) -> Option<Disassembled> {
+    let opcode = *bytes.first()?;
+
+    let info = match instruction_info(opcode) {
+        Some(info) => info,
+        None => {
+            return Some(Disassembled {
+                address,
+                bytes: vec![opcode],
+                text: format!("db ${:02X}", opcode),
+            });
+        }
+    };
+
+    let bytes = bytes.get(..info.size())?.to_vec();
+
+    let mut operands = vec![];
+    let mut offset = 1;
+    for operand in info.operands {
+        let text = match operand {
+            Operand::Literal => format!("${:02X}{:02X}", bytes[offset], bytes[offset + 1]),
+            Operand::Address => format!("&{:02X}{:02X}", bytes[offset], bytes[offset + 1]),
+            Operand::Register => Register::from_index(bytes[offset]).name().to_string(),
+            Operand::Pointer => format!("&{}", Register::from_index(bytes[offset]).name()),
+        };
+        operands.push(text);
+        offset += operand.size();
+    }
+
+    let text = if operands.is_empty() {
+        info.mnemonic.to_string()
+    } else {
+        format!("{} {}", info.mnemonic, operands.join(", "))
+    };
+
+    Some(Disassembled {
+        address,
+        bytes,
+        text,
+    })
+}
+
+// Decodes the instruction at `address`, or returns None if any of its bytes can't be read
+pub fn disassemble<D: Device + ?Sized>(device: &D, address: usize) -> Option<Disassembled> {
+    disassemble_with(|address| device.read_at_u8(address), address)
+}
+
+// Like disassemble, reading the bytes with `read`
+pub fn disassemble_with<F: Fn(usize) -> Option<u8>>(read: F, address: usize) -> Option<Disassembled> {
+    let opcode = read(address)?;
+    let size = instruction_info(opcode).map_or(1, |info| info.size());
+
+    let mut bytes = vec![];
+    for i in 0..size {
+        bytes.push(read(address + i)?);
+    }
+
+    decode(address, &bytes)
+}
+
+impl std::fmt::Display for Disassembled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(f, "0x{:04X}: {:<14} {}", self.address, bytes.join(" "), self.text)
+    }
+}
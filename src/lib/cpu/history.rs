@@ -0,0 +1,63 @@
+use std::collections::VecDeque;
+use crate::cpu::supervisor::ControlRegisters;
+
+// What an instruction changed, so it can be undone
+pub(crate) struct UndoRecord {
+    pub registers: Vec<u8>,
+    pub stack_frame_size: u16,
+    pub control: ControlRegisters,
+    pub cycles: u64,
+    pub instructions: u64,
+    // The interrupt taken instead of an instruction, queued again when the record is undone
+    pub interrupt: Option<u16>,
+    pub unfinished_block: Option<u16>,
+    // In the order the writes happened
+    pub memory_writes: Vec<MemoryWrite>,
+}
+
+pub(crate) struct MemoryWrite {
+    // The address the program used, and where it was translated to
+    pub address: usize,
+    pub physical: usize,
+    // None when the device can't be read back (like the screen) and the write can't be undone
+    pub old: Option<u16>,
+    // False for one byte of a word that was split across two pages
+    pub word: bool,
+}
+
+pub(crate) struct History {
+    records: VecDeque<UndoRecord>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, record: UndoRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+}
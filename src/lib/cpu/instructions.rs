@@ -2,12 +2,196 @@ pub const MOV_LIT_REG: u8 = 0x10;
 pub const MOV_REG_REG: u8 = 0x11;
 pub const MOV_REG_MEM: u8 = 0x12;
 pub const MOV_MEM_REG: u8 = 0x13;
+pub const MOV_PTR_REG: u8 = 0x1C;
+pub const MOV_REG_PTR: u8 = 0x1D;
 pub const ADD_REG_REG: u8 = 0x14;
+pub const SUB_REG_REG: u8 = 0x1F;
+pub const MUL_REG_REG: u8 = 0x21;
+pub const LSF_REG_REG: u8 = 0x27;
+pub const RSF_REG_REG: u8 = 0x2B;
+pub const AND_REG_REG: u8 = 0x2F;
+pub const OR_REG_REG:  u8 = 0x31;
+pub const XOR_REG_REG: u8 = 0x33;
+pub const NOT_REG:     u8 = 0x34;
+pub const NEG_REG:     u8 = 0x37;
+pub const SXB_REG:     u8 = 0x38;
+pub const ASR_REG_REG: u8 = 0x2A;
+pub const SCMP_REG_REG: u8 = 0x39;
+pub const DIV_REG_REG: u8 = 0x22;
+pub const DIV_REG_LIT: u8 = 0x23;
+pub const MOD_REG_REG: u8 = 0x24;
+pub const MOD_REG_LIT: u8 = 0x25;
+pub const SDIV_REG_REG: u8 = 0x28;
+pub const SDIV_REG_LIT: u8 = 0x29;
+pub const SMOD_REG_REG: u8 = 0x2C;
+pub const SMOD_REG_LIT: u8 = 0x2D;
 pub const JMP_NOT_EQ:  u8 = 0x15;
+pub const JEQ_REG:     u8 = 0x3E;
+pub const JNE_REG:     u8 = 0x40;
+pub const JEQ_LIT:     u8 = 0x41;
+pub const JLT_REG:     u8 = 0x42;
+pub const JGT_REG:     u8 = 0x44;
+pub const JLE_REG:     u8 = 0x46;
+pub const JGE_REG:     u8 = 0x48;
+pub const JMP_LIT:     u8 = 0x4A;
+pub const JLTS_REG:    u8 = 0x4B;
+pub const JGTS_REG:    u8 = 0x4C;
+pub const JLES_REG:    u8 = 0x4D;
+pub const JGES_REG:    u8 = 0x4E;
 pub const PSH_LIT:     u8 = 0x17;
 pub const PSH_REG:     u8 = 0x18;
 pub const POP:         u8 = 0x1A;
 pub const CAL_LIT:     u8 = 0x5E;
 pub const CAL_REG:     u8 = 0x5F;
 pub const RET:         u8 = 0x60;
-pub const HLT:         u8 = 0xFF;
\ No newline at end of file
+pub const ARG_LIT_REG: u8 = 0x61;
+pub const MCPY_REG_REG_REG: u8 = 0x62;
+pub const MSET_REG_REG_REG: u8 = 0x63;
+pub const SYSCALL:     u8 = 0x70;
+pub const SRET:        u8 = 0x71;
+pub const STT_LIT:     u8 = 0x72;
+pub const SSP_LIT:     u8 = 0x73;
+pub const USR_LIT:     u8 = 0x74;
+pub const CLI:         u8 = 0x75;
+pub const STI:         u8 = 0x76;
+pub const PTB_LIT:     u8 = 0x77;
+pub const PGE:         u8 = 0x78;
+pub const PGD:         u8 = 0x79;
+pub const FLT_REG:     u8 = 0x7A;
+pub const HLT:         u8 = 0xFF;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    // Two byte literal, written `$1234`
+    Literal,
+    // One byte register index, written `r1`
+    Register,
+    // Two byte memory address, written `&3000`
+    Address,
+    // One byte index of a register holding a memory address, written `&r1`
+    Pointer,
+}
+
+impl Operand {
+    pub fn size(self) -> usize {
+        match self {
+            Operand::Literal => 2,
+            Operand::Register => 1,
+            Operand::Address => 2,
+            Operand::Pointer => 1,
+        }
+    }
+}
+
+pub struct InstructionInfo {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub operands: &'static [Operand],
+    // Cycles spent decoding and executing, not counting data memory accesses (see MEMORY_ACCESS_CYCLES)
+    pub cycles: u64,
+}
+
+impl InstructionInfo {
+    pub fn size(&self) -> usize {
+        1 + self.operands.iter().map(|o| o.size()).sum::<usize>()
+    }
+}
+
+const fn info(opcode: u8, mnemonic: &'static str, operands: &'static [Operand], cycles: u64) -> InstructionInfo {
+    InstructionInfo {
+        opcode,
+        mnemonic,
+        operands,
+        cycles,
+    }
+}
+
+// Added for every word the CPU reads from or writes to memory while executing an instruction
+pub const MEMORY_ACCESS_CYCLES: u64 = 3;
+
+// The most bytes MCPY_REG_REG_REG and MSET_REG_REG_REG copy or set in one step before starting again for the rest
+pub const BLOCK_CHUNK_SIZE: u16 = 16;
+
+use Operand::*;
+
+pub const INSTRUCTIONS: &[InstructionInfo] = &[
+    info(MOV_LIT_REG, "mov", &[Literal, Register], 2),
+    info(MOV_REG_REG, "mov", &[Register, Register], 1),
+    info(MOV_REG_MEM, "mov", &[Register, Address], 2),
+    info(MOV_MEM_REG, "mov", &[Address, Register], 2),
+    info(MOV_PTR_REG, "mov", &[Pointer, Register], 2),
+    info(MOV_REG_PTR, "mov", &[Register, Pointer], 2),
+    info(ADD_REG_REG, "add", &[Register, Register], 1),
+    info(SUB_REG_REG, "sub", &[Register, Register], 1),
+    info(MUL_REG_REG, "mul", &[Register, Register], 2),
+    info(LSF_REG_REG, "lsf", &[Register, Register], 1),
+    info(RSF_REG_REG, "rsf", &[Register, Register], 1),
+    info(AND_REG_REG, "and", &[Register, Register], 1),
+    info(OR_REG_REG,  "or",  &[Register, Register], 1),
+    info(XOR_REG_REG, "xor", &[Register, Register], 1),
+    info(NOT_REG,     "not", &[Register], 1),
+    info(NEG_REG,     "neg", &[Register], 1),
+    info(SXB_REG,     "sxb", &[Register], 1),
+    info(ASR_REG_REG, "asr", &[Register, Register], 1),
+    info(SCMP_REG_REG, "scmp", &[Register, Register], 1),
+    info(DIV_REG_REG, "div", &[Register, Register, Register], 4),
+    info(DIV_REG_LIT, "div", &[Register, Literal, Register], 4),
+    info(MOD_REG_REG, "mod", &[Register, Register], 4),
+    info(MOD_REG_LIT, "mod", &[Register, Literal], 4),
+    info(SDIV_REG_REG, "sdiv", &[Register, Register, Register], 4),
+    info(SDIV_REG_LIT, "sdiv", &[Register, Literal, Register], 4),
+    info(SMOD_REG_REG, "smod", &[Register, Register], 4),
+    info(SMOD_REG_LIT, "smod", &[Register, Literal], 4),
+    info(JMP_NOT_EQ,  "jne", &[Literal, Address], 2),
+    info(JNE_REG,     "jne", &[Register, Address], 2),
+    info(JEQ_LIT,     "jeq", &[Literal, Address], 2),
+    info(JEQ_REG,     "jeq", &[Register, Address], 2),
+    info(JLT_REG,     "jlt", &[Register, Address], 2),
+    info(JGT_REG,     "jgt", &[Register, Address], 2),
+    info(JLE_REG,     "jle", &[Register, Address], 2),
+    info(JGE_REG,     "jge", &[Register, Address], 2),
+    info(JLTS_REG,    "jlts", &[Register, Address], 2),
+    info(JGTS_REG,    "jgts", &[Register, Address], 2),
+    info(JLES_REG,    "jles", &[Register, Address], 2),
+    info(JGES_REG,    "jges", &[Register, Address], 2),
+    info(JMP_LIT,     "jmp", &[Address], 2),
+    info(PSH_LIT,     "psh", &[Literal], 2),
+    info(PSH_REG,     "psh", &[Register], 1),
+    info(POP,         "pop", &[Register], 1),
+    info(CAL_LIT,     "cal", &[Literal], 3),
+    info(CAL_REG,     "cal", &[Register], 2),
+    info(RET,         "ret", &[], 2),
+    info(ARG_LIT_REG, "arg", &[Literal, Register], 2),
+    info(MCPY_REG_REG_REG, "mcpy", &[Register, Register, Register], 2),
+    info(MSET_REG_REG_REG, "mset", &[Register, Register, Register], 2),
+    info(SYSCALL,     "sys", &[Literal], 2),
+    info(SRET,        "sret", &[], 2),
+    info(STT_LIT,     "stt", &[Literal], 1),
+    info(SSP_LIT,     "ssp", &[Literal], 1),
+    info(USR_LIT,     "usr", &[Literal], 2),
+    info(CLI,         "cli", &[], 1),
+    info(STI,         "sti", &[], 1),
+    info(PTB_LIT,     "ptb", &[Literal], 1),
+    info(PGE,         "pge", &[], 1),
+    info(PGD,         "pgd", &[], 1),
+    info(FLT_REG,     "flt", &[Register], 1),
+    info(HLT,         "hlt", &[], 1),
+];
+
+// Jumps that may or may not be taken, tracked separately by code coverage
+pub fn is_conditional_jump(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        JMP_NOT_EQ | JNE_REG | JEQ_LIT | JEQ_REG | JLT_REG | JGT_REG | JLE_REG | JGE_REG
+            | JLTS_REG | JGTS_REG | JLES_REG | JGES_REG
+    )
+}
+
+// Instructions that trap when executed in user mode
+pub fn is_privileged(opcode: u8) -> bool {
+    matches!(opcode, SRET | STT_LIT | SSP_LIT | USR_LIT | CLI | STI | PTB_LIT | PGE | PGD | FLT_REG | HLT)
+}
+
+pub fn instruction_info(opcode: u8) -> Option<&'static InstructionInfo> {
+    INSTRUCTIONS.iter().find(|i| i.opcode == opcode)
+}
@@ -0,0 +1,68 @@
+use crate::cpu::ExecuteError;
+use crate::cpu::supervisor::Trap;
+use crate::devices::device::Device;
+
+pub const PAGE_SIZE: usize = 256;
+pub const PAGES: usize = 256;
+
+// Each page table entry is a frame word (the physical address divided by PAGE_SIZE) followed by a flags word
+pub const PAGE_TABLE_ENTRY_SIZE: usize = 4;
+pub const PAGE_TABLE_SIZE: usize = PAGES * PAGE_TABLE_ENTRY_SIZE;
+
+pub const PAGE_PRESENT: u16 = 0x0001;
+pub const PAGE_READ: u16 = 0x0002;
+pub const PAGE_WRITE: u16 = 0x0004;
+pub const PAGE_EXECUTE: u16 = 0x0008;
+pub const PAGE_USER: u16 = 0x0010;
+
+// Translates a virtual address through the page table stored at frame `page_table`.
+// `access` is the PAGE_READ, PAGE_WRITE or PAGE_EXECUTE bit the entry needs, plus PAGE_USER in user mode
+pub fn translate<D>(memory: &D, page_table: u16, address: usize, access: u16) -> Result<usize, ExecuteError>
+where
+    D: Device + ?Sized
+{
+    let fault = ExecuteError::Trap(Trap::PageFault { address: address as u16, access });
+
+    let page = address / PAGE_SIZE;
+    if page >= PAGES {
+        return Err(fault);
+    }
+
+    let entry = page_table as usize * PAGE_SIZE + page * PAGE_TABLE_ENTRY_SIZE;
+    let frame = memory.read_at_u16(entry)
+        .ok_or(ExecuteError::MemoryFault(entry))?;
+    let flags = memory.read_at_u16(entry + 2)
+        .ok_or(ExecuteError::MemoryFault(entry + 2))?;
+
+    if flags & PAGE_PRESENT == 0 || flags & access != access {
+        return Err(fault);
+    }
+
+    Ok(frame as usize * PAGE_SIZE + address % PAGE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::ExecuteError;
+    use crate::cpu::mmu::*;
+    use crate::cpu::supervisor::Trap;
+    use crate::devices::memory::Memory;
+
+    #[test]
+    fn translation() {
+        let mut table = vec![0; PAGE_TABLE_SIZE];
+        // Page 0x12 is frame 0x345, read-only for the supervisor
+        table[0x12 * 4..0x12 * 4 + 4].copy_from_slice(&[0x03, 0x45, 0x00, (PAGE_PRESENT | PAGE_READ) as u8]);
+        let memory = Memory::from_vec(table);
+
+        assert_eq!(translate(&memory, 0, 0x12AB, PAGE_READ).unwrap(), 0x345AB);
+
+        assert!(matches!(translate(&memory, 0, 0x12AB, PAGE_WRITE),
+            Err(ExecuteError::Trap(Trap::PageFault { address: 0x12AB, access: PAGE_WRITE }))));
+        assert!(matches!(translate(&memory, 0, 0x12AB, PAGE_READ | PAGE_USER),
+            Err(ExecuteError::Trap(Trap::PageFault { address: 0x12AB, .. }))));
+        assert!(matches!(translate(&memory, 0, 0x1300, PAGE_READ),
+            Err(ExecuteError::Trap(Trap::PageFault { address: 0x1300, access: PAGE_READ }))));
+        assert!(matches!(translate(&memory, 4, 0x0000, PAGE_READ), Err(ExecuteError::MemoryFault(0x400))));
+    }
+}
@@ -1,20 +1,77 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use data_view::View;
 use enum_iterator::{all, cardinality};
+use crate::cpu::backtrace::*;
+use crate::cpu::disassemble::{disassemble_with, Disassembled};
+use crate::cpu::history::{History, MemoryWrite, UndoRecord};
 use crate::cpu::instructions::*;
+use crate::cpu::mmu::*;
 use crate::cpu::register::Register;
+use crate::cpu::snapshot::Snapshot;
+use crate::cpu::supervisor::*;
+use crate::cpu::trace::{MemoryAccess, RegisterDelta, TraceEntry, Tracer, TrapEntry};
+use crate::cpu::watchpoint::{WatchKind, Watchpoint};
 use crate::create_memory::create_memory;
-use crate::devices::device::Device;
+use crate::debug_info::DebugInfo;
+use crate::devices::device::{Device, StateMismatch};
 
+pub mod backtrace;
+pub mod disassemble;
+mod history;
 pub mod instructions;
+pub mod mmu;
 pub mod register;
+pub mod snapshot;
+pub mod supervisor;
+pub mod trace;
+pub mod watchpoint;
 
 #[derive(Debug)]
 pub enum ExecuteError {
     UnknownInstruction(u8),
     NullByte,
+    // ARG_LIT_REG asked for an argument past the number the caller pushed
+    ArgumentOutOfRange(u16),
+    // A push with Sp already below the stack limit, or a pop with Sp already at the stack base
+    StackOverflow(u16),
+    StackUnderflow(u16),
+    // Nothing that can be accessed is mapped at the address, like a guard region
+    MemoryFault(usize),
+    // A division by zero at the address, which traps instead when a trap table is set
+    DivideByZero(u16),
+    // A trap that couldn't be handled because no trap table is set
+    Trap(Trap),
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Halt,
+    // Returned before the instruction at the address is executed
+    Breakpoint(u16),
+    // Returned after the instruction that made the access has finished
+    Watchpoint { addr: usize, kind: WatchKind },
+}
+
+// Where reset sends Ip
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResetVector {
+    Address(u16),
+    // Read the start address from this word in memory, so firmware can say where it starts
+    Indirect(u16),
+}
+
+impl Default for ResetVector {
+    fn default() -> Self {
+        ResetVector::Address(0)
+    }
+}
+
+// Where Sp and Fp start unless set_stack says otherwise, the stack grows down from here
+pub const STACK_START: u16 = 0xFFFF - 1;
+
+// Stops a backtrace of a corrupted stack from walking forever
+const MAX_BACKTRACE_FRAMES: usize = 1024;
+
 pub struct CPU<T>
 where
     T: Device
@@ -25,6 +82,43 @@ where
     register_map: HashMap<Register, usize>,
 
     stack_frame_size: u16,
+    stack_base: u16,
+    // The lowest address a push can write to
+    stack_limit: u16,
+
+    reset_vector: ResetVector,
+
+    control: ControlRegisters,
+    pending_interrupts: VecDeque<u16>,
+    // The registers as they were before the instruction being executed, put back if it faults
+    step_registers: Vec<u8>,
+    step_frame_size: u16,
+
+    cycles: u64,
+    instructions: u64,
+    // Cycles the instruction being executed has used so far
+    step_cycles: u64,
+
+    breakpoints: BTreeSet<u16>,
+    // Set when we stop at a breakpoint so the next step executes the instruction instead of stopping again
+    resume_address: Option<u16>,
+    // Where a block instruction stopped partway, so the steps carrying it on don't count as more instructions
+    unfinished_block: Option<u16>,
+    watchpoints: Vec<Watchpoint>,
+    watchpoint_hit: Option<(usize, WatchKind)>,
+
+    tracer: Option<Box<dyn Tracer>>,
+    // The entry for the instruction being executed, only built while a tracer is set
+    trace_entry: Option<TraceEntry>,
+
+    history: Option<History>,
+    // The record for the instruction being executed, only built while history is enabled
+    undo_record: Option<UndoRecord>,
+
+    // Where the program's code came from, for naming addresses
+    debug_info: Option<DebugInfo>,
+    // The instruction being executed, or the last one if it faulted
+    instruction_address: u16,
 }
 
 impl<T> CPU<T>
@@ -44,10 +138,31 @@ where
             registers: create_memory(cardinality::<Register>() * 2),
             register_map,
             stack_frame_size: 0,
+            stack_base: STACK_START,
+            stack_limit: 0,
+            reset_vector: ResetVector::default(),
+            control: ControlRegisters::default(),
+            pending_interrupts: VecDeque::new(),
+            step_registers: vec![],
+            step_frame_size: 0,
+            cycles: 0,
+            instructions: 0,
+            step_cycles: 0,
+            breakpoints: BTreeSet::new(),
+            resume_address: None,
+            unfinished_block: None,
+            watchpoints: vec![],
+            watchpoint_hit: None,
+            tracer: None,
+            trace_entry: None,
+            history: None,
+            undo_record: None,
+            debug_info: None,
+            instruction_address: 0,
         };
 
-        cpu.set_register(Register::Sp, 0xFFFF - 1);
-        cpu.set_register(Register::Fp, 0xFFFF - 1);
+        cpu.set_register(Register::Sp, STACK_START);
+        cpu.set_register(Register::Fp, STACK_START);
 
         cpu
     }
@@ -56,12 +171,17 @@ where
         for register in all::<Register>() {
             println!("{:?}: 0x{:04X?}", register, self.get_register(register));
         }
+        println!("Mode: {}", if self.control.supervisor { "supervisor" } else { "user" });
+        if self.debug_info.is_some() {
+            println!("At: {}", self.describe_address(self.get_register(Register::Ip)));
+        }
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn view_memory_at(&self, address: usize, n: usize) -> Result<(), ()> {
         let mut next_n_bytes = vec![];
         for i in 0..=n {
-            let next = self.memory.read_at_u8(address + i);
+            let next = self.peek_u8(address + i);
             if let Some(next) = next {
                 next_n_bytes.push(next);
             } else {
@@ -69,14 +189,328 @@ where
             }
         }
 
-        println!("0x{:04X?}: {:02X?}", address, next_n_bytes);
+        match &self.debug_info {
+            Some(info) => println!("0x{:04X} <{}>: {:02X?}", address, info.format_address(address as u16), next_n_bytes),
+            None => println!("0x{:04X?}: {:02X?}", address, next_n_bytes),
+        }
+
+        Ok(())
+    }
+
+    pub fn set_debug_info(&mut self, debug_info: Option<DebugInfo>) {
+        self.debug_info = debug_info;
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    // `print_string+0x04 (strings.asm:17)` with debug info, otherwise just the address
+    pub fn describe_address(&self, address: u16) -> String {
+        match &self.debug_info {
+            Some(info) => info.format_address(address),
+            None => format!("0x{:04X}", address),
+        }
+    }
+
+    pub fn instruction_address(&self) -> u16 {
+        self.instruction_address
+    }
+
+    pub fn memory(&self) -> &T {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut T {
+        &mut self.memory
+    }
+
+    // Moves the stack to grow down from `base` to no lower than `limit`, emptying it
+    pub fn set_stack(&mut self, base: u16, limit: u16) {
+        self.stack_base = base;
+        self.stack_limit = limit;
+        self.stack_frame_size = 0;
+        self.control.supervisor_stack = base;
+        self.set_register(Register::Sp, base);
+        self.set_register(Register::Fp, base);
+    }
+
+    pub fn stack_bounds(&self) -> (u16, u16) {
+        (self.stack_base, self.stack_limit)
+    }
+
+    pub fn set_reset_vector(&mut self, reset_vector: ResetVector) {
+        self.reset_vector = reset_vector;
+    }
+
+    pub fn reset_vector(&self) -> ResetVector {
+        self.reset_vector
+    }
+
+    pub fn control(&self) -> &ControlRegisters {
+        &self.control
+    }
+
+    pub fn set_control(&mut self, control: ControlRegisters) {
+        self.control = control;
+    }
+
+    // Queues an interrupt, taken before the next instruction once interrupts are enabled and a trap table is set
+    pub fn interrupt(&mut self, number: u16) {
+        self.pending_interrupts.push_back(number);
+    }
+
+    // Puts the CPU back in its power-on state and resets the devices, then jumps to the reset vector.
+    // Breakpoints, watchpoints, the tracer and the stack bounds are kept
+    pub fn reset(&mut self) -> Result<(), ExecuteError> {
+        self.memory.reset();
+
+        let start = match self.reset_vector {
+            ResetVector::Address(address) => address,
+            ResetVector::Indirect(address) => self.memory.read_at_u16(address as usize)
+                .ok_or(ExecuteError::MemoryFault(address as usize))?,
+        };
+
+        self.registers.fill(0);
+        self.control = ControlRegisters::default();
+        self.pending_interrupts.clear();
+        self.set_stack(self.stack_base, self.stack_limit);
+        self.set_register(Register::Ip, start);
+
+        self.cycles = 0;
+        self.instructions = 0;
+        self.resume_address = None;
+        self.unfinished_block = None;
+        self.watchpoint_hit = None;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+
+        Ok(())
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, start: usize, end: usize, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint {
+            start,
+            end,
+            kind,
+        });
+    }
+
+    pub fn remove_watchpoint(&mut self, start: usize, end: usize, kind: WatchKind) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != Watchpoint { start, end, kind });
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions
+    }
+
+    // Walks the frames push_state left on the stack through their saved Fp chain
+    pub fn backtrace(&self) -> Backtrace {
+        let read = |address: u16| self.peek_u16(address);
+
+        let mut frames = vec![];
+        let mut frame_pointer = self.get_register(Register::Fp);
+
+        while frame_pointer < self.stack_base && frames.len() < MAX_BACKTRACE_FRAMES {
+            let (Some(frame_size), Some(return_address), Some(argument_count)) = (
+                read(frame_pointer.wrapping_add(FRAME_SIZE_OFFSET)),
+                read(frame_pointer.wrapping_add(RETURN_ADDRESS_OFFSET)),
+                read(frame_pointer.wrapping_add(ARGUMENT_COUNT_OFFSET)),
+            ) else {
+                break;
+            };
+
+            let saved_registers = SAVED_REGISTERS.iter().enumerate()
+                .filter_map(|(i, r)| Some((*r, read(frame_pointer.wrapping_add(SAVED_REGISTERS_OFFSET + i as u16 * 2))?)))
+                .collect();
+
+            // The last argument pushed is the closest to the frame
+            let mut arguments: Vec<u16> = (0..argument_count.min(64))
+                .map_while(|i| read(frame_pointer.wrapping_add(ARGUMENTS_OFFSET + i * 2)))
+                .collect();
+            arguments.reverse();
+
+            frames.push(Frame {
+                frame_pointer,
+                return_address,
+                saved_registers,
+                arguments,
+            });
+
+            // The caller's frame is always above ours, anything else means the stack is corrupt
+            match frame_pointer.checked_add(frame_size) {
+                Some(next) if next > frame_pointer => frame_pointer = next,
+                _ => break,
+            }
+        }
+
+        Backtrace {
+            ip: self.get_register(Register::Ip),
+            frames,
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers.clone(),
+            stack_frame_size: self.stack_frame_size,
+            control: self.control,
+            cycles: self.cycles,
+            instructions: self.instructions,
+            stack_base: self.stack_base,
+            stack_limit: self.stack_limit,
+            reset_vector: self.reset_vector,
+            pending_interrupts: self.pending_interrupts.iter().copied().collect(),
+            device_state: self.memory.save_state(),
+        }
+    }
+
+    // Fails without changing the CPU if the snapshot was taken on a machine with a different layout
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), StateMismatch> {
+        if snapshot.registers.len() != self.registers.len() {
+            return Err(StateMismatch);
+        }
+
+        let current = self.memory.save_state();
+        if let Err(mismatch) = self.memory.restore_state(&snapshot.device_state) {
+            self.memory.restore_state(&current)
+                .expect("restore the state the device just saved");
+            return Err(mismatch);
+        }
+
+        self.registers.copy_from_slice(&snapshot.registers);
+        self.stack_frame_size = snapshot.stack_frame_size;
+        self.control = snapshot.control;
+        self.cycles = snapshot.cycles;
+        self.instructions = snapshot.instructions;
+        self.stack_base = snapshot.stack_base;
+        self.stack_limit = snapshot.stack_limit;
+        self.reset_vector = snapshot.reset_vector;
+        self.pending_interrupts = snapshot.pending_interrupts.iter().copied().collect();
+        self.resume_address = None;
+        self.unfinished_block = None;
+
+        // The undo log describes how we got to the old state, not the restored one
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
 
         Ok(())
     }
 
+    // Keeps an undo log of the last `capacity` instructions so they can be stepped back over
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |h| h.len())
+    }
+
+    // Undoes the last executed instruction, returning the address and size of each write it made,
+    // or None if there's nothing left in the history
+    pub fn step_back(&mut self) -> Option<Vec<(usize, usize)>> {
+        let record = self.history.as_mut()?.pop()?;
+
+        let mut written = vec![];
+        for write in record.memory_writes.into_iter().rev() {
+            if let Some(old) = write.old {
+                // The value was just read from here, so writing it back can only fail if the device changed
+                let _ = if write.word {
+                    self.memory.write_at_u16(write.physical, old)
+                } else {
+                    self.memory.write_at_u8(write.physical, old as u8)
+                };
+            }
+            written.push((write.address, if write.word { 2 } else { 1 }));
+        }
+
+        self.registers = record.registers;
+        self.stack_frame_size = record.stack_frame_size;
+        self.control = record.control;
+        self.cycles = record.cycles;
+        self.instructions = record.instructions;
+        self.unfinished_block = record.unfinished_block;
+        if let Some(number) = record.interrupt {
+            self.pending_interrupts.push_front(number);
+        }
+
+        // Stepping forward again should execute the instruction we're now at, even if it has a breakpoint
+        self.resume_address = Some(self.get_register(Register::Ip));
+
+        Some(written)
+    }
+
+    // Steps back until Ip is `address`, returning false if the history runs out first
+    pub fn run_back_to(&mut self, address: u16) -> bool {
+        loop {
+            if self.step_back().is_none() {
+                return false;
+            }
+
+            if self.get_register(Register::Ip) == address {
+                return true;
+            }
+        }
+    }
+
+    // Steps back until reaching a breakpoint or undoing a write a watchpoint covers.
+    // Returns None if the history runs out first
+    pub fn reverse_continue(&mut self) -> Option<StopReason> {
+        loop {
+            let written = self.step_back()?;
+
+            let ip = self.get_register(Register::Ip);
+            if self.breakpoints.contains(&ip) {
+                return Some(StopReason::Breakpoint(ip));
+            }
+
+            let watched = written.into_iter()
+                .find(|(a, size)| self.watchpoints.iter().any(|w| w.matches(*a, *size, WatchKind::Write)));
+            if let Some((addr, _)) = watched {
+                return Some(StopReason::Watchpoint { addr, kind: WatchKind::Write });
+            }
+        }
+    }
+
     pub fn get_register(&self, register: Register) -> u16 {
         let index = self.register_map.get(&register)
-            .expect(&format!("register {:?} not in self.register_map", register));
+            .unwrap_or_else(|| panic!("register {:?} not in self.register_map", register));
 
         let index = *index;
 
@@ -84,9 +518,9 @@ where
             .expect("read register")
     }
 
-    fn set_register(&mut self, register: Register, value: u16) {
+    pub fn set_register(&mut self, register: Register, value: u16) {
         let index = self.register_map.get(&register)
-            .expect(&format!("register {:?} not in self.register_map", register));
+            .unwrap_or_else(|| panic!("register {:?} not in self.register_map", register));
 
         let index = *index;
 
@@ -95,101 +529,460 @@ where
             .expect("write to register");
     }
 
-    fn fetch(&mut self) -> u8 {
+    fn fetch(&mut self) -> Result<u8, ExecuteError> {
         let next_instruction_address = self.get_register(Register::Ip);
-        let instruction = self.memory.read_at_u8(next_instruction_address as usize)
-            .unwrap();
-        self.set_register(Register::Ip, next_instruction_address + 1);
+        let instruction = self.load_u8(next_instruction_address as usize, PAGE_EXECUTE)?;
+        self.set_register(Register::Ip, next_instruction_address.wrapping_add(1));
+
+        if let Some(entry) = &mut self.trace_entry {
+            entry.operands.push(instruction);
+        }
 
-        instruction
+        Ok(instruction)
     }
 
-    fn fetch16(&mut self) -> u16 {
+    fn fetch16(&mut self) -> Result<u16, ExecuteError> {
         let next_instruction_address = self.get_register(Register::Ip);
-        let instruction = self.memory.read_at_u16(next_instruction_address as usize)
-            .unwrap();
-        self.set_register(Register::Ip, next_instruction_address + 2);
+        let instruction = self.load_u16(next_instruction_address as usize, PAGE_EXECUTE)?;
+        self.set_register(Register::Ip, next_instruction_address.wrapping_add(2));
+
+        if let Some(entry) = &mut self.trace_entry {
+            entry.operands.extend(instruction.to_be_bytes());
+        }
+
+        Ok(instruction)
+    }
+
+    // Where a virtual address is in the memory device, faulting if the current mode isn't allowed the access
+    fn physical_address(&self, address: usize, access: u16) -> Result<usize, ExecuteError> {
+        let physical = if self.control.paging {
+            let access = if self.control.supervisor { access } else { access | PAGE_USER };
+            translate(&self.memory, self.control.page_table, address, access)?
+        } else {
+            address
+        };
+
+        if !self.control.supervisor && self.memory.is_supervisor_only(physical) {
+            return Err(ExecuteError::Trap(Trap::ProtectionFault(address as u16)));
+        }
+        Ok(physical)
+    }
+
+    // The physical addresses of a word's two bytes, which aren't next to each other when it straddles two pages
+    fn physical_word(&self, address: usize, access: u16) -> Result<(usize, usize), ExecuteError> {
+        Ok((self.physical_address(address, access)?, self.physical_address(address + 1, access)?))
+    }
+
+    fn load_u8(&self, address: usize, access: u16) -> Result<u8, ExecuteError> {
+        let physical = self.physical_address(address, access)?;
+        self.memory.read_at_u8(physical)
+            .ok_or(ExecuteError::MemoryFault(physical))
+    }
+
+    fn load_u16(&self, address: usize, access: u16) -> Result<u16, ExecuteError> {
+        let (high, low) = self.physical_word(address, access)?;
+        if low == high + 1 {
+            return self.memory.read_at_u16(high)
+                .ok_or(ExecuteError::MemoryFault(high));
+        }
+
+        let read = |physical| self.memory.read_at_u8(physical).ok_or(ExecuteError::MemoryFault(physical));
+        Ok(u16::from_be_bytes([read(high)?, read(low)?]))
+    }
+
+    // Where the program sees a virtual address in the memory device, ignoring the page's permissions, for tools
+    // like backtrace and the gdb stub. None if the page isn't mapped
+    pub fn debug_address(&self, address: usize) -> Option<usize> {
+        if self.control.paging {
+            translate(&self.memory, self.control.page_table, address, 0).ok()
+        } else {
+            Some(address)
+        }
+    }
+
+    // Reads a byte the way the program sees it without faulting
+    pub fn peek_u8(&self, address: usize) -> Option<u8> {
+        self.memory.read_at_u8(self.debug_address(address)?)
+    }
+
+    // Writes a byte where the program would see it, ignoring the page's permissions. False if it can't be written
+    pub fn poke_u8(&mut self, address: usize, value: u8) -> bool {
+        match self.debug_address(address) {
+            Some(physical) => self.memory.write_at_u8(physical, value).is_ok(),
+            None => false,
+        }
+    }
+
+    // Decodes the instruction at a virtual address
+    pub fn disassemble_at(&self, address: usize) -> Option<Disassembled> {
+        disassemble_with(|address| self.peek_u8(address), address)
+    }
+
+    // Reads a word the way the program sees it without faulting
+    fn peek_u16(&self, address: u16) -> Option<u16> {
+        let high = self.debug_address(address as usize)?;
+        let low = self.debug_address(address as usize + 1)?;
+
+        if low == high + 1 {
+            self.memory.read_at_u16(high)
+        } else {
+            Some(u16::from_be_bytes([self.memory.read_at_u8(high)?, self.memory.read_at_u8(low)?]))
+        }
+    }
 
-        instruction
+    fn check_watchpoints(&mut self, address: usize, size: usize, kind: WatchKind) {
+        if self.watchpoint_hit.is_none() && self.watchpoints.iter().any(|w| w.matches(address, size, kind)) {
+            self.watchpoint_hit = Some((address, kind));
+        }
+    }
+
+    fn record_access(&mut self, address: usize, kind: WatchKind, value: u16) {
+        if let Some(entry) = &mut self.trace_entry {
+            entry.memory_accesses.push(MemoryAccess {
+                address,
+                kind,
+                value,
+            });
+        }
     }
 
-    fn fetch_register_index(&mut self) -> usize {
+    // All data accesses the CPU makes go through these so watchpoints and tracers see them, instruction fetches don't
+    fn read_memory_u16(&mut self, address: usize) -> Result<u16, ExecuteError> {
+        self.check_watchpoints(address, 2, WatchKind::Read);
+        self.step_cycles += MEMORY_ACCESS_CYCLES;
+        let value = self.load_u16(address, PAGE_READ)?;
+        self.record_access(address, WatchKind::Read, value);
+        Ok(value)
+    }
+
+    fn write_memory_u16(&mut self, address: usize, value: u16) -> Result<(), ExecuteError> {
+        self.check_watchpoints(address, 2, WatchKind::Write);
+        self.step_cycles += MEMORY_ACCESS_CYCLES;
+        let (high, low) = self.physical_word(address, PAGE_WRITE)?;
+
+        // A word split across two pages is written a byte at a time
+        let writes = if low == high + 1 {
+            vec![(high, value, true)]
+        } else {
+            let [high_byte, low_byte] = value.to_be_bytes();
+            vec![(high, high_byte as u16, false), (low, low_byte as u16, false)]
+        };
+
+        for (physical, value, word) in writes {
+            if let Some(record) = &mut self.undo_record {
+                let old = if word {
+                    self.memory.read_at_u16(physical)
+                } else {
+                    self.memory.read_at_u8(physical).map(u16::from)
+                };
+                record.memory_writes.push(MemoryWrite {
+                    address,
+                    physical,
+                    old,
+                    word,
+                });
+            }
+
+            let written = if word {
+                self.memory.write_at_u16(physical, value)
+            } else {
+                self.memory.write_at_u8(physical, value as u8)
+            };
+            written.map_err(|_| ExecuteError::MemoryFault(physical))?;
+        }
+
+        self.record_access(address, WatchKind::Write, value);
+        Ok(())
+    }
+
+    fn read_memory_u8(&mut self, address: usize) -> Result<u8, ExecuteError> {
+        self.check_watchpoints(address, 1, WatchKind::Read);
+        self.step_cycles += MEMORY_ACCESS_CYCLES;
+        let value = self.load_u8(address, PAGE_READ)?;
+        self.record_access(address, WatchKind::Read, value as u16);
+        Ok(value)
+    }
+
+    fn write_memory_u8(&mut self, address: usize, value: u8) -> Result<(), ExecuteError> {
+        self.check_watchpoints(address, 1, WatchKind::Write);
+        self.step_cycles += MEMORY_ACCESS_CYCLES;
+        let physical = self.physical_address(address, PAGE_WRITE)?;
+
+        if let Some(record) = &mut self.undo_record {
+            record.memory_writes.push(MemoryWrite {
+                address,
+                physical,
+                old: self.memory.read_at_u8(physical).map(u16::from),
+                word: false,
+            });
+        }
+
+        self.memory.write_at_u8(physical, value)
+            .map_err(|_| ExecuteError::MemoryFault(physical))?;
+        self.record_access(address, WatchKind::Write, value as u16);
+        Ok(())
+    }
+
+    fn fetch_register_index(&mut self) -> Result<usize, ExecuteError> {
         // multiplied by two because each register is two bytes long
-        (self.fetch() as usize % self.register_map.len()) * 2
+        Ok((self.fetch()? as usize % self.register_map.len()) * 2)
     }
 
-    fn push(&mut self, value: u16) {
+    fn push(&mut self, value: u16) -> Result<(), ExecuteError> {
         let sp_address = self.get_register(Register::Sp);
-        self.memory.write_at_u16(sp_address as usize, value).unwrap();
+        if sp_address < self.stack_limit || sp_address < 2 {
+            return Err(ExecuteError::StackOverflow(sp_address));
+        }
+
+        self.write_memory_u16(sp_address as usize, value)?;
         self.set_register(Register::Sp, sp_address - 2);
         self.stack_frame_size += 2;
+        Ok(())
     }
 
-    fn pop(&mut self) -> u16 {
-        let next_sp_address = self.get_register(Register::Sp) + 2;
+    fn pop(&mut self) -> Result<u16, ExecuteError> {
+        let sp_address = self.get_register(Register::Sp);
+        if sp_address >= self.stack_base {
+            return Err(ExecuteError::StackUnderflow(sp_address));
+        }
+
+        // A base at the very top of memory can leave no room for a word above sp
+        let next_sp_address = sp_address.checked_add(2)
+            .ok_or(ExecuteError::StackUnderflow(sp_address))?;
         self.set_register(Register::Sp, next_sp_address);
-        self.stack_frame_size -= 2;
-        self.memory.read_at_u16(next_sp_address as usize).unwrap()
-    }
-
-    fn push_state(&mut self) {
-        self.push(self.get_register(Register::R1));
-        self.push(self.get_register(Register::R2));
-        self.push(self.get_register(Register::R3));
-        self.push(self.get_register(Register::R4));
-        self.push(self.get_register(Register::R5));
-        self.push(self.get_register(Register::R6));
-        self.push(self.get_register(Register::R7));
-        self.push(self.get_register(Register::R8));
-        self.push(self.get_register(Register::Ip));
-        self.push(self.stack_frame_size + 2);
+        // Saturating because pop_state pops its own frame's bookkeeping before resetting the size
+        self.stack_frame_size = self.stack_frame_size.saturating_sub(2);
+        self.read_memory_u16(next_sp_address as usize)
+    }
+
+    fn push_state(&mut self) -> Result<(), ExecuteError> {
+        self.push(self.get_register(Register::R1))?;
+        self.push(self.get_register(Register::R2))?;
+        self.push(self.get_register(Register::R3))?;
+        self.push(self.get_register(Register::R4))?;
+        self.push(self.get_register(Register::R5))?;
+        self.push(self.get_register(Register::R6))?;
+        self.push(self.get_register(Register::R7))?;
+        self.push(self.get_register(Register::R8))?;
+        self.push(self.get_register(Register::Ip))?;
+        self.push(self.stack_frame_size + 2)?;
 
         self.set_register(Register::Fp, self.get_register(Register::Sp));
         self.stack_frame_size = 0;
+        Ok(())
     }
 
-    fn pop_state(&mut self) {
+    fn pop_state(&mut self) -> Result<(), ExecuteError> {
         let frame_pointer_address = self.get_register(Register::Fp);
         self.set_register(Register::Sp, frame_pointer_address);
 
-        self.stack_frame_size = self.pop();
-        let stack_frame_size = self.stack_frame_size;
+        let saved_frame_size = self.pop()?;
 
-        let mut pop_into_reg = |register: Register| {
-            let value = self.pop();
+        let mut pop_into_reg = |register: Register| -> Result<(), ExecuteError> {
+            let value = self.pop()?;
             self.set_register(register, value);
+            Ok(())
         };
 
-        pop_into_reg(Register::Ip);
-        pop_into_reg(Register::R8);
-        pop_into_reg(Register::R7);
-        pop_into_reg(Register::R6);
-        pop_into_reg(Register::R5);
-        pop_into_reg(Register::R4);
-        pop_into_reg(Register::R3);
-        pop_into_reg(Register::R2);
-        pop_into_reg(Register::R1);
-
-        let n_args = self.pop();
+        pop_into_reg(Register::Ip)?;
+        pop_into_reg(Register::R8)?;
+        pop_into_reg(Register::R7)?;
+        pop_into_reg(Register::R6)?;
+        pop_into_reg(Register::R5)?;
+        pop_into_reg(Register::R4)?;
+        pop_into_reg(Register::R3)?;
+        pop_into_reg(Register::R2)?;
+        pop_into_reg(Register::R1)?;
+
+        let n_args = self.pop()?;
         for _i in 0..n_args {
-            self.pop();
+            self.pop()?;
+        }
+
+        // The saved size counted everything the caller pushed, including the saved state, the argument count and the
+        // arguments, none of which are on the stack anymore
+        self.stack_frame_size = saved_frame_size
+            .saturating_sub(SAVED_STATE_SIZE + 2 + n_args * 2);
+
+        self.set_register(Register::Fp, frame_pointer_address.wrapping_add(saved_frame_size));
+        Ok(())
+    }
+
+    // `op reg1, reg2`, leaving the result in Acc like ADD_REG_REG
+    fn binary_operation(&mut self, op: fn(u16, u16) -> u16) -> Result<(), ExecuteError> {
+        let reg1 = self.fetch_register_index()?;
+        let reg2 = self.fetch_register_index()?;
+        let reg1_value = self.registers.read_at::<u16>(reg1).unwrap();
+        let reg2_value = self.registers.read_at::<u16>(reg2).unwrap();
+
+        self.set_register(Register::Acc, op(reg1_value, reg2_value));
+        Ok(())
+    }
+
+    // The dividend register and the divisor register or literal of a division
+    fn fetch_division_operands(&mut self, literal: bool) -> Result<(u16, u16), ExecuteError> {
+        let reg_dividend = self.fetch_register_index()?;
+        let divisor = match literal {
+            true => self.fetch16()?,
+            false => {
+                let reg_divisor = self.fetch_register_index()?;
+                self.registers.read_at::<u16>(reg_divisor).unwrap()
+            }
+        };
+        Ok((self.registers.read_at::<u16>(reg_dividend).unwrap(), divisor))
+    }
+
+    // The quotient and remainder, rounding towards zero when signed. Dividing by zero traps when there's a trap
+    // table to handle it and stops the CPU otherwise
+    fn divide(&self, dividend: u16, divisor: u16, signed: bool) -> Result<(u16, u16), ExecuteError> {
+        if divisor == 0 {
+            return Err(match self.control.trap_table {
+                Some(_) => ExecuteError::Trap(Trap::DivideByZero(self.instruction_address)),
+                None => ExecuteError::DivideByZero(self.instruction_address),
+            });
+        }
+
+        if signed {
+            let (dividend, divisor) = (dividend as i16, divisor as i16);
+            Ok((dividend.wrapping_div(divisor) as u16, dividend.wrapping_rem(divisor) as u16))
+        } else {
+            Ok((dividend / divisor, dividend % divisor))
+        }
+    }
+
+    // `mcpy src, dst, len` and `mset value, dst, len`, a byte at a time through the memory map. A step does at most
+    // BLOCK_CHUNK_SIZE bytes, moving the registers on past them, and runs the instruction again while there are
+    // bytes left, so interrupts are taken in between and a fault part way through starts again from the last chunk
+    fn block_operation(&mut self, copy: bool) -> Result<(), ExecuteError> {
+        let reg_source = self.fetch_register_index()?;
+        let reg_destination = self.fetch_register_index()?;
+        let reg_length = self.fetch_register_index()?;
+        let read = |cpu: &Self, reg| cpu.registers.read_at::<u16>(reg).unwrap();
+
+        let mut length = read(self, reg_length);
+        for _ in 0..length.min(BLOCK_CHUNK_SIZE) {
+            let source = read(self, reg_source);
+            let destination = read(self, reg_destination);
+            let value = match copy {
+                true => self.read_memory_u8(source as usize)?,
+                false => source as u8,
+            };
+            self.write_memory_u8(destination as usize, value)?;
+
+            if copy {
+                self.registers.write_at::<u16>(reg_source, source.wrapping_add(1))
+                    .unwrap();
+            }
+            self.registers.write_at::<u16>(reg_destination, destination.wrapping_add(1))
+                .unwrap();
+            length -= 1;
+            self.registers.write_at::<u16>(reg_length, length)
+                .unwrap();
         }
 
-        self.set_register(Register::Fp, frame_pointer_address + stack_frame_size);
+        self.unfinished_block = None;
+        if length != 0 {
+            // Not a new instruction, so a breakpoint on it doesn't stop it again
+            self.set_register(Register::Ip, self.instruction_address);
+            self.resume_address = Some(self.instruction_address);
+            self.unfinished_block = Some(self.instruction_address);
+        }
+        Ok(())
+    }
+
+    // `jxx reg, &address`, jumping when `condition(acc, reg)` holds
+    fn jump_if(&mut self, condition: fn(u16, u16) -> bool) -> Result<(), ExecuteError> {
+        let reg = self.fetch_register_index()?;
+        let address = self.fetch16()?;
+        let value = self.registers.read_at::<u16>(reg).unwrap();
+
+        if condition(self.get_register(Register::Acc), value) {
+            self.set_register(Register::Ip, address);
+        }
+        Ok(())
+    }
+
+    // Reads the index-th argument pushed by the caller of the current subroutine, 0 being the first one pushed
+    fn argument(&mut self, index: u16) -> Result<u16, ExecuteError> {
+        let frame_pointer = self.get_register(Register::Fp) as usize;
+        let n_args = self.read_memory_u16(frame_pointer + ARGUMENT_COUNT_OFFSET as usize)?;
+        if index >= n_args {
+            return Err(ExecuteError::ArgumentOutOfRange(index));
+        }
+
+        // The count comes from memory, so the argument can be past the end of the address space
+        let address = frame_pointer + ARGUMENTS_OFFSET as usize + (n_args - 1 - index) as usize * 2;
+        if address > u16::MAX as usize - 1 {
+            return Err(ExecuteError::MemoryFault(address));
+        }
+        self.read_memory_u16(address)
+    }
+
+    // Switches to supervisor mode and calls the trap's handler, passing it the cause, the detail and what it needs
+    // to put the interrupted code back (see TRAP_ARG_*). Returns to the current Ip
+    fn enter_trap(&mut self, trap: Trap) -> Result<(), ExecuteError> {
+        let table = self.control.trap_table
+            .ok_or(ExecuteError::Trap(trap))?;
+
+        if let Trap::PageFault { access, .. } = trap {
+            self.control.fault_access = access;
+        }
+
+        let status = self.control.status();
+        let sp = self.get_register(Register::Sp);
+        let fp = self.get_register(Register::Fp);
+        let frame_size = self.stack_frame_size;
+
+        self.control.supervisor = true;
+        self.control.interrupts_enabled = false;
+        self.step_cycles += TRAP_CYCLES;
+
+        let handler = self.read_memory_u16(table as usize + trap.cause() as usize * 2)?;
+
+        if status & STATUS_SUPERVISOR == 0 {
+            self.set_register(Register::Sp, self.control.supervisor_stack);
+            self.set_register(Register::Fp, self.control.supervisor_stack);
+        }
+        self.stack_frame_size = 0;
+
+        for argument in [trap.cause(), trap.detail(), status, sp, fp, frame_size] {
+            self.push(argument)?;
+        }
+        self.push(TRAP_ARGUMENTS)?;
+        self.push_state()?;
+
+        self.set_register(Register::Ip, handler);
+        Ok(())
+    }
+
+    // Undoes enter_trap, putting back the mode and the stack the trap frame saved
+    fn return_from_trap(&mut self) -> Result<(), ExecuteError> {
+        let status = self.argument(TRAP_ARG_STATUS)?;
+        let sp = self.argument(TRAP_ARG_SP)?;
+        let fp = self.argument(TRAP_ARG_FP)?;
+        let frame_size = self.argument(TRAP_ARG_FRAME_SIZE)?;
+
+        self.pop_state()?;
+
+        self.set_register(Register::Sp, sp);
+        self.set_register(Register::Fp, fp);
+        self.stack_frame_size = frame_size;
+        self.control.set_status(status);
+        Ok(())
     }
 
     fn execute(&mut self, instruction: u8) -> Result<bool, ExecuteError> {
         match instruction {
             MOV_LIT_REG => {
-                let literal = self.fetch16();
-                let register = self.fetch_register_index();
+                let literal = self.fetch16()?;
+                let register = self.fetch_register_index()?;
                 self.registers.write_at::<u16>(register, literal)
                     .unwrap();
             }
 
             MOV_REG_REG => {
-                let reg_from = self.fetch_register_index();
-                let reg_to = self.fetch_register_index();
+                let reg_from = self.fetch_register_index()?;
+                let reg_to = self.fetch_register_index()?;
                 let value = self.registers.read_at::<u16>(reg_from)
                     .unwrap();
                 self.registers.write_at::<u16>(reg_to, value)
@@ -197,75 +990,226 @@ where
             }
 
             MOV_REG_MEM => {
-                let reg_from = self.fetch_register_index();
-                let address = self.fetch16() as usize;
+                let reg_from = self.fetch_register_index()?;
+                let address = self.fetch16()? as usize;
                 let value = self.registers.read_at::<u16>(reg_from)
                     .unwrap();
-                self.memory.write_at_u16(address, value)
-                    .unwrap();
+                self.write_memory_u16(address, value)?;
             }
 
             MOV_MEM_REG => {
-                let address = self.fetch16() as usize;
-                let reg_to = self.fetch_register_index();
-                let value = self.memory.read_at_u16(address)
-                    .unwrap();
+                let address = self.fetch16()? as usize;
+                let reg_to = self.fetch_register_index()?;
+                let value = self.read_memory_u16(address)?;
                 self.registers.write_at::<u16>(reg_to, value)
                     .unwrap();
             }
 
             ADD_REG_REG => {
-                let reg1 = self.fetch_register_index();
-                let reg2 = self.fetch_register_index();
+                let reg1 = self.fetch_register_index()?;
+                let reg2 = self.fetch_register_index()?;
                 let reg1_value = self.registers.read_at::<u16>(reg1).unwrap();
                 let reg2_value = self.registers.read_at::<u16>(reg2).unwrap();
 
-                self.set_register(Register::Acc, reg1_value + reg2_value);
+                self.set_register(Register::Acc, reg1_value.wrapping_add(reg2_value));
+            }
+
+            MOV_PTR_REG => {
+                let reg_pointer = self.fetch_register_index()?;
+                let reg_to = self.fetch_register_index()?;
+                let address = self.registers.read_at::<u16>(reg_pointer)
+                    .unwrap();
+                let value = self.read_memory_u16(address as usize)?;
+                self.registers.write_at::<u16>(reg_to, value)
+                    .unwrap();
+            }
+
+            MOV_REG_PTR => {
+                let reg_from = self.fetch_register_index()?;
+                let reg_pointer = self.fetch_register_index()?;
+                let value = self.registers.read_at::<u16>(reg_from)
+                    .unwrap();
+                let address = self.registers.read_at::<u16>(reg_pointer)
+                    .unwrap();
+                self.write_memory_u16(address as usize, value)?;
+            }
+
+            SUB_REG_REG => self.binary_operation(|a, b| a.wrapping_sub(b))?,
+            MUL_REG_REG => self.binary_operation(|a, b| a.wrapping_mul(b))?,
+            LSF_REG_REG => self.binary_operation(|a, b| a.checked_shl(b as u32).unwrap_or(0))?,
+            RSF_REG_REG => self.binary_operation(|a, b| a.checked_shr(b as u32).unwrap_or(0))?,
+            AND_REG_REG => self.binary_operation(|a, b| a & b)?,
+            OR_REG_REG => self.binary_operation(|a, b| a | b)?,
+            XOR_REG_REG => self.binary_operation(|a, b| a ^ b)?,
+            // Shifting by 15 or more leaves only copies of the sign bit
+            ASR_REG_REG => self.binary_operation(|a, b| ((a as i16) >> b.min(15)) as u16)?,
+            // -1, 0 or 1 as the first register is less than, equal to or greater than the second, both signed
+            SCMP_REG_REG => self.binary_operation(|a, b| (a as i16).cmp(&(b as i16)) as i16 as u16)?,
+
+            NEG_REG => {
+                let reg = self.fetch_register_index()?;
+                let value = self.registers.read_at::<u16>(reg).unwrap();
+                self.set_register(Register::Acc, value.wrapping_neg());
+            }
+
+            // The low byte as a signed number
+            SXB_REG => {
+                let reg = self.fetch_register_index()?;
+                let value = self.registers.read_at::<u16>(reg).unwrap();
+                self.set_register(Register::Acc, value as u8 as i8 as i16 as u16);
+            }
+
+            DIV_REG_REG | DIV_REG_LIT | SDIV_REG_REG | SDIV_REG_LIT => {
+                let signed = matches!(instruction, SDIV_REG_REG | SDIV_REG_LIT);
+                let (dividend, divisor) = self.fetch_division_operands(matches!(instruction, DIV_REG_LIT | SDIV_REG_LIT))?;
+                let reg_remainder = self.fetch_register_index()?;
+                let (quotient, remainder) = self.divide(dividend, divisor, signed)?;
+
+                self.set_register(Register::Acc, quotient);
+                self.registers.write_at::<u16>(reg_remainder, remainder)
+                    .unwrap();
+            }
+
+            MOD_REG_REG | MOD_REG_LIT | SMOD_REG_REG | SMOD_REG_LIT => {
+                let signed = matches!(instruction, SMOD_REG_REG | SMOD_REG_LIT);
+                let (dividend, divisor) = self.fetch_division_operands(matches!(instruction, MOD_REG_LIT | SMOD_REG_LIT))?;
+                let (_, remainder) = self.divide(dividend, divisor, signed)?;
+
+                self.set_register(Register::Acc, remainder);
+            }
+
+            NOT_REG => {
+                let reg = self.fetch_register_index()?;
+                let value = self.registers.read_at::<u16>(reg).unwrap();
+                self.set_register(Register::Acc, !value);
             }
 
             JMP_NOT_EQ => {
-                let value = self.fetch16();
-                let address = self.fetch16();
+                let value = self.fetch16()?;
+                let address = self.fetch16()?;
 
                 if value != self.get_register(Register::Acc) {
                     self.set_register(Register::Ip, address);
                 }
             }
 
+            JEQ_LIT => {
+                let value = self.fetch16()?;
+                let address = self.fetch16()?;
+
+                if value == self.get_register(Register::Acc) {
+                    self.set_register(Register::Ip, address);
+                }
+            }
+
+            JNE_REG => self.jump_if(|acc, value| acc != value)?,
+            JEQ_REG => self.jump_if(|acc, value| acc == value)?,
+            JLT_REG => self.jump_if(|acc, value| acc < value)?,
+            JGT_REG => self.jump_if(|acc, value| acc > value)?,
+            JLE_REG => self.jump_if(|acc, value| acc <= value)?,
+            JGE_REG => self.jump_if(|acc, value| acc >= value)?,
+            JLTS_REG => self.jump_if(|acc, value| (acc as i16) < value as i16)?,
+            JGTS_REG => self.jump_if(|acc, value| acc as i16 > value as i16)?,
+            JLES_REG => self.jump_if(|acc, value| acc as i16 <= value as i16)?,
+            JGES_REG => self.jump_if(|acc, value| acc as i16 >= value as i16)?,
+
+            JMP_LIT => {
+                let address = self.fetch16()?;
+                self.set_register(Register::Ip, address);
+            }
+
             PSH_LIT => {
-                let value = self.fetch16();
-                self.push(value);
+                let value = self.fetch16()?;
+                self.push(value)?;
             }
 
             PSH_REG => {
-                let reg = self.fetch_register_index();
+                let reg = self.fetch_register_index()?;
                 let value = self.registers.read_at::<u16>(reg).unwrap();
-                self.push(value);
+                self.push(value)?;
             }
 
             POP => {
-                let reg = self.fetch_register_index();
-                let value = self.pop();
+                let reg = self.fetch_register_index()?;
+                let value = self.pop()?;
                 self.registers.write_at::<u16>(reg, value).unwrap();
             }
 
             CAL_LIT => {
-                let address = self.fetch16();
-                self.push_state();
+                let address = self.fetch16()?;
+                self.push_state()?;
 
                 self.set_register(Register::Ip, address);
             }
 
             CAL_REG => {
-                let reg = self.fetch_register_index();
+                let reg = self.fetch_register_index()?;
                 let address = self.registers.read_at::<u16>(reg).unwrap();
-                self.push_state();
+                self.push_state()?;
 
                 self.set_register(Register::Ip, address);
             }
 
             RET => {
-                self.pop_state();
+                self.pop_state()?;
+            }
+
+            ARG_LIT_REG => {
+                let index = self.fetch16()?;
+                let reg = self.fetch_register_index()?;
+                let value = self.argument(index)?;
+                self.registers.write_at::<u16>(reg, value).unwrap();
+            }
+
+            MCPY_REG_REG_REG => self.block_operation(true)?,
+            MSET_REG_REG_REG => self.block_operation(false)?,
+
+            SYSCALL => {
+                let number = self.fetch16()?;
+                return Err(ExecuteError::Trap(Trap::Syscall(number)));
+            }
+
+            SRET => {
+                self.return_from_trap()?;
+            }
+
+            STT_LIT => {
+                self.control.trap_table = Some(self.fetch16()?);
+            }
+
+            SSP_LIT => {
+                self.control.supervisor_stack = self.fetch16()?;
+            }
+
+            USR_LIT => {
+                let address = self.fetch16()?;
+                self.control.supervisor = false;
+                self.set_register(Register::Ip, address);
+            }
+
+            CLI => {
+                self.control.interrupts_enabled = false;
+            }
+
+            PTB_LIT => {
+                self.control.page_table = self.fetch16()?;
+            }
+
+            PGE => {
+                self.control.paging = true;
+            }
+
+            PGD => {
+                self.control.paging = false;
+            }
+
+            FLT_REG => {
+                let reg = self.fetch_register_index()?;
+                self.registers.write_at::<u16>(reg, self.control.fault_access).unwrap();
+            }
+
+            STI => {
+                self.control.interrupts_enabled = true;
             }
 
             HLT => {
@@ -283,28 +1227,213 @@ where
         Ok(false)
     }
 
-    pub fn step(&mut self) -> Result<bool, ExecuteError> {
-        let instruction = self.fetch();
+    fn begin_undo_record(&mut self, interrupt: Option<u16>) {
+        if self.history.is_some() {
+            self.undo_record = Some(UndoRecord {
+                registers: self.registers.clone(),
+                stack_frame_size: self.stack_frame_size,
+                control: self.control,
+                cycles: self.cycles,
+                instructions: self.instructions,
+                interrupt,
+                unfinished_block: self.unfinished_block,
+                memory_writes: vec![],
+            });
+        }
+    }
+
+    fn finish_undo_record(&mut self) {
+        if let (Some(history), Some(record)) = (&mut self.history, self.undo_record.take()) {
+            history.push(record);
+        }
+    }
+
+    fn next_interrupt(&mut self) -> Option<u16> {
+        if self.control.interrupts_enabled && self.control.trap_table.is_some() {
+            self.pending_interrupts.pop_front()
+        } else {
+            None
+        }
+    }
+
+    // Taking an interrupt is a step of its own, the handler's first instruction runs on the next one
+    fn step_interrupt(&mut self, number: u16) -> Result<Option<StopReason>, ExecuteError> {
+        self.watchpoint_hit = None;
+        self.begin_undo_record(Some(number));
+        self.step_cycles = 0;
+
+        let registers_before = self.tracer.as_ref().map(|_| self.registers.clone());
+        if self.tracer.is_some() {
+            self.trace_entry = Some(TraceEntry {
+                ip: self.get_register(Register::Ip),
+                ..TraceEntry::default()
+            });
+        }
+
+        let result = self.enter_trap_traced(Trap::Interrupt(number));
+
+        self.cycles += self.step_cycles;
+        self.memory.tick(self.step_cycles);
+
+        if let Some(registers_before) = registers_before {
+            self.finish_trace(&registers_before);
+        }
+
+        self.finish_undo_record();
+
+        result?;
+        Ok(self.watchpoint_hit.take().map(|(addr, kind)| StopReason::Watchpoint { addr, kind }))
+    }
+
+    // Runs the instruction at ip unless the current mode isn't allowed to
+    fn execute_at(&mut self, ip: u16) -> Result<bool, ExecuteError> {
+        let instruction = self.load_u8(ip as usize, PAGE_EXECUTE)?;
+        self.set_register(Register::Ip, ip.wrapping_add(1));
+        // Unknown instructions still take a cycle to find out they're unknown
+        self.step_cycles = instruction_info(instruction).map_or(1, |info| info.cycles);
+        if let Some(entry) = &mut self.trace_entry {
+            entry.opcode = instruction;
+        }
+
+        if !self.control.supervisor && is_privileged(instruction) {
+            return Err(ExecuteError::Trap(Trap::PrivilegedInstruction(instruction)));
+        }
+
         self.execute(instruction)
     }
 
-    pub fn run(&mut self) {
+    pub fn step(&mut self) -> Result<Option<StopReason>, ExecuteError> {
+        if let Some(number) = self.next_interrupt() {
+            return self.step_interrupt(number);
+        }
+
+        let ip = self.get_register(Register::Ip);
+        if self.resume_address.take() != Some(ip) && self.breakpoints.contains(&ip) {
+            self.resume_address = Some(ip);
+            return Ok(Some(StopReason::Breakpoint(ip)));
+        }
+
+        self.watchpoint_hit = None;
+        self.instruction_address = ip;
+        let continued = self.unfinished_block == Some(ip);
+
+        let registers_before = self.tracer.as_ref().map(|_| self.registers.clone());
+        self.step_registers.clone_from(&self.registers);
+        self.step_frame_size = self.stack_frame_size;
+
+        self.begin_undo_record(None);
+
+        // Fetching the opcode can fault too
+        self.step_cycles = 1;
+
+        if self.tracer.is_some() {
+            self.trace_entry = Some(TraceEntry {
+                ip,
+                continued,
+                ..TraceEntry::default()
+            });
+        }
+
+        let result = match self.execute_at(ip) {
+            Err(ExecuteError::Trap(trap)) if self.control.trap_table.is_some() => {
+                // Faults return to the instruction that caused them with its changes to the registers undone,
+                // syscalls carry on after it
+                if !matches!(trap, Trap::Syscall(_)) {
+                    self.registers.clone_from(&self.step_registers);
+                    self.stack_frame_size = self.step_frame_size;
+                }
+                self.enter_trap_traced(trap).map(|_| false)
+            }
+            result => result,
+        };
+
+        self.cycles += self.step_cycles;
+        self.instructions += !continued as u64;
+        self.memory.tick(self.step_cycles);
+
+        if let Some(registers_before) = registers_before {
+            self.finish_trace(&registers_before);
+        }
+
+        self.finish_undo_record();
+
+        if result? {
+            return Ok(Some(StopReason::Halt));
+        }
+
+        Ok(self.watchpoint_hit.take().map(|(addr, kind)| StopReason::Watchpoint { addr, kind }))
+    }
+
+    // Enters the trap's handler, marking the step's trace entry with it
+    fn enter_trap_traced(&mut self, trap: Trap) -> Result<(), ExecuteError> {
+        self.enter_trap(trap)?;
+        if let Some(entry) = &mut self.trace_entry {
+            entry.trap = Some(TrapEntry {
+                cause: trap.cause(),
+                detail: trap.detail(),
+            });
+        }
+        Ok(())
+    }
+
+    fn finish_trace(&mut self, registers_before: &[u8]) {
+        let mut entry = match self.trace_entry.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        entry.next_ip = self.get_register(Register::Ip);
+        entry.cycles = self.step_cycles;
+        for register in all::<Register>().filter(|r| *r != Register::Ip) {
+            let old = registers_before.read_at::<u16>(self.register_map[&register])
+                .expect("read register");
+            let new = self.get_register(register);
+            if old != new {
+                entry.register_deltas.push(RegisterDelta {
+                    register,
+                    old,
+                    new,
+                });
+            }
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&entry);
+        }
+    }
+
+    // Runs until at least `cycles` more cycles have passed, returning None if nothing stopped the CPU before then
+    pub fn run_for(&mut self, cycles: u64) -> Result<Option<StopReason>, ExecuteError> {
+        let end = self.cycles + cycles;
+        while self.cycles < end {
+            if let Some(reason) = self.step()? {
+                return Ok(Some(reason));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn run(&mut self) -> Result<StopReason, ExecuteError> {
         loop {
-            let should_halt = self.step()
-                .unwrap();
-            if should_halt {
-                break;
+            if let Some(reason) = self.step()? {
+                return Ok(reason);
             }
         }
     }
 }
 
-// TODO: fix some warnings generated by the tests
 #[cfg(test)]
 mod tests {
-    use crate::cpu::{CPU, ExecuteError, Register};
+    use crate::cpu::{CPU, ExecuteError, Register, ResetVector, StopReason};
     use crate::cpu::instructions::*;
-    use crate::cpu::memory::Memory;
+    use crate::cpu::mmu::*;
+    use crate::cpu::snapshot::Snapshot;
+    use crate::cpu::supervisor::Trap;
+    use crate::cpu::watchpoint::WatchKind;
+    use crate::devices::device::Device;
+    use crate::devices::memory::Memory;
+    use crate::devices::memory_mapper::MemoryMapper;
     use crate::create_memory::create_memory;
 
     #[test]
@@ -326,10 +1455,15 @@ mod tests {
         assert_eq!(cpu.register_map.get(&Register::Fp), Some(&22));
     }
 
+    const ACC: u8 = 1;
     const R1: u8  = 2;
     const R2: u8  = 3;
+    const R3: u8  = 4;
     const R8: u8  = 9;
     const R4: u8  = 5;
+    const R5: u8  = 6;
+    const R6: u8  = 7;
+    const R7: u8  = 8;
 
     #[test]
     fn subroutine() {
@@ -417,15 +1551,16 @@ mod tests {
                 match e {
                     ExecuteError::UnknownInstruction(instruction) => panic!("unknown instruction {:02X?}", instruction),
                     ExecuteError::NullByte => break,
+                    e => panic!("unexpected error {:?}", e),
                 }
             }
         }
 
         // Check that the state is the same as when we left it before calling the subroutine
-        assert_eq!(cpu.pop(), 0x4444);
-        assert_eq!(cpu.pop(), 0x1111);
-        assert_eq!(cpu.pop(), 0x2222);
-        assert_eq!(cpu.pop(), 0x3333);
+        assert_eq!(cpu.pop().unwrap(), 0x4444);
+        assert_eq!(cpu.pop().unwrap(), 0x1111);
+        assert_eq!(cpu.pop().unwrap(), 0x2222);
+        assert_eq!(cpu.pop().unwrap(), 0x3333);
 
         assert_eq!(cpu.get_register(Register::R1), 0x1234);
         assert_eq!(cpu.get_register(Register::R4), 0x5678);
@@ -458,11 +1593,1006 @@ mod tests {
 
         let memory = Memory::from_vec(memory);
         let mut cpu = CPU::new(memory);
-        cpu.step();
-        cpu.step();
-        cpu.step();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
 
         let acc_value = cpu.get_register(Register::Acc);
         assert_eq!(acc_value, 0x1234 + 0xABCD);
     }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut memory = create_memory(256);
+
+        let program = [
+            MOV_LIT_REG, 0x12, 0x34, R1,
+            MOV_REG_MEM, R1, 0x00, 0x80,
+            MOV_MEM_REG, 0x00, 0x80, R2,
+            HLT,
+        ];
+        memory[..program.len()].copy_from_slice(&program);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.add_breakpoint(0x0004);
+        cpu.add_watchpoint(0x81, 0x81, WatchKind::Read);
+
+        assert_eq!(cpu.run().unwrap(), StopReason::Breakpoint(0x0004));
+        assert_eq!(cpu.get_register(Register::Ip), 0x0004);
+
+        // Resuming executes the instruction under the breakpoint instead of stopping again,
+        // and the write doesn't trigger the read watchpoint
+        assert_eq!(cpu.run().unwrap(), StopReason::Watchpoint { addr: 0x80, kind: WatchKind::Read });
+        assert_eq!(cpu.get_register(Register::R2), 0x1234);
+
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+    }
+
+    #[test]
+    fn reverse_execution() {
+        let mut memory = create_memory(256);
+
+        let program = [
+            MOV_LIT_REG, 0x12, 0x34, R1,
+            PSH_REG, R1,
+            MOV_LIT_REG, 0xBA, 0xD0, R1,
+            MOV_REG_MEM, R1, 0x00, 0xFE,
+            POP, R2,
+            HLT,
+        ];
+        memory[..program.len()].copy_from_slice(&program);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_register(Register::Sp, 0xFE);
+        cpu.enable_history(4);
+        cpu.add_watchpoint(0xFE, 0xFF, WatchKind::Write);
+
+        while cpu.step().unwrap() != Some(StopReason::Halt) {}
+        assert_eq!(cpu.get_register(Register::R2), 0xBAD0);
+        assert_eq!(cpu.history_len(), 4);
+
+        // Rewind to the write that clobbered the pushed value
+        assert_eq!(cpu.reverse_continue(), Some(StopReason::Watchpoint { addr: 0xFE, kind: WatchKind::Write }));
+        assert_eq!(cpu.get_register(Register::Ip), 0x000A);
+        assert_eq!(cpu.memory().read_at_u16(0xFE), Some(0x1234));
+        assert_eq!(cpu.get_register(Register::Sp), 0xFC);
+        assert_eq!(cpu.get_register(Register::R2), 0);
+
+        // Only the last four instructions were kept
+        assert!(!cpu.run_back_to(0x0000));
+        assert_eq!(cpu.get_register(Register::Ip), 0x0006);
+        assert_eq!(cpu.get_register(Register::R1), 0x1234);
+        assert!(cpu.step_back().is_none());
+
+        assert_eq!(cpu.run().unwrap(), StopReason::Watchpoint { addr: 0xFE, kind: WatchKind::Write });
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+        assert_eq!(cpu.get_register(Register::R2), 0xBAD0);
+
+        // Undoing a byte write only hits watchpoints on that byte, like doing it did
+        let mut memory = create_memory(256);
+        let program = [
+            MOV_LIT_REG, 0x00, 0xAA, R1,
+            MOV_LIT_REG, 0x00, 0x80, R2,
+            MOV_LIT_REG, 0x00, 0x01, R3,
+            MSET_REG_REG_REG, R1, R2, R3,
+            HLT,
+        ];
+        memory[..program.len()].copy_from_slice(&program);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.enable_history(8);
+        cpu.add_watchpoint(0x81, 0x81, WatchKind::Write);
+
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+        assert_eq!(cpu.reverse_continue(), None);
+        assert_eq!(cpu.get_register(Register::Ip), 0x0000);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut memory = create_memory(256);
+
+        let program = [
+            MOV_LIT_REG, 0x00, 0x01, R1,
+            ADD_REG_REG, R1, R1,
+            MOV_REG_MEM, ACC, 0x00, 0x80,
+            PSH_LIT, 0xAB, 0xCD,
+            HLT,
+        ];
+        memory[..program.len()].copy_from_slice(&program);
+
+        let mut mm = MemoryMapper::new();
+        mm.map(Box::new(Memory::from_vec(memory)), 0, 0xFF, true);
+        mm.map(Box::new(Memory::from_num_of_bytes(16)), 0xF0, 0xFF, true);
+
+        let mut cpu = CPU::new(mm);
+        cpu.set_stack(0xFE, 0xE0);
+        cpu.set_reset_vector(ResetVector::Indirect(0xF0));
+        cpu.step().unwrap();
+        cpu.interrupt(2);
+        cpu.interrupt(1);
+
+        let snapshot = Snapshot::from_bytes(&cpu.snapshot().to_bytes()).unwrap();
+        assert_eq!(snapshot, cpu.snapshot());
+        assert_eq!(snapshot.pending_interrupts, [2, 1]);
+        assert!(Snapshot::from_bytes(&cpu.snapshot().to_bytes()[..40]).is_err());
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory().read_at_u16(0x80), Some(0x0002));
+        assert_eq!(cpu.memory().read_at_u16(0xFE), Some(0xABCD));
+
+        cpu.set_stack(0x100, 0);
+        cpu.set_reset_vector(ResetVector::Address(0));
+        cpu.restore(&snapshot).unwrap();
+        assert_eq!(cpu.stack_bounds(), (0xFE, 0xE0));
+        assert_eq!(cpu.reset_vector(), ResetVector::Indirect(0xF0));
+        assert_eq!(cpu.snapshot().pending_interrupts, [2, 1]);
+        assert_eq!(cpu.get_register(Register::Ip), 0x0004);
+        assert_eq!(cpu.get_register(Register::Acc), 0);
+        assert_eq!(cpu.memory().read_at_u16(0x80), Some(0x0000));
+        assert_eq!(cpu.memory().read_at_u16(0xFE), Some(0x0000));
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory().read_at_u16(0xFE), Some(0xABCD));
+
+        // A snapshot of a machine with a different layout is rejected
+        let mut other = CPU::new(Memory::from_num_of_bytes(256));
+        assert!(other.restore(&snapshot).is_err());
+    }
+
+    #[test]
+    fn cycle_counting() {
+        let mut memory = create_memory(256);
+
+        let program = [
+            MOV_LIT_REG, 0x00, 0x01, R1,
+            PSH_REG, R1,
+            POP, R2,
+            HLT,
+        ];
+        memory[..program.len()].copy_from_slice(&program);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_register(Register::Sp, 0xFE);
+
+        // mov takes 2 cycles, so a budget of 1 still runs it
+        assert_eq!(cpu.run_for(1).unwrap(), None);
+        assert_eq!(cpu.cycles(), 2);
+
+        // psh and pop each cost 1 plus a memory access
+        assert_eq!(cpu.run_for(4).unwrap(), None);
+        assert_eq!(cpu.cycles(), 6);
+        assert_eq!(cpu.run_for(100).unwrap(), Some(StopReason::Halt));
+        assert_eq!(cpu.cycles(), 11);
+        assert_eq!(cpu.instructions_executed(), 4);
+    }
+
+    #[test]
+    fn arithmetic_and_pointers() {
+        let mut memory = create_memory(256);
+
+        let program = [
+            MOV_LIT_REG, 0x00, 0x03, R1,
+            MOV_LIT_REG, 0x00, 0x05, R2,
+            SUB_REG_REG, R1, R2,
+            MOV_REG_REG, ACC, R3,
+            MUL_REG_REG, R2, R2,
+            MOV_REG_REG, ACC, R4,
+            LSF_REG_REG, R2, R1,
+            MOV_LIT_REG, 0x00, 0x80, R5,
+            MOV_REG_PTR, ACC, R5,
+            MOV_PTR_REG, R5, R6,
+            // Counts r1 down to 0 with the register and literal jumps
+            MOV_LIT_REG, 0x00, 0x01, R7,
+            SUB_REG_REG, R1, R7,
+            MOV_REG_REG, ACC, R1,
+            JEQ_LIT, 0x00, 0x00, 0x00, 0x33,
+            JMP_LIT, 0x00, 0x25,
+            NOT_REG, R1,
+            MOV_LIT_REG, 0x00, 0x10, R8,
+            JGT_REG, R8, 0x00, 0x3E,
+            HLT,
+            HLT,
+        ];
+        memory[..program.len()].copy_from_slice(&program);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_register(Register::Sp, 0xFE);
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.get_register(Register::R3), 0xFFFE);
+        assert_eq!(cpu.get_register(Register::R4), 25);
+        assert_eq!(cpu.memory().read_at_u16(0x80), Some(40));
+        assert_eq!(cpu.get_register(Register::R6), 40);
+        assert_eq!(cpu.get_register(Register::R1), 0);
+        assert_eq!(cpu.get_register(Register::Acc), 0xFFFF);
+        assert_eq!(cpu.get_register(Register::Ip), 0x3F);
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut memory = create_memory(256 * 256);
+
+        let main = [
+            MOV_LIT_REG, 0x12, 0x34, R1,
+            PSH_LIT, 0xAA, 0xAA,
+            PSH_LIT, 0xBB, 0xBB,
+            PSH_LIT, 0x00, 0x02,
+            CAL_LIT, 0x30, 0x00,
+            HLT,
+        ];
+        memory[..main.len()].copy_from_slice(&main);
+
+        let outer = [
+            MOV_LIT_REG, 0x56, 0x78, R1,
+            PSH_LIT, 0x00, 0x00,
+            CAL_LIT, 0x40, 0x00,
+            RET,
+        ];
+        memory[0x3000..0x3000 + outer.len()].copy_from_slice(&outer);
+
+        memory[0x4000] = HLT;
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.add_breakpoint(0x4000);
+        assert_eq!(cpu.run().unwrap(), StopReason::Breakpoint(0x4000));
+
+        let backtrace = cpu.backtrace();
+        assert_eq!(backtrace.ip, 0x4000);
+        assert_eq!(backtrace.frames.len(), 2);
+
+        assert_eq!(backtrace.frames[0].return_address, 0x300A);
+        assert_eq!(backtrace.frames[0].arguments, vec![]);
+        assert!(backtrace.frames[0].saved_registers.contains(&(Register::R1, 0x5678)));
+
+        assert_eq!(backtrace.frames[1].return_address, 0x0010);
+        assert_eq!(backtrace.frames[1].arguments, vec![0xAAAA, 0xBBBB]);
+        assert!(backtrace.frames[1].saved_registers.contains(&(Register::R1, 0x1234)));
+
+        let symbols = "0x0000 main\n0x3000 outer\n0x4000 inner".parse().unwrap();
+        let text = backtrace.format(Some(&symbols));
+        assert!(text.starts_with("#0  inner\n#1  outer+0x0A (fp "));
+        assert!(text.contains("#2  main+0x10 (fp 0xFFE4, args [0xAAAA, 0xBBBB])"));
+    }
+
+    #[test]
+    fn stack_bounds() {
+        // Recurses until the stack runs out
+        let program = [
+            PSH_LIT, 0x00, 0x00,
+            CAL_LIT, 0x00, 0x00,
+        ];
+        let mut memory = create_memory(0x100);
+        memory[..program.len()].copy_from_slice(&program);
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_stack(0xFE, 0x80);
+
+        match cpu.run() {
+            Err(ExecuteError::StackOverflow(sp)) => assert!(sp < 0x80),
+            other => panic!("expected a stack overflow, got {:?}", other),
+        }
+        assert!(cpu.get_register(Register::Sp) >= 0x7E);
+
+        // Returning without a call pops past the base
+        let mut memory = create_memory(0x100);
+        memory[0] = RET;
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_stack(0xFE, 0x80);
+
+        assert!(matches!(cpu.run(), Err(ExecuteError::StackUnderflow(0xFE))));
+
+        // Popping off the end of memory is an underflow too
+        let mut memory = create_memory(0x10000);
+        memory[..2].copy_from_slice(&[POP, R1]);
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_stack(0xFFFF, 0x8000);
+        cpu.set_register(Register::Sp, 0xFFFE);
+
+        assert!(matches!(cpu.run(), Err(ExecuteError::StackUnderflow(0xFFFE))));
+    }
+
+    #[test]
+    fn guard_region() {
+        let mut mm = MemoryMapper::new();
+        mm.map(Box::new(Memory::from_num_of_bytes(0x100)), 0x00, 0xFF, false);
+        mm.map_guard(0x70, 0x7F);
+        mm.write_at_u8(0x00, PSH_LIT).unwrap();
+        mm.write_at_u8(0x03, CAL_LIT).unwrap();
+
+        let mut cpu = CPU::new(mm);
+        cpu.set_stack(0xFE, 0x00);
+
+        match cpu.run() {
+            Err(ExecuteError::MemoryFault(address)) => assert!((0x70..=0x7F).contains(&address)),
+            other => panic!("expected the guard to fault, got {:?}", other),
+        }
+
+        // Nothing is mapped past the memory at all
+        let mut cpu = CPU::new(MemoryMapper::new());
+        assert!(matches!(cpu.step(), Err(ExecuteError::MemoryFault(0))));
+    }
+
+    #[test]
+    fn reset() {
+        let program = [
+            MOV_LIT_REG, 0x12, 0x34, R1,
+            PSH_REG, R1,
+            HLT,
+        ];
+        let mut memory = create_memory(0x100);
+        memory[0x10..0x10 + program.len()].copy_from_slice(&program);
+        memory[0xF0] = 0x00;
+        memory[0xF1] = 0x10;
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_stack(0xEE, 0x80);
+        cpu.set_reset_vector(ResetVector::Indirect(0xF0));
+        cpu.reset().unwrap();
+        assert_eq!(cpu.get_register(Register::Ip), 0x10);
+
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(Register::R1), 0x1234);
+
+        cpu.reset().unwrap();
+        assert_eq!(cpu.get_register(Register::Ip), 0x10);
+        assert_eq!(cpu.get_register(Register::R1), 0);
+        assert_eq!(cpu.get_register(Register::Sp), 0xEE);
+        assert_eq!(cpu.get_register(Register::Fp), 0xEE);
+        assert_eq!(cpu.cycles(), 0);
+        // Memory survives a reset
+        assert_eq!(cpu.memory().read_at_u16(0xEE), Some(0x1234));
+
+        cpu.set_reset_vector(ResetVector::Address(0x20));
+        cpu.reset().unwrap();
+        assert_eq!(cpu.get_register(Register::Ip), 0x20);
+
+        cpu.set_reset_vector(ResetVector::Indirect(0x1000));
+        assert!(matches!(cpu.reset(), Err(ExecuteError::MemoryFault(0x1000))));
+    }
+
+    fn load(mm: &mut MemoryMapper, address: usize, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            mm.write_at_u8(address + i, *byte).unwrap();
+        }
+    }
+
+    // Kernel memory below 0x1000 only the kernel can touch, everything above is the user program's
+    fn tiny_os(user_program: &[u8]) -> CPU<MemoryMapper> {
+        let mut mm = MemoryMapper::new();
+        mm.map_supervisor_only(Box::new(Memory::from_num_of_bytes(0x1000)), 0x0000, 0x0FFF, true);
+        mm.map(Box::new(Memory::from_num_of_bytes(0xF000)), 0x1000, 0xFFFF, true);
+
+        load(&mut mm, 0x0000, &[
+            STT_LIT, 0x01, 0x00,
+            SSP_LIT, 0x0F, 0xFE,
+            MOV_LIT_REG, 0xE0, 0x00, 10,
+            MOV_LIT_REG, 0xE0, 0x00, 11,
+            STI,
+            USR_LIT, 0x10, 0x00,
+        ]);
+        load(&mut mm, 0x0100, &[0x02, 0x00, 0x03, 0x00, 0x04, 0x00, 0x05, 0x00, 0x00, 0x00, 0x06, 0x00]);
+
+        // Privileged instructions and protection faults kill the program, recording the detail
+        load(&mut mm, 0x0200, &[ARG_LIT_REG, 0x00, 0x01, R1, MOV_REG_MEM, R1, 0x08, 0x00, HLT]);
+        load(&mut mm, 0x0300, &[ARG_LIT_REG, 0x00, 0x01, R1, MOV_REG_MEM, R1, 0x08, 0x02, HLT]);
+        // Syscall n returns n + 1
+        load(&mut mm, 0x0400, &[
+            ARG_LIT_REG, 0x00, 0x01, R1,
+            MOV_LIT_REG, 0x00, 0x01, R2,
+            ADD_REG_REG, R1, R2,
+            SRET,
+        ]);
+        // Interrupts are counted
+        load(&mut mm, 0x0500, &[
+            MOV_MEM_REG, 0x08, 0x04, R1,
+            MOV_LIT_REG, 0x00, 0x01, R2,
+            ADD_REG_REG, R1, R2,
+            MOV_REG_MEM, ACC, 0x08, 0x04,
+            SRET,
+        ]);
+        // Division by zero kills the program like the first two
+        load(&mut mm, 0x0600, &[ARG_LIT_REG, 0x00, 0x01, R1, MOV_REG_MEM, R1, 0x08, 0x06, HLT]);
+
+        load(&mut mm, 0x1000, user_program);
+        CPU::new(mm)
+    }
+
+    #[test]
+    fn syscalls_and_protection() {
+        let mut cpu = tiny_os(&[
+            MOV_LIT_REG, 0x12, 0x34, R1,
+            SYSCALL, 0x00, 0x41,
+            MOV_REG_MEM, ACC, 0x20, 0x00,
+            MOV_MEM_REG, 0x08, 0x00, R2,
+        ]);
+
+        // Run up to the user program's first instruction
+        while cpu.get_register(Register::Ip) != 0x1000 {
+            cpu.step().unwrap();
+        }
+        assert!(!cpu.control().supervisor);
+
+        // The syscall comes back to user mode with the stack and registers the way they were
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.control().supervisor);
+        assert_eq!(cpu.get_register(Register::Ip), 0x0400);
+        assert_eq!(cpu.get_register(Register::Sp), 0x0FFE - 2 * 17);
+        while cpu.get_register(Register::Ip) != 0x1007 {
+            cpu.step().unwrap();
+        }
+        assert!(!cpu.control().supervisor);
+        assert_eq!(cpu.get_register(Register::Acc), 0x42);
+        assert_eq!(cpu.get_register(Register::R1), 0x1234);
+        assert_eq!(cpu.get_register(Register::Sp), 0xE000);
+        assert_eq!(cpu.get_register(Register::Fp), 0xE000);
+
+        // Reading kernel memory traps before R2 is written
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+        assert_eq!(cpu.memory().read_at_u16(0x2000), Some(0x0042));
+        assert_eq!(cpu.memory().read_at_u16(0x0802), Some(0x0800));
+        assert_eq!(cpu.get_register(Register::R2), 0);
+        assert_eq!(cpu.backtrace().frames[0].return_address, 0x100B);
+
+        // User mode can't halt the machine
+        let mut cpu = tiny_os(&[HLT]);
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+        assert_eq!(cpu.memory().read_at_u16(0x0800), Some(HLT as u16));
+        assert_eq!(cpu.get_register(Register::Ip), 0x0209);
+    }
+
+    #[test]
+    fn signed_arithmetic() {
+        let mut memory = create_memory(256);
+
+        let program = [
+            // -10 and 3
+            MOV_LIT_REG, 0xFF, 0xF6, R1,
+            MOV_LIT_REG, 0x00, 0x03, R2,
+            ASR_REG_REG, R1, R2,
+            MOV_REG_REG, ACC, R3,
+            NEG_REG, R1,
+            MOV_REG_REG, ACC, R4,
+            MOV_LIT_REG, 0x12, 0x80, R5,
+            SXB_REG, R5,
+            MOV_REG_REG, ACC, R6,
+            SCMP_REG_REG, R1, R2,
+            MOV_REG_REG, ACC, R7,
+            // Signed -10 is less than 3, unsigned 0xFFF6 isn't
+            MOV_REG_REG, R1, ACC,
+            JLTS_REG, R2, 0x00, 0x2A,
+            HLT,
+            JGES_REG, R2, 0x00, 0x29,
+            JGTS_REG, R2, 0x00, 0x29,
+            JLT_REG, R2, 0x00, 0x29,
+            JLES_REG, R1, 0x00, 0x3B,
+            HLT,
+            HLT,
+        ];
+        memory[..program.len()].copy_from_slice(&program);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_register(Register::Sp, 0xFE);
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+
+        assert_eq!(cpu.get_register(Register::R3), (-2i16) as u16);
+        assert_eq!(cpu.get_register(Register::R4), 10);
+        assert_eq!(cpu.get_register(Register::R6), 0xFF80);
+        assert_eq!(cpu.get_register(Register::R7), 0xFFFF);
+        assert_eq!(cpu.get_register(Register::Ip), 0x3C);
+    }
+
+    #[test]
+    fn division() {
+        let mut memory = create_memory(256);
+
+        let program = [
+            MOV_LIT_REG, 0x00, 0x11, R1,
+            MOV_LIT_REG, 0x00, 0x05, R2,
+            DIV_REG_REG, R1, R2, R3,
+            MOV_REG_REG, ACC, R4,
+            MOD_REG_LIT, R1, 0x00, 0x04,
+            MOV_REG_REG, ACC, R5,
+            // -17
+            MOV_LIT_REG, 0xFF, 0xEF, R1,
+            SDIV_REG_LIT, R1, 0x00, 0x05, R6,
+            MOV_REG_REG, ACC, R7,
+            SMOD_REG_REG, R1, R2,
+            MOV_REG_REG, ACC, R8,
+            MOD_REG_LIT, R1, 0x00, 0x00,
+            HLT,
+        ];
+        memory[..program.len()].copy_from_slice(&program);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_register(Register::Sp, 0xFE);
+        assert!(matches!(cpu.run(), Err(ExecuteError::DivideByZero(0x28))));
+
+        assert_eq!(cpu.get_register(Register::R4), 3);
+        assert_eq!(cpu.get_register(Register::R3), 2);
+        assert_eq!(cpu.get_register(Register::R5), 1);
+        assert_eq!(cpu.get_register(Register::R7), (-3i16) as u16);
+        assert_eq!(cpu.get_register(Register::R6), (-2i16) as u16);
+        assert_eq!(cpu.get_register(Register::R8), (-2i16) as u16);
+
+        // With a trap table it's a fault the kernel handles
+        let mut cpu = tiny_os(&[
+            MOV_LIT_REG, 0x00, 0x01, R2,
+            DIV_REG_LIT, R2, 0x00, 0x00, R2,
+        ]);
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+        assert_eq!(cpu.memory().read_at_u16(0x0806), Some(0x1004));
+        assert_eq!(cpu.backtrace().frames[0].return_address, 0x1004);
+    }
+
+    #[test]
+    fn block_copy_and_fill() {
+        // The source straddles two devices
+        let mut mm = MemoryMapper::new();
+        mm.map(Box::new(Memory::from_num_of_bytes(0x100)), 0x000, 0x0FF, true);
+        mm.map(Box::new(Memory::from_num_of_bytes(0x100)), 0x100, 0x1FF, true);
+        load(&mut mm, 0x00, &[
+            MCPY_REG_REG_REG, R1, R2, R3,
+            MSET_REG_REG_REG, R4, R2, R5,
+            HLT,
+        ]);
+        let data: Vec<u8> = (1..=20).collect();
+        load(&mut mm, 0xF8, &data);
+
+        let mut cpu = CPU::new(mm);
+        cpu.set_register(Register::Sp, 0xF0);
+        cpu.set_register(Register::R1, 0xF8);
+        cpu.set_register(Register::R2, 0x180);
+        cpu.set_register(Register::R3, 20);
+        cpu.set_register(Register::R4, 0x12AA);
+        cpu.set_register(Register::R5, 3);
+        cpu.add_breakpoint(0x00);
+        assert_eq!(cpu.run().unwrap(), StopReason::Breakpoint(0x00));
+
+        // The first step copies a chunk and comes back to the same instruction for the rest
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(Register::Ip), 0x00);
+        assert_eq!(cpu.get_register(Register::R3), 20 - BLOCK_CHUNK_SIZE);
+        assert_eq!(cpu.cycles(), 2 + BLOCK_CHUNK_SIZE as u64 * 2 * MEMORY_ACCESS_CYCLES);
+
+        // without stopping at the breakpoint again
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+        assert_eq!(cpu.cycles(), 2 * 2 + 20 * 2 * MEMORY_ACCESS_CYCLES + 2 + 3 * MEMORY_ACCESS_CYCLES + 1);
+        assert_eq!(cpu.get_register(Register::R1), 0xF8 + 20);
+        assert_eq!(cpu.get_register(Register::R2), 0x180 + 20 + 3);
+        assert_eq!(cpu.get_register(Register::R3), 0);
+        assert_eq!(cpu.get_register(Register::R5), 0);
+        let copied: Vec<u8> = (0x180..0x180 + 23).map(|a| cpu.memory().read_at_u8(a).unwrap()).collect();
+        assert_eq!(copied[..20], data[..]);
+        assert_eq!(copied[20..], [0xAA; 3]);
+        // The chunks after the first carry on the same instruction
+        assert_eq!(cpu.instructions_executed(), 3);
+
+        // Interrupts are taken between chunks and the copy carries on after them
+        let mut cpu = tiny_os(&[MCPY_REG_REG_REG, R1, R2, R3, HLT]);
+        while cpu.get_register(Register::Ip) != 0x1000 {
+            cpu.step().unwrap();
+        }
+        for address in 0x2000..0x2028 {
+            cpu.memory_mut().write_at_u8(address, address as u8).unwrap();
+        }
+        cpu.set_register(Register::R1, 0x2000);
+        cpu.set_register(Register::R2, 0x3000);
+        cpu.set_register(Register::R3, 0x28);
+
+        cpu.step().unwrap();
+        cpu.interrupt(3);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(Register::Ip), 0x0500);
+        assert_eq!(cpu.get_register(Register::R3), 0x28 - BLOCK_CHUNK_SIZE);
+
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+        assert_eq!(cpu.memory().read_at_u16(0x0804), Some(1));
+        assert_eq!(cpu.memory().read_at_u16(0x0800), Some(HLT as u16));
+        assert_eq!(cpu.get_register(Register::R3), 0);
+        assert!((0..0x28).all(|i| cpu.memory().read_at_u8(0x3000 + i) == Some(i as u8)));
+
+        // Copying out of kernel memory faults on the first byte
+        let mut cpu = tiny_os(&[MCPY_REG_REG_REG, R1, R2, R3]);
+        while cpu.get_register(Register::Ip) != 0x1000 {
+            cpu.step().unwrap();
+        }
+        cpu.set_register(Register::R1, 0x0010);
+        cpu.set_register(Register::R2, 0x3000);
+        cpu.set_register(Register::R3, 4);
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+        assert_eq!(cpu.memory().read_at_u16(0x0802), Some(0x0010));
+    }
+
+    #[test]
+    fn interrupts() {
+        // Counts up forever in r1
+        let mut cpu = tiny_os(&[
+            MOV_LIT_REG, 0x00, 0x01, R2,
+            ADD_REG_REG, R1, R2,
+            MOV_REG_REG, ACC, R1,
+            JMP_NOT_EQ, 0x00, 0x00, 0x10, 0x04,
+        ]);
+        cpu.enable_history(100);
+
+        for _ in 0..20 {
+            cpu.step().unwrap();
+        }
+        cpu.interrupt(3);
+        cpu.interrupt(3);
+        for _ in 0..40 {
+            cpu.step().unwrap();
+        }
+
+        assert_eq!(cpu.memory().read_at_u16(0x0804), Some(2));
+        assert!(!cpu.control().supervisor);
+        assert!(cpu.control().interrupts_enabled);
+        assert_eq!(cpu.get_register(Register::Sp), 0xE000);
+
+        // Stepping back over an interrupt queues it again
+        while cpu.get_register(Register::Ip) != 0x0500 {
+            cpu.step_back().unwrap();
+        }
+        cpu.step_back().unwrap();
+        assert!(!cpu.control().supervisor);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(Register::Ip), 0x0500);
+
+        // Without a trap table the interrupt waits
+        let mut cpu = CPU::new(Memory::from_vec(vec![HLT; 16]));
+        cpu.interrupt(1);
+        assert_eq!(cpu.step().unwrap(), Some(StopReason::Halt));
+
+        // and traps are errors
+        let mut memory = vec![0; 16];
+        memory[..3].copy_from_slice(&[SYSCALL, 0x00, 0x05]);
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        assert!(matches!(cpu.step(), Err(ExecuteError::Trap(Trap::Syscall(5)))));
+    }
+
+    fn map_page(mm: &mut MemoryMapper, page_table: usize, page: usize, frame: u16, flags: u16) {
+        let entry = page_table * 256 + page * 4;
+        mm.write_at_u16(entry, frame).unwrap();
+        mm.write_at_u16(entry + 2, flags).unwrap();
+    }
+
+    #[test]
+    fn paging() {
+        let mut mm = MemoryMapper::new();
+        mm.map(Box::new(Memory::from_num_of_bytes(0x40000)), 0x00000, 0x3FFFF, true);
+
+        load(&mut mm, 0x0000, &[
+            STT_LIT, 0x01, 0x00,
+            SSP_LIT, 0x0F, 0xFE,
+            PTB_LIT, 0x00, 0x10,
+            PGE,
+            MOV_LIT_REG, 0x21, 0xFE, 10,
+            MOV_LIT_REG, 0x21, 0xFE, 11,
+            USR_LIT, 0x20, 0x00,
+        ]);
+        load(&mut mm, 0x0100, &[0x06, 0x00, 0x06, 0x00, 0x04, 0x00, 0x06, 0x00, 0x05, 0x00]);
+        // The syscall switches to the second process
+        load(&mut mm, 0x0400, &[
+            PTB_LIT, 0x00, 0x14,
+            MOV_LIT_REG, 0x21, 0xFE, 10,
+            MOV_LIT_REG, 0x21, 0xFE, 11,
+            USR_LIT, 0x20, 0x00,
+        ]);
+        // Page faults record the access and address
+        load(&mut mm, 0x0500, &[
+            FLT_REG, R1,
+            MOV_REG_MEM, R1, 0x08, 0x02,
+            ARG_LIT_REG, 0x00, 0x01, R2,
+            MOV_REG_MEM, R2, 0x08, 0x04,
+            HLT,
+        ]);
+        load(&mut mm, 0x0600, &[HLT]);
+
+        // Both processes see the kernel, but only in supervisor mode
+        for page_table in [0x10, 0x14] {
+            for page in 0..0x10 {
+                map_page(&mut mm, page_table, page, page as u16, PAGE_PRESENT | PAGE_READ | PAGE_WRITE | PAGE_EXECUTE);
+            }
+        }
+        let user = PAGE_PRESENT | PAGE_READ | PAGE_WRITE | PAGE_EXECUTE | PAGE_USER;
+        map_page(&mut mm, 0x10, 0x20, 0x100, user);
+        map_page(&mut mm, 0x10, 0x21, 0x101, user);
+        map_page(&mut mm, 0x14, 0x20, 0x200, user);
+        // Not next to the frame before it, so a word at 0x20FF is split
+        map_page(&mut mm, 0x14, 0x21, 0x300, user);
+
+        load(&mut mm, 0x10000, &[
+            MOV_LIT_REG, 0xAA, 0xAA, R1,
+            MOV_REG_MEM, R1, 0x20, 0x80,
+            SYSCALL, 0x00, 0x00,
+        ]);
+        load(&mut mm, 0x20000, &[
+            MOV_LIT_REG, 0xBB, 0xBB, R1,
+            MOV_REG_MEM, R1, 0x20, 0x80,
+            MOV_LIT_REG, 0x12, 0x34, R2,
+            MOV_REG_MEM, R2, 0x20, 0xFF,
+            MOV_MEM_REG, 0x08, 0x00, R3,
+        ]);
+
+        let mut cpu = CPU::new(mm);
+        cpu.enable_history(1000);
+        assert_eq!(cpu.run().unwrap(), StopReason::Halt);
+
+        let memory = cpu.memory();
+        assert_eq!(memory.read_at_u16(0x10080), Some(0xAAAA));
+        assert_eq!(memory.read_at_u16(0x20080), Some(0xBBBB));
+        assert_eq!(memory.read_at_u8(0x200FF), Some(0x12));
+        assert_eq!(memory.read_at_u8(0x30000), Some(0x34));
+        assert_eq!(memory.read_at_u16(0x0802), Some(PAGE_READ | PAGE_USER));
+        assert_eq!(memory.read_at_u16(0x0804), Some(0x0800));
+        assert_eq!(cpu.get_register(Register::R3), 0);
+        // The backtrace reads the trap frame through the page table
+        assert_eq!(cpu.backtrace().frames[0].return_address, 0x2010);
+
+        while cpu.step_back().is_some() {}
+        let memory = cpu.memory();
+        assert_eq!(memory.read_at_u16(0x10080), Some(0));
+        assert_eq!(memory.read_at_u8(0x200FF), Some(0));
+        assert_eq!(memory.read_at_u8(0x30000), Some(0));
+        assert!(!cpu.control().paging);
+    }
+
+    #[test]
+    fn argument_out_of_range() {
+        let program = [
+            PSH_LIT, 0x12, 0x34,
+            PSH_LIT, 0x00, 0x01,
+            CAL_LIT, 0x00, 0x10,
+            HLT,
+        ];
+        let subroutine = [
+            ARG_LIT_REG, 0x00, 0x00, R1,
+            ARG_LIT_REG, 0x00, 0x01, R2,
+            RET,
+        ];
+
+        let mut memory = create_memory(256 * 256);
+        memory[..program.len()].copy_from_slice(&program);
+        memory[0x10..0x10 + subroutine.len()].copy_from_slice(&subroutine);
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+
+        match cpu.run() {
+            Err(ExecuteError::ArgumentOutOfRange(1)) => {}
+            other => panic!("expected ArgumentOutOfRange(1), got {:?}", other),
+        }
+        assert_eq!(cpu.get_register(Register::R1), 0x1234);
+
+        // A bogus argument count can't reach past the address space
+        let program = [
+            PSH_LIT, 0xFF, 0xFF,
+            CAL_LIT, 0x00, 0x10,
+            HLT,
+        ];
+        let subroutine = [
+            ARG_LIT_REG, 0x00, 0x00, R1,
+            RET,
+        ];
+
+        let mut memory = create_memory(256 * 256);
+        memory[..program.len()].copy_from_slice(&program);
+        memory[0x10..0x10 + subroutine.len()].copy_from_slice(&subroutine);
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+
+        assert!(matches!(cpu.run(), Err(ExecuteError::MemoryFault(_))));
+    }
+
+    // xorshift, so the generated programs are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn word(&mut self) -> [u8; 2] {
+            (self.next() as u16).to_be_bytes()
+        }
+    }
+
+    const FUNCTIONS: u16 = 6;
+    const RECURSIVE: u16 = 0x3000;
+    const RECURSION_LIMIT: u8 = 6;
+
+    fn function_address(k: u16) -> u16 {
+        0x1000 + k * 0x200
+    }
+
+    // Pushes the arguments and argument count, then calls `address` with CAL_LIT or CAL_REG
+    fn emit_call(rng: &mut Rng, code: &mut Vec<u8>, address: u16, args: &[[u8; 2]]) {
+        let by_register = rng.below(2) == 0;
+        let reg = 2 + rng.below(8) as u8;
+        if by_register {
+            code.extend([MOV_LIT_REG, (address >> 8) as u8, address as u8, reg]);
+        }
+
+        for arg in args {
+            code.extend([PSH_LIT, arg[0], arg[1]]);
+        }
+        code.extend([PSH_LIT, 0x00, args.len() as u8]);
+
+        if by_register {
+            code.extend([CAL_REG, reg]);
+        } else {
+            code.extend([CAL_LIT, (address >> 8) as u8, address as u8]);
+        }
+    }
+
+    fn emit_body(rng: &mut Rng, code: &mut Vec<u8>, k: u16, arity: &[u8]) {
+        let mut pushed = 0;
+        for _ in 0..rng.below(10) {
+            let reg = 2 + rng.below(8) as u8;
+            match rng.below(7) {
+                0 => code.extend([MOV_LIT_REG, rng.word()[0], rng.word()[1], reg]),
+                1 => {
+                    code.extend([PSH_LIT, rng.word()[0], rng.word()[1]]);
+                    pushed += 1;
+                }
+                2 => {
+                    code.extend([PSH_REG, reg]);
+                    pushed += 1;
+                }
+                3 if pushed > 0 => {
+                    code.extend([POP, reg]);
+                    pushed -= 1;
+                }
+                4 if k < FUNCTIONS && arity[k as usize] > 0 => {
+                    let index = rng.below(arity[k as usize] as u64) as u8;
+                    code.extend([ARG_LIT_REG, 0x00, index, reg]);
+                }
+                5 => {
+                    let start = rng.below(RECURSION_LIMIT as u64) as u8;
+                    emit_call(rng, code, RECURSIVE, &[[0x00, start]]);
+                }
+                6 if k + 1 < FUNCTIONS => {
+                    let callee = k + 1 + rng.below((FUNCTIONS - k - 1) as u64) as u16;
+                    let args: Vec<_> = (0..arity[callee as usize]).map(|_| rng.word()).collect();
+                    emit_call(rng, code, function_address(callee), &args);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn random_call_program(seed: u64) -> Vec<u8> {
+        let mut rng = Rng(seed);
+        let mut memory = create_memory(256 * 256);
+
+        let arity: Vec<u8> = (0..FUNCTIONS).map(|_| rng.below(4) as u8).collect();
+
+        // main is function number FUNCTIONS so it can call any of the others
+        let mut main = vec![];
+        for _ in 0..3 {
+            let callee = rng.below(FUNCTIONS as u64) as u16;
+            let args: Vec<_> = (0..arity[callee as usize]).map(|_| rng.word()).collect();
+            emit_call(&mut rng, &mut main, function_address(callee), &args);
+            emit_body(&mut rng, &mut main, FUNCTIONS, &arity);
+        }
+        main.push(HLT);
+        memory[..main.len()].copy_from_slice(&main);
+
+        for k in 0..FUNCTIONS {
+            let mut code = vec![];
+            emit_body(&mut rng, &mut code, k, &arity);
+            code.push(RET);
+
+            let address = function_address(k) as usize;
+            memory[address..address + code.len()].copy_from_slice(&code);
+        }
+
+        // Calls itself with its argument plus one until it reaches RECURSION_LIMIT
+        let recurse = RECURSIVE + 21;
+        let code = [
+            ARG_LIT_REG, 0x00, 0x00, R1,
+            MOV_LIT_REG, 0x00, 0x01, R2,
+            ADD_REG_REG, R1, R2,
+            JMP_NOT_EQ, 0x00, RECURSION_LIMIT, (recurse >> 8) as u8, recurse as u8,
+            MOV_LIT_REG, 0xFF, 0xFF, R8,
+            RET,
+        ];
+        let address = RECURSIVE as usize;
+        memory[address..address + code.len()].copy_from_slice(&code);
+        memory[recurse as usize] = PSH_REG;
+        memory[recurse as usize + 1] = ACC;
+        memory[recurse as usize + 2] = PSH_LIT;
+        memory[recurse as usize + 3] = 0x00;
+        memory[recurse as usize + 4] = 0x01;
+        memory[recurse as usize + 5] = CAL_LIT;
+        memory[recurse as usize + 6] = (RECURSIVE >> 8) as u8;
+        memory[recurse as usize + 7] = RECURSIVE as u8;
+        memory[recurse as usize + 8] = RET;
+
+        memory
+    }
+
+    struct PendingCall {
+        return_address: u16,
+        sp: u16,
+        fp: u16,
+        stack_frame_size: u16,
+        registers: Vec<u16>,
+        args: Vec<u16>,
+    }
+
+    const SAVED: [Register; 8] = [
+        Register::R1, Register::R2, Register::R3, Register::R4,
+        Register::R5, Register::R6, Register::R7, Register::R8,
+    ];
+
+    #[test]
+    fn calls_restore_caller_state() {
+        for seed in 1..=200 {
+            let memory = Memory::from_vec(random_call_program(seed));
+            let mut cpu = CPU::new(memory);
+            let mut calls: Vec<PendingCall> = vec![];
+            let mut max_depth = 0;
+
+            loop {
+                let ip = cpu.get_register(Register::Ip);
+                let opcode = cpu.memory.read_at_u8(ip as usize).unwrap();
+
+                match opcode {
+                    CAL_LIT | CAL_REG => {
+                        // What RET has to put back: everything from before the arguments were pushed
+                        let sp = cpu.get_register(Register::Sp);
+                        let n_args = cpu.memory.read_at_u16(sp as usize + 2).unwrap();
+                        let args = (0..n_args)
+                            .map(|i| cpu.memory.read_at_u16((sp + 2 + (n_args - i) * 2) as usize).unwrap())
+                            .collect();
+
+                        calls.push(PendingCall {
+                            return_address: ip + if opcode == CAL_LIT { 3 } else { 2 },
+                            sp: sp + 2 + n_args * 2,
+                            fp: cpu.get_register(Register::Fp),
+                            stack_frame_size: cpu.stack_frame_size - 2 - n_args * 2,
+                            registers: SAVED.iter().map(|r| cpu.get_register(*r)).collect(),
+                            args,
+                        });
+                        max_depth = max_depth.max(calls.len());
+                    }
+                    _ => {}
+                }
+
+                let reg = cpu.memory.read_at_u8(ip as usize + 3).unwrap();
+                let index = cpu.memory.read_at_u16(ip as usize + 1).unwrap();
+
+                if cpu.step().unwrap() == Some(StopReason::Halt) {
+                    break;
+                }
+
+                match opcode {
+                    RET => {
+                        let call = calls.pop().expect("RET without a call");
+                        assert_eq!(cpu.get_register(Register::Ip), call.return_address, "seed {}", seed);
+                        assert_eq!(cpu.get_register(Register::Sp), call.sp, "seed {}", seed);
+                        assert_eq!(cpu.get_register(Register::Fp), call.fp, "seed {}", seed);
+                        assert_eq!(cpu.stack_frame_size, call.stack_frame_size, "seed {}", seed);
+                        let registers: Vec<u16> = SAVED.iter().map(|r| cpu.get_register(*r)).collect();
+                        assert_eq!(registers, call.registers, "seed {}", seed);
+                    }
+                    ARG_LIT_REG => {
+                        let call = calls.last().expect("ARG outside a call");
+                        let value = cpu.get_register(Register::from_index(reg));
+                        assert_eq!(value, call.args[index as usize], "seed {}", seed);
+                    }
+                    _ => {}
+                }
+            }
+
+            assert!(calls.is_empty());
+            assert!(max_depth > 0);
+            assert_eq!(cpu.get_register(Register::Fp), 0xFFFE);
+        }
+    }
 }
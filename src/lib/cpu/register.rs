@@ -1,4 +1,5 @@
-use enum_iterator::Sequence;
+use std::str::FromStr;
+use enum_iterator::{all, cardinality, Sequence};
 
 #[derive(Copy, Clone, Debug, Sequence, Hash, PartialEq, Eq)]
 pub enum Register {
@@ -15,3 +16,43 @@ pub enum Register {
     Sp,
     Fp,
 }
+
+impl Register {
+    // Same wrapping as CPU::fetch_register_index, so out of range bytes name the register the CPU would use
+    pub fn from_index(index: u8) -> Register {
+        all::<Register>().nth(index as usize % cardinality::<Register>())
+            .expect("index is in range")
+    }
+
+    pub fn index(self) -> u8 {
+        all::<Register>().position(|r| r == self)
+            .expect("register is in all::<Register>()") as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::Ip => "ip",
+            Register::Acc => "acc",
+            Register::R1 => "r1",
+            Register::R2 => "r2",
+            Register::R3 => "r3",
+            Register::R4 => "r4",
+            Register::R5 => "r5",
+            Register::R6 => "r6",
+            Register::R7 => "r7",
+            Register::R8 => "r8",
+            Register::Sp => "sp",
+            Register::Fp => "fp",
+        }
+    }
+}
+
+impl FromStr for Register {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        all::<Register>().find(|r| r.name() == s)
+            .ok_or(())
+    }
+}
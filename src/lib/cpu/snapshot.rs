@@ -0,0 +1,136 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use crate::cpu::ResetVector;
+use crate::cpu::supervisor::ControlRegisters;
+
+const MAGIC: &[u8; 8] = b"MAYOSNP3";
+
+// The whole state of a machine: the CPU's registers and everything its memory device saved
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: Vec<u8>,
+    pub stack_frame_size: u16,
+    pub control: ControlRegisters,
+    pub cycles: u64,
+    pub instructions: u64,
+    pub stack_base: u16,
+    pub stack_limit: u16,
+    pub reset_vector: ResetVector,
+    // Interrupts raised but not taken yet, oldest first
+    pub pending_interrupts: Vec<u16>,
+    pub device_state: Vec<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+// Splits the next `len` bytes off `bytes`
+fn take(bytes: &[u8], len: usize) -> io::Result<(&[u8], &[u8])> {
+    if bytes.len() < len {
+        return Err(invalid("snapshot is truncated"));
+    }
+    Ok(bytes.split_at(len))
+}
+
+fn take_u16(bytes: &[u8]) -> io::Result<(u16, &[u8])> {
+    let (value, rest) = take(bytes, 2)?;
+    Ok((u16::from_be_bytes([value[0], value[1]]), rest))
+}
+
+fn take_u64(bytes: &[u8]) -> io::Result<(u64, &[u8])> {
+    let (value, rest) = take(bytes, 8)?;
+    Ok((u64::from_be_bytes(value.try_into().expect("slice is 8 bytes")), rest))
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.registers.len() as u8);
+        bytes.extend(&self.registers);
+        let control = self.control.to_bytes();
+        bytes.push(control.len() as u8);
+        bytes.extend(control);
+        bytes.extend(self.stack_frame_size.to_be_bytes());
+        bytes.extend(self.cycles.to_be_bytes());
+        bytes.extend(self.instructions.to_be_bytes());
+        bytes.extend(self.stack_base.to_be_bytes());
+        bytes.extend(self.stack_limit.to_be_bytes());
+        let (kind, address) = match self.reset_vector {
+            ResetVector::Address(address) => (0, address),
+            ResetVector::Indirect(address) => (1, address),
+        };
+        bytes.push(kind);
+        bytes.extend(address.to_be_bytes());
+        bytes.extend((self.pending_interrupts.len() as u16).to_be_bytes());
+        for number in &self.pending_interrupts {
+            bytes.extend(number.to_be_bytes());
+        }
+        bytes.extend((self.device_state.len() as u32).to_be_bytes());
+        bytes.extend(&self.device_state);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let rest = bytes.strip_prefix(MAGIC.as_slice())
+            .ok_or_else(|| invalid("not a mayo snapshot"))?;
+
+        let (register_len, rest) = take(rest, 1)?;
+        let (registers, rest) = take(rest, register_len[0] as usize)?;
+
+        let (control_len, rest) = take(rest, 1)?;
+        let (control, rest) = take(rest, control_len[0] as usize)?;
+        let control = ControlRegisters::from_bytes(control)
+            .ok_or_else(|| invalid("snapshot control registers are invalid"))?;
+
+        let (stack_frame_size, rest) = take_u16(rest)?;
+        let (cycles, rest) = take_u64(rest)?;
+        let (instructions, rest) = take_u64(rest)?;
+        let (stack_base, rest) = take_u16(rest)?;
+        let (stack_limit, rest) = take_u16(rest)?;
+
+        let (kind, rest) = take(rest, 1)?;
+        let (address, rest) = take_u16(rest)?;
+        let reset_vector = match kind[0] {
+            0 => ResetVector::Address(address),
+            1 => ResetVector::Indirect(address),
+            _ => return Err(invalid("snapshot reset vector is invalid")),
+        };
+
+        let (pending_len, mut rest) = take_u16(rest)?;
+        let mut pending_interrupts = vec![];
+        for _ in 0..pending_len {
+            let (number, next) = take_u16(rest)?;
+            pending_interrupts.push(number);
+            rest = next;
+        }
+
+        let (device_len, device_state) = take(rest, 4)?;
+        let device_len = u32::from_be_bytes(device_len.try_into().expect("slice is 4 bytes")) as usize;
+        if device_state.len() != device_len {
+            return Err(invalid("snapshot device state has the wrong length"));
+        }
+
+        Ok(Snapshot {
+            registers: registers.to_vec(),
+            stack_frame_size,
+            control,
+            cycles,
+            instructions,
+            stack_base,
+            stack_limit,
+            reset_vector,
+            pending_interrupts,
+            device_state: device_state.to_vec(),
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
@@ -0,0 +1,143 @@
+use crate::cpu::STACK_START;
+
+// Bits of the status word traps save and SRET restores
+pub const STATUS_SUPERVISOR: u16 = 0x0001;
+pub const STATUS_INTERRUPTS: u16 = 0x0002;
+pub const STATUS_PAGING: u16 = 0x0004;
+
+// Index of each cause's handler address in the trap table
+pub const TRAP_PRIVILEGED_INSTRUCTION: u16 = 0;
+pub const TRAP_PROTECTION_FAULT: u16 = 1;
+pub const TRAP_SYSCALL: u16 = 2;
+pub const TRAP_INTERRUPT: u16 = 3;
+pub const TRAP_PAGE_FAULT: u16 = 4;
+pub const TRAP_DIVIDE_BY_ZERO: u16 = 5;
+
+// The arguments a trap frame passes to its handler, read with `arg`
+pub const TRAP_ARG_CAUSE: u16 = 0;
+pub const TRAP_ARG_DETAIL: u16 = 1;
+pub const TRAP_ARG_STATUS: u16 = 2;
+pub const TRAP_ARG_SP: u16 = 3;
+pub const TRAP_ARG_FP: u16 = 4;
+pub const TRAP_ARG_FRAME_SIZE: u16 = 5;
+pub const TRAP_ARGUMENTS: u16 = 6;
+
+// Spent switching to the handler, on top of the pushes
+pub const TRAP_CYCLES: u64 = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    // The opcode of a supervisor-only instruction executed in user mode
+    PrivilegedInstruction(u8),
+    // User mode touched an address in a supervisor-only region
+    ProtectionFault(u16),
+    Syscall(u16),
+    Interrupt(u16),
+    // A virtual address with no page table entry allowing the access, see mmu::translate
+    PageFault { address: u16, access: u16 },
+    // The address of a division by zero
+    DivideByZero(u16),
+}
+
+impl Trap {
+    pub fn cause(self) -> u16 {
+        match self {
+            Trap::PrivilegedInstruction(_) => TRAP_PRIVILEGED_INSTRUCTION,
+            Trap::ProtectionFault(_) => TRAP_PROTECTION_FAULT,
+            Trap::Syscall(_) => TRAP_SYSCALL,
+            Trap::Interrupt(_) => TRAP_INTERRUPT,
+            Trap::PageFault { .. } => TRAP_PAGE_FAULT,
+            Trap::DivideByZero(_) => TRAP_DIVIDE_BY_ZERO,
+        }
+    }
+
+    pub fn detail(self) -> u16 {
+        match self {
+            Trap::PrivilegedInstruction(opcode) => opcode as u16,
+            Trap::ProtectionFault(address) => address,
+            Trap::Syscall(number) => number,
+            Trap::Interrupt(number) => number,
+            Trap::PageFault { address, .. } => address,
+            Trap::DivideByZero(address) => address,
+        }
+    }
+}
+
+// CPU state that isn't in a register and that only supervisor mode can change
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ControlRegisters {
+    pub supervisor: bool,
+    pub interrupts_enabled: bool,
+    // Where the handler address for each trap cause is stored. Traps are returned as errors until it's set
+    pub trap_table: Option<u16>,
+    // Where Sp and Fp are moved to when a trap is taken from user mode
+    pub supervisor_stack: u16,
+    // Translate addresses through the page table at frame page_table
+    pub paging: bool,
+    pub page_table: u16,
+    // The access bits of the last page fault, for its handler to read with `flt`
+    pub fault_access: u16,
+}
+
+impl Default for ControlRegisters {
+    fn default() -> Self {
+        Self {
+            supervisor: true,
+            interrupts_enabled: false,
+            trap_table: None,
+            supervisor_stack: STACK_START,
+            paging: false,
+            page_table: 0,
+            fault_access: 0,
+        }
+    }
+}
+
+impl ControlRegisters {
+    pub fn status(&self) -> u16 {
+        let mut status = 0;
+        if self.supervisor {
+            status |= STATUS_SUPERVISOR;
+        }
+        if self.interrupts_enabled {
+            status |= STATUS_INTERRUPTS;
+        }
+        if self.paging {
+            status |= STATUS_PAGING;
+        }
+        status
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.supervisor = status & STATUS_SUPERVISOR != 0;
+        self.interrupts_enabled = status & STATUS_INTERRUPTS != 0;
+        self.paging = status & STATUS_PAGING != 0;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.status().to_be_bytes().to_vec();
+        bytes.push(self.trap_table.is_some() as u8);
+        bytes.extend(self.trap_table.unwrap_or(0).to_be_bytes());
+        bytes.extend(self.supervisor_stack.to_be_bytes());
+        bytes.extend(self.page_table.to_be_bytes());
+        bytes.extend(self.fault_access.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 11 {
+            return None;
+        }
+
+        let mut control = Self::default();
+        control.set_status(u16::from_be_bytes([bytes[0], bytes[1]]));
+        control.trap_table = match bytes[2] {
+            0 => None,
+            _ => Some(u16::from_be_bytes([bytes[3], bytes[4]])),
+        };
+        control.supervisor_stack = u16::from_be_bytes([bytes[5], bytes[6]]);
+        control.page_table = u16::from_be_bytes([bytes[7], bytes[8]]);
+        control.fault_access = u16::from_be_bytes([bytes[9], bytes[10]]);
+        Some(control)
+    }
+}
@@ -0,0 +1,479 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::rc::Rc;
+use crate::cpu::disassemble::decode;
+use crate::cpu::register::Register;
+use crate::cpu::supervisor::TRAP_INTERRUPT;
+use crate::cpu::watchpoint::WatchKind;
+use crate::debug_info::DebugInfo;
+
+const BINARY_MAGIC: &[u8; 8] = b"MAYOTRC5";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: usize,
+    // Only ever WatchKind::Read or WatchKind::Write
+    pub kind: WatchKind,
+    pub value: u16,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegisterDelta {
+    pub register: Register,
+    pub old: u16,
+    pub new: u16,
+}
+
+// The cause and detail of a trap a step entered, see Trap
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrapEntry {
+    pub cause: u16,
+    pub detail: u16,
+}
+
+// Everything one executed instruction did. Ip is left out of the register deltas, see next_ip. A step that enters
+// a trap handler has `trap` set with the handler at next_ip. Taking an interrupt is a step without an instruction,
+// its ip is where the interrupted code carries on. Block instructions like mcpy take a step per chunk, all but the
+// first are `continued` rather than another execution
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub ip: u16,
+    pub opcode: u8,
+    pub operands: Vec<u8>,
+    pub next_ip: u16,
+    pub cycles: u64,
+    pub register_deltas: Vec<RegisterDelta>,
+    pub memory_accesses: Vec<MemoryAccess>,
+    pub trap: Option<TrapEntry>,
+    pub continued: bool,
+}
+
+impl TraceEntry {
+    pub fn is_interrupt(&self) -> bool {
+        matches!(self.trap, Some(TrapEntry { cause: TRAP_INTERRUPT, .. }))
+    }
+
+    pub fn instruction_text(&self) -> String {
+        if let Some(trap) = self.trap.filter(|_| self.is_interrupt()) {
+            return format!("interrupt {}", trap.detail);
+        }
+
+        let mut bytes = vec![self.opcode];
+        bytes.extend(&self.operands);
+        decode(self.ip as usize, &bytes)
+            .map_or_else(|| format!("db ${:02X}", self.opcode), |d| d.text)
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:04X}: {:<20}", self.ip, self.instruction_text())?;
+
+        for delta in &self.register_deltas {
+            write!(f, " {}={:04X}->{:04X}", delta.register.name(), delta.old, delta.new)?;
+        }
+
+        for access in &self.memory_accesses {
+            let kind = if access.kind == WatchKind::Write { "W" } else { "R" };
+            write!(f, " {}[{:04X}]={:04X}", kind, access.address, access.value)?;
+        }
+
+        if let Some(trap) = self.trap.filter(|_| !self.is_interrupt()) {
+            write!(f, " trap {}:{:04X}", trap.cause, trap.detail)?;
+        }
+
+        if self.continued {
+            write!(f, " (continued)")?;
+        }
+
+        if self.is_interrupt() || self.next_ip as usize != self.ip as usize + 1 + self.operands.len() {
+            write!(f, " -> 0x{:04X}", self.next_ip)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub trait Tracer {
+    fn trace(&mut self, entry: &TraceEntry);
+
+    // Called once tracing is over, tracers that write somewhere flush and report the first write that failed
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<F> Tracer for F
+where
+    F: FnMut(&TraceEntry)
+{
+    fn trace(&mut self, entry: &TraceEntry) {
+        self(entry)
+    }
+}
+
+// Lets a tracer be shared so its results can be read after the CPU is done with it
+impl<T> Tracer for Rc<RefCell<T>>
+where
+    T: Tracer
+{
+    fn trace(&mut self, entry: &TraceEntry) {
+        self.borrow_mut().trace(entry)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.borrow_mut().finish()
+    }
+}
+
+// Passes every entry to two tracers, so a profile can be taken while writing a trace
+pub struct Tee {
+    first: Box<dyn Tracer>,
+    second: Box<dyn Tracer>,
+}
+
+impl Tee {
+    pub fn new(first: Box<dyn Tracer>, second: Box<dyn Tracer>) -> Self {
+        Self {
+            first,
+            second,
+        }
+    }
+}
+
+impl Tracer for Tee {
+    fn trace(&mut self, entry: &TraceEntry) {
+        self.first.trace(entry);
+        self.second.trace(entry);
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let first = self.first.finish();
+        let second = self.second.finish();
+        first.and(second)
+    }
+}
+
+// Tracers keep the first error writing the trace for finish to report, and stop writing rather than stopping the run
+fn finish_output<W: Write>(output: &mut W, error: &mut Option<io::Error>) -> io::Result<()> {
+    match error.take() {
+        Some(e) => Err(e),
+        None => output.flush(),
+    }
+}
+
+// Writes one human readable line per instruction
+pub struct TextTracer<W: Write> {
+    output: W,
+    // Names each instruction's address and source line at the end of its line
+    debug_info: Option<DebugInfo>,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            debug_info: None,
+            error: None,
+        }
+    }
+
+    pub fn with_debug_info(output: W, debug_info: DebugInfo) -> Self {
+        Self {
+            output,
+            debug_info: Some(debug_info),
+            error: None,
+        }
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let written = match &self.debug_info {
+            Some(info) => writeln!(self.output, "{}  ; {}", entry, info.format_address(entry.ip)),
+            None => writeln!(self.output, "{}", entry),
+        };
+        self.error = written.err();
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        finish_output(&mut self.output, &mut self.error)
+    }
+}
+
+// Writes a compact big-endian encoding that read_binary_trace turns back into entries
+pub struct BinaryTracer<W: Write> {
+    output: W,
+    wrote_header: bool,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> BinaryTracer<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            wrote_header: false,
+            error: None,
+        }
+    }
+
+    fn write_entry(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        if !self.wrote_header {
+            self.output.write_all(BINARY_MAGIC)?;
+            self.wrote_header = true;
+        }
+
+        let mut bytes = vec![];
+        bytes.extend(entry.ip.to_be_bytes());
+        bytes.push(entry.opcode);
+        bytes.push(entry.operands.len() as u8);
+        bytes.extend(&entry.operands);
+        bytes.extend(entry.next_ip.to_be_bytes());
+        bytes.extend(entry.cycles.to_be_bytes());
+
+        bytes.push(entry.register_deltas.len() as u8);
+        for delta in &entry.register_deltas {
+            bytes.push(delta.register.index());
+            bytes.extend(delta.old.to_be_bytes());
+            bytes.extend(delta.new.to_be_bytes());
+        }
+
+        bytes.push(entry.memory_accesses.len() as u8);
+        for access in &entry.memory_accesses {
+            bytes.push((access.kind == WatchKind::Write) as u8);
+            bytes.extend((access.address as u32).to_be_bytes());
+            bytes.extend(access.value.to_be_bytes());
+        }
+
+        match entry.trap {
+            Some(trap) => {
+                bytes.push(1);
+                bytes.extend(trap.cause.to_be_bytes());
+                bytes.extend(trap.detail.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.push(entry.continued as u8);
+
+        self.output.write_all(&bytes)
+    }
+}
+
+impl<W: Write> Tracer for BinaryTracer<W> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        if self.error.is_none() {
+            self.error = self.write_entry(entry).err();
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        finish_output(&mut self.output, &mut self.error)
+    }
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    input.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_entry<R: Read>(input: &mut R, ip: u16) -> io::Result<TraceEntry> {
+    let opcode = read_u8(input)?;
+
+    let mut operands = vec![0; read_u8(input)? as usize];
+    input.read_exact(&mut operands)?;
+
+    let next_ip = read_u16(input)?;
+    let cycles = read_u64(input)?;
+
+    let mut register_deltas = vec![];
+    for _ in 0..read_u8(input)? {
+        register_deltas.push(RegisterDelta {
+            register: Register::from_index(read_u8(input)?),
+            old: read_u16(input)?,
+            new: read_u16(input)?,
+        });
+    }
+
+    let mut memory_accesses = vec![];
+    for _ in 0..read_u8(input)? {
+        let kind = if read_u8(input)? == 1 { WatchKind::Write } else { WatchKind::Read };
+        let mut address = [0; 4];
+        input.read_exact(&mut address)?;
+        memory_accesses.push(MemoryAccess {
+            address: u32::from_be_bytes(address) as usize,
+            kind,
+            value: read_u16(input)?,
+        });
+    }
+
+    let trap = match read_u8(input)? {
+        0 => None,
+        _ => Some(TrapEntry {
+            cause: read_u16(input)?,
+            detail: read_u16(input)?,
+        }),
+    };
+    let continued = read_u8(input)? != 0;
+
+    Ok(TraceEntry {
+        ip,
+        opcode,
+        operands,
+        next_ip,
+        cycles,
+        register_deltas,
+        memory_accesses,
+        trap,
+        continued,
+    })
+}
+
+pub fn read_binary_trace<R: Read>(mut input: R) -> io::Result<Vec<TraceEntry>> {
+    let mut magic = [0; 8];
+    match input.read_exact(&mut magic) {
+        Ok(()) => {}
+        // Nothing was traced
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(vec![]),
+        Err(e) => return Err(e),
+    }
+
+    if &magic != BINARY_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a mayo trace file"));
+    }
+
+    let mut entries = vec![];
+    loop {
+        let ip = match read_u16(&mut input) {
+            Ok(ip) => ip,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(entries),
+            Err(e) => return Err(e),
+        };
+        entries.push(read_entry(&mut input, ip)?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+    use crate::cpu::CPU;
+    use crate::cpu::instructions::*;
+    use crate::cpu::register::Register;
+    use crate::cpu::trace::{BinaryTracer, MemoryAccess, read_binary_trace, RegisterDelta, TextTracer, TraceEntry, Tracer, TrapEntry};
+    use crate::cpu::watchpoint::WatchKind;
+    use crate::devices::memory::Memory;
+
+    fn traced_run(program: &[u8]) -> Vec<TraceEntry> {
+        let mut memory = program.to_vec();
+        memory.resize(0x100, 0);
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_register(Register::Sp, 0xFE);
+
+        let entries = Rc::new(RefCell::new(vec![]));
+        let recorded = entries.clone();
+        cpu.set_tracer(Box::new(move |entry: &TraceEntry| recorded.borrow_mut().push(entry.clone())));
+        cpu.run().unwrap();
+
+        let entries = entries.borrow().clone();
+        entries
+    }
+
+    #[test]
+    fn records_registers_and_memory() {
+        let entries = traced_run(&[
+            MOV_LIT_REG, 0x12, 0x34, 2,
+            PSH_REG, 2,
+            HLT,
+        ]);
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].operands, vec![0x12, 0x34, 2]);
+        assert_eq!(entries[0].register_deltas, vec![RegisterDelta { register: Register::R1, old: 0, new: 0x1234 }]);
+        assert_eq!(entries[1].memory_accesses, vec![MemoryAccess { address: 0xFE, kind: WatchKind::Write, value: 0x1234 }]);
+        assert_eq!(entries[1].register_deltas, vec![RegisterDelta { register: Register::Sp, old: 0xFE, new: 0xFC }]);
+        assert_eq!(entries[1].next_ip, 6);
+        assert_eq!(entries[1].to_string(), "0x0004: psh r1               sp=00FE->00FC W[00FE]=1234");
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut entries = traced_run(&[
+            MOV_LIT_REG, 0x00, 0x05, 2,
+            PSH_LIT, 0xAB, 0xCD,
+            POP, 3,
+            JMP_NOT_EQ, 0x00, 0x01, 0x00, 0x0F,
+            HLT, HLT,
+        ]);
+        // Longer than a word's worth of cycles
+        entries[0].cycles = 100_000;
+        entries[1].trap = Some(TrapEntry {
+            cause: 2,
+            detail: 0x1234,
+        });
+        entries[2].continued = true;
+
+        let mut output = vec![];
+        {
+            let mut tracer = BinaryTracer::new(&mut output);
+            for entry in &entries {
+                tracer.write_entry(entry).unwrap();
+            }
+        }
+
+        assert_eq!(read_binary_trace(output.as_slice()).unwrap(), entries);
+        assert_eq!(entries[3].next_ip, 0x0F);
+    }
+
+    struct FullDisk {
+        writes: usize,
+    }
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_errors() {
+        let entries = traced_run(&[MOV_LIT_REG, 0x00, 0x05, 2, HLT]);
+
+        // The first failure is kept for finish instead of stopping the run, and nothing more is written after it
+        let mut tracer = TextTracer::new(FullDisk { writes: 0 });
+        for entry in &entries {
+            tracer.trace(entry);
+        }
+        assert_eq!(tracer.finish().unwrap_err().to_string(), "disk full");
+        assert_eq!(tracer.output.writes, 1);
+        assert!(tracer.finish().is_ok());
+
+        let mut tracer = BinaryTracer::new(FullDisk { writes: 0 });
+        tracer.trace(&entries[0]);
+        assert!(tracer.finish().is_err());
+    }
+}
@@ -0,0 +1,27 @@
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Either a read or a write
+    Access,
+}
+
+impl WatchKind {
+    fn covers(self, access: WatchKind) -> bool {
+        self == WatchKind::Access || self == access
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Watchpoint {
+    pub start: usize,
+    // Inclusive, like the ranges given to MemoryMapper::map
+    pub end: usize,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: usize, size: usize, access: WatchKind) -> bool {
+        self.kind.covers(access) && address <= self.end && address + size > self.start
+    }
+}
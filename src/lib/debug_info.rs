@@ -0,0 +1,147 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use crate::machine::parse_number;
+use crate::symbols::SymbolTable;
+
+// The statement that assembled to the `size` bytes at `address`. In an object, `address` is an offset in its section
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineInfo {
+    pub address: u16,
+    pub size: u16,
+    pub file: String,
+    pub line: usize,
+}
+
+// Where a program's bytes came from: its labels and the source line of each statement.
+// Written to a file as `symbol <address> <name>` and `line <address> <size> <file>:<line>` lines
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub symbols: SymbolTable,
+    // Sorted by address
+    lines: Vec<LineInfo>,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| e.to_string())?;
+        text.parse()
+    }
+
+    pub fn add_line(&mut self, line: LineInfo) {
+        let i = self.lines.partition_point(|l| l.address <= line.address);
+        self.lines.insert(i, line);
+    }
+
+    pub fn lines(&self) -> &[LineInfo] {
+        &self.lines
+    }
+
+    // The file and line of the statement whose bytes include `address`
+    pub fn location(&self, address: u16) -> Option<(&str, usize)> {
+        let i = self.lines.partition_point(|l| l.address <= address);
+        let line = self.lines[..i].last()?;
+        if (address as usize) < line.address as usize + line.size as usize {
+            Some((line.file.as_str(), line.line))
+        } else {
+            None
+        }
+    }
+
+    // `print_string+0x04 (strings.asm:17)`, leaving out whichever part isn't known
+    pub fn format_address(&self, address: u16) -> String {
+        let name = self.symbols.format_address(address);
+        match self.location(address) {
+            Some((file, line)) => format!("{} ({}:{})", name, file, line),
+            None => name,
+        }
+    }
+}
+
+impl FromStr for DebugInfo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut info = DebugInfo::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let error = |message: &str| format!("line {}: {}", i + 1, message);
+            let text = line.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+
+            let (kind, rest) = text.split_once(char::is_whitespace)
+                .ok_or_else(|| error("expected `symbol` or `line`"))?;
+            let (address, rest) = rest.trim().split_once(char::is_whitespace)
+                .ok_or_else(|| error("missing address"))?;
+            let address = parse_number(address)
+                .and_then(|a| u16::try_from(a).ok())
+                .ok_or_else(|| error(&format!("invalid address `{}`", address)))?;
+
+            match kind {
+                "symbol" => info.symbols.insert(rest.trim(), address),
+                "line" => {
+                    let (size, location) = rest.trim().split_once(char::is_whitespace)
+                        .ok_or_else(|| error("expected `line <address> <size> <file>:<line>`"))?;
+                    let size = parse_number(size)
+                        .and_then(|s| u16::try_from(s).ok())
+                        .ok_or_else(|| error(&format!("invalid size `{}`", size)))?;
+                    let (file, line) = location.trim().rsplit_once(':')
+                        .and_then(|(file, line)| Some((file, line.parse().ok()?)))
+                        .ok_or_else(|| error(&format!("invalid location `{}`", location)))?;
+
+                    info.add_line(LineInfo {
+                        address,
+                        size,
+                        file: file.to_string(),
+                        line,
+                    });
+                }
+                _ => return Err(error(&format!("unknown entry `{}`", kind))),
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+impl std::fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (address, name) in self.symbols.iter() {
+            writeln!(f, "symbol 0x{:04X} {}", address, name)?;
+        }
+        for line in &self.lines {
+            writeln!(f, "line 0x{:04X} {} {}:{}", line.address, line.size, line.file, line.line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug_info::{DebugInfo, LineInfo};
+
+    #[test]
+    fn locations() {
+        let mut info = DebugInfo::new();
+        info.symbols.insert("print_string", 0x0010);
+        info.add_line(LineInfo { address: 0x0014, size: 4, file: "strings.asm".to_string(), line: 17 });
+        info.add_line(LineInfo { address: 0x0010, size: 4, file: "strings.asm".to_string(), line: 16 });
+
+        assert_eq!(info.format_address(0x0014), "print_string+0x04 (strings.asm:17)");
+        assert_eq!(info.format_address(0x0013), "print_string+0x03 (strings.asm:16)");
+        assert_eq!(info.format_address(0x0018), "print_string+0x08");
+        assert_eq!(info.format_address(0x0002), "0x0002");
+
+        let text = info.to_string();
+        assert_eq!(text.parse::<DebugInfo>().unwrap(), info);
+        assert!("line 0x0000 4 nowhere".parse::<DebugInfo>().is_err());
+        assert!("frame 0x0000 x".parse::<DebugInfo>().is_err());
+    }
+}
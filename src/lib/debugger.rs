@@ -0,0 +1,486 @@
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use crate::cpu::{CPU, StopReason};
+use crate::cpu::register::Register;
+use crate::cpu::snapshot::Snapshot;
+use crate::cpu::watchpoint::WatchKind;
+use crate::devices::device::Device;
+use crate::machine::parse_number;
+
+// How many instructions the debugger can step back over
+pub const HISTORY_CAPACITY: usize = 10_000;
+
+const HELP: &str = "\
+commands:
+  s, step [n]            execute n instructions (default 1)
+  c, continue            run until a breakpoint, watchpoint, halt or fault
+  rs [n]                 step back over n instructions (default 1)
+  rc                     run backwards until a breakpoint or a write to a watched address
+  rt <addr>              run backwards until ip is addr
+  b, break <addr>        stop before executing the instruction at addr
+  d, delete <addr>       remove the breakpoint at addr
+  w, watch <addr> [n] [r|w|a]
+                         stop after a read, write or any access to the n bytes at addr
+                         (default 2 bytes, writes)
+  unwatch <addr>         remove the watchpoints starting at addr
+  r, regs                print the registers
+  bt, backtrace          print the subroutine calls leading to ip
+  x <addr> [n]           print n bytes of memory starting at addr (default 16)
+  dis [addr] [n]         disassemble n instructions starting at addr (default ip, 5)
+  set <reg|addr> <value> set a register to a word or a memory address to a byte
+  save <file>            write a snapshot of the machine to file
+  load <file>            restore the machine from a snapshot file
+  reset                  reset the machine and start again from the reset vector
+  h, help                print this message
+  q, quit                leave the debugger
+an empty line repeats the last command";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Step(usize),
+    Continue,
+    ReverseStep(usize),
+    ReverseContinue,
+    RunBackTo(u16),
+    Break(u16),
+    Delete(u16),
+    Watch(usize, usize, WatchKind),
+    Unwatch(usize),
+    Registers,
+    Backtrace,
+    Examine(usize, usize),
+    Disassemble(Option<usize>, usize),
+    SetRegister(Register, u16),
+    SetMemory(usize, u8),
+    Save(String),
+    Load(String),
+    Reset,
+    Help,
+    Quit,
+}
+
+fn number(word: Option<&str>) -> Result<usize, String> {
+    let word = word.ok_or("missing argument")?;
+    parse_number(word)
+        .ok_or_else(|| format!("invalid number `{}`", word))
+}
+
+fn address(word: Option<&str>) -> Result<u16, String> {
+    let value = number(word)?;
+    u16::try_from(value)
+        .map_err(|_| format!("invalid address `{}`", word.unwrap_or_default()))
+}
+
+fn number_or(word: Option<&str>, default: usize) -> Result<usize, String> {
+    match word {
+        Some(_) => number(word),
+        None => Ok(default),
+    }
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().ok_or("empty command")?;
+
+        let command = match name {
+            "s" | "step" => Command::Step(number_or(words.next(), 1)?),
+            "c" | "continue" => Command::Continue,
+            "rs" => Command::ReverseStep(number_or(words.next(), 1)?),
+            "rc" => Command::ReverseContinue,
+            "rt" => Command::RunBackTo(address(words.next())?),
+            "b" | "break" => Command::Break(address(words.next())?),
+            "d" | "delete" => Command::Delete(address(words.next())?),
+            "w" | "watch" => {
+                let address = number(words.next())?;
+                let n = number_or(words.next(), 2)?;
+                let kind = match words.next() {
+                    Some("r") => WatchKind::Read,
+                    Some("w") | None => WatchKind::Write,
+                    Some("a") => WatchKind::Access,
+                    Some(kind) => return Err(format!("unknown watch kind `{}`, expected r, w or a", kind)),
+                };
+                Command::Watch(address, n.max(1), kind)
+            }
+            "unwatch" => Command::Unwatch(number(words.next())?),
+            "r" | "regs" => Command::Registers,
+            "bt" | "backtrace" => Command::Backtrace,
+            "x" => Command::Examine(number(words.next())?, number_or(words.next(), 16)?),
+            "dis" => {
+                let address = words.next().map(|w| number(Some(w))).transpose()?;
+                Command::Disassemble(address, number_or(words.next(), 5)?)
+            }
+            "set" => {
+                let target = words.next().ok_or("missing argument")?;
+                let value = number(words.next())?;
+                match target.parse::<Register>() {
+                    Ok(register) => Command::SetRegister(register, value as u16),
+                    Err(_) => Command::SetMemory(number(Some(target))?, value as u8),
+                }
+            }
+            "save" => Command::Save(words.next().ok_or("missing file")?.to_string()),
+            "load" => Command::Load(words.next().ok_or("missing file")?.to_string()),
+            "reset" => Command::Reset,
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => return Err(format!("unknown command `{}`, try `help`", name)),
+        };
+
+        if let Some(extra) = words.next() {
+            return Err(format!("unexpected `{}`", extra));
+        }
+
+        Ok(command)
+    }
+}
+
+pub struct Debugger<T>
+where
+    T: Device
+{
+    cpu: CPU<T>,
+    halted: bool,
+}
+
+impl<T> Debugger<T>
+where
+    T: Device
+{
+    pub fn new(mut cpu: CPU<T>) -> Self {
+        cpu.enable_history(HISTORY_CAPACITY);
+
+        Self {
+            cpu,
+            halted: false,
+        }
+    }
+
+    pub fn cpu(&self) -> &CPU<T> {
+        &self.cpu
+    }
+
+    pub fn into_cpu(self) -> CPU<T> {
+        self.cpu
+    }
+
+    // Executes one instruction, returning why the machine stopped if it did
+    fn step_once(&mut self) -> Option<String> {
+        if self.halted {
+            return Some("the machine has halted".to_string());
+        }
+
+        match self.cpu.step() {
+            Ok(None) => None,
+            Ok(Some(StopReason::Halt)) => {
+                self.halted = true;
+                Some("halted".to_string())
+            }
+            Ok(Some(StopReason::Breakpoint(address))) => Some(format!("breakpoint at 0x{:04X}", address)),
+            Ok(Some(StopReason::Watchpoint { addr, kind })) => Some(format!("{:?} watchpoint hit at 0x{:04X}", kind, addr)),
+            Err(e) => {
+                self.halted = true;
+                Some(format!(
+                    "fault in {}: {:?}\n{}",
+                    self.cpu.describe_address(self.cpu.instruction_address()),
+                    e,
+                    self.cpu.backtrace().format_with(&|a| self.cpu.describe_address(a)),
+                ))
+            }
+        }
+    }
+
+    fn print_current(&self) {
+        let ip = self.cpu.get_register(Register::Ip) as usize;
+        match self.cpu.disassemble_at(ip) {
+            Some(instruction) => println!("{}", instruction),
+            None => println!("0x{:04X}: <unreadable>", ip),
+        }
+    }
+
+    // Returns false once the debugger should exit
+    pub fn execute(&mut self, command: &Command) -> bool {
+        match command {
+            Command::Step(n) => {
+                for _ in 0..*n {
+                    if let Some(reason) = self.step_once() {
+                        println!("{}", reason);
+                        break;
+                    }
+                }
+                self.print_current();
+            }
+
+            Command::Continue => {
+                loop {
+                    if let Some(reason) = self.step_once() {
+                        println!("{}", reason);
+                        break;
+                    }
+                }
+                self.print_current();
+            }
+
+            Command::ReverseStep(n) => {
+                for _ in 0..*n {
+                    if self.cpu.step_back().is_none() {
+                        println!("reached the start of the history");
+                        break;
+                    }
+                    self.halted = false;
+                }
+                self.print_current();
+            }
+
+            Command::ReverseContinue => {
+                match self.cpu.reverse_continue() {
+                    Some(StopReason::Breakpoint(address)) => println!("breakpoint at 0x{:04X}", address),
+                    Some(StopReason::Watchpoint { addr, .. }) => println!("undid a write to 0x{:04X}", addr),
+                    _ => println!("reached the start of the history"),
+                }
+                self.halted = false;
+                self.print_current();
+            }
+
+            Command::RunBackTo(address) => {
+                if !self.cpu.run_back_to(*address) {
+                    println!("reached the start of the history");
+                }
+                self.halted = false;
+                self.print_current();
+            }
+
+            Command::Break(address) => {
+                self.cpu.add_breakpoint(*address);
+            }
+
+            Command::Delete(address) => {
+                if !self.cpu.remove_breakpoint(*address) {
+                    println!("no breakpoint at 0x{:04X}", address);
+                }
+            }
+
+            Command::Watch(address, n, kind) => {
+                self.cpu.add_watchpoint(*address, address + n - 1, *kind);
+            }
+
+            Command::Unwatch(address) => {
+                let watchpoints: Vec<_> = self.cpu.watchpoints().iter()
+                    .filter(|w| w.start == *address)
+                    .copied()
+                    .collect();
+                for w in watchpoints {
+                    self.cpu.remove_watchpoint(w.start, w.end, w.kind);
+                }
+            }
+
+            Command::Registers => {
+                self.cpu.debug();
+                println!("{} instructions, {} cycles", self.cpu.instructions_executed(), self.cpu.cycles());
+            }
+
+            Command::Backtrace => {
+                print!("{}", self.cpu.backtrace());
+            }
+
+            Command::Examine(address, n) => {
+                if *n > 0 && self.cpu.view_memory_at(*address, n - 1).is_err() {
+                    println!("can't read memory at 0x{:04X}", address);
+                }
+            }
+
+            Command::Disassemble(address, n) => {
+                let mut address = address.unwrap_or(self.cpu.get_register(Register::Ip) as usize);
+                for _ in 0..*n {
+                    match self.cpu.disassemble_at(address) {
+                        Some(instruction) => {
+                            println!("{}", instruction);
+                            address += instruction.bytes.len();
+                        }
+                        None => {
+                            println!("0x{:04X}: <unreadable>", address);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            Command::SetRegister(register, value) => {
+                self.cpu.set_register(*register, *value);
+            }
+
+            Command::SetMemory(address, value) => {
+                if !self.cpu.poke_u8(*address, *value) {
+                    println!("can't write memory at 0x{:04X}", address);
+                }
+            }
+
+            Command::Save(path) => {
+                if let Err(e) = self.cpu.snapshot().save(path) {
+                    println!("can't save snapshot: {}", e);
+                }
+            }
+
+            Command::Load(path) => {
+                match Snapshot::load(path) {
+                    Ok(snapshot) => {
+                        if self.cpu.restore(&snapshot).is_err() {
+                            println!("the snapshot doesn't match this machine");
+                        } else {
+                            self.halted = false;
+                            self.print_current();
+                        }
+                    }
+                    Err(e) => println!("can't load snapshot: {}", e),
+                }
+            }
+
+            Command::Reset => {
+                match self.cpu.reset() {
+                    Ok(()) => {
+                        self.halted = false;
+                        self.print_current();
+                    }
+                    Err(e) => println!("can't reset: {:?}", e),
+                }
+            }
+
+            Command::Help => {
+                println!("{}", HELP);
+            }
+
+            Command::Quit => {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn run<R: BufRead>(&mut self, input: R) {
+        self.print_current();
+
+        let mut last_command: Option<Command> = None;
+        let mut lines = input.lines();
+        loop {
+            print!("(mayo) ");
+            io::stdout().flush()
+                .unwrap();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+
+            let command = if line.trim().is_empty() {
+                match &last_command {
+                    Some(command) => Ok(command.clone()),
+                    None => continue,
+                }
+            } else {
+                line.parse::<Command>()
+            };
+
+            match command {
+                Ok(command) => {
+                    if !self.execute(&command) {
+                        break;
+                    }
+                    last_command = Some(command);
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::cpu::instructions::*;
+    use crate::cpu::register::Register;
+    use crate::cpu::watchpoint::WatchKind;
+    use crate::debugger::{Command, Debugger};
+    use crate::devices::device::Device;
+    use crate::devices::memory::Memory;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("s".parse(), Ok(Command::Step(1)));
+        assert_eq!("step 0x10".parse(), Ok(Command::Step(16)));
+        assert_eq!("b 0x3000".parse(), Ok(Command::Break(0x3000)));
+        assert_eq!("dis".parse(), Ok(Command::Disassemble(None, 5)));
+        assert_eq!("rt 4".parse(), Ok(Command::RunBackTo(4)));
+        assert_eq!("set acc 5".parse(), Ok(Command::SetRegister(Register::Acc, 5)));
+        assert_eq!("set 0x20 0xAB".parse(), Ok(Command::SetMemory(0x20, 0xAB)));
+        assert_eq!("w 0x20 1 a".parse(), Ok(Command::Watch(0x20, 1, WatchKind::Access)));
+        assert_eq!("reset".parse(), Ok(Command::Reset));
+        assert!("step 1 2".parse::<Command>().is_err());
+        assert!("jump".parse::<Command>().is_err());
+        assert_eq!("b 0x10000".parse::<Command>(), Err("invalid address `0x10000`".to_string()));
+        assert!("rt 65536".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn breakpoints_and_watches() {
+        let mut program = vec![
+            MOV_LIT_REG, 0x00, 0x01, 2,
+            MOV_LIT_REG, 0x00, 0x02, 3,
+            MOV_REG_MEM, 3, 0x00, 0x80,
+            HLT,
+        ];
+        program.resize(0x100, 0);
+        let mut debugger = Debugger::new(CPU::new(Memory::from_vec(program)));
+
+        debugger.execute(&Command::Break(0x0004));
+        debugger.execute(&Command::Watch(0x80, 2, WatchKind::Write));
+
+        debugger.execute(&Command::Continue);
+        assert_eq!(debugger.cpu().get_register(Register::Ip), 0x0004);
+
+        debugger.execute(&Command::Continue);
+        assert_eq!(debugger.cpu().get_register(Register::Ip), 0x000C);
+        assert_eq!(debugger.cpu().memory().read_at_u16(0x80), Some(0x0002));
+
+        debugger.execute(&Command::Continue);
+        assert!(debugger.halted);
+
+        debugger.execute(&Command::ReverseContinue);
+        assert_eq!(debugger.cpu().get_register(Register::Ip), 0x0008);
+        assert_eq!(debugger.cpu().memory().read_at_u16(0x80), Some(0x0000));
+        assert!(!debugger.halted);
+
+        debugger.execute(&Command::SetRegister(Register::R1, 0x1234));
+        assert_eq!(debugger.cpu().get_register(Register::R1), 0x1234);
+    }
+
+    #[test]
+    fn virtual_addresses() {
+        let mut memory = vec![
+            PTB_LIT, 0x00, 0x01,
+            PGE,
+            HLT,
+        ];
+        memory.resize(0x100, 0);
+        // Page 0 is mapped to itself so the program carries on, page 0x20 to frame 2
+        memory.extend([0x00, 0x00, 0x00, 0x0F]);
+        memory.resize(0x100 + 0x20 * 4, 0);
+        memory.extend([0x00, 0x02, 0x00, 0x07]);
+        memory.resize(0x200, 0);
+        memory.extend([PSH_LIT, 0x12, 0x34]);
+        memory.resize(0x4000, 0);
+        let mut debugger = Debugger::new(CPU::new(Memory::from_vec(memory)));
+        debugger.execute(&Command::Continue);
+
+        debugger.execute(&Command::SetMemory(0x2001, 0xBE));
+        debugger.execute(&Command::SetMemory(0x3000, 0xAA));
+        assert_eq!(debugger.cpu().memory().read_at_u8(0x201), Some(0xBE));
+        // 0x3000 isn't mapped, so the physical byte there is left alone
+        assert_eq!(debugger.cpu().memory().read_at_u8(0x3000), Some(0));
+
+        assert_eq!(debugger.cpu().disassemble_at(0x2000).unwrap().text, "psh $BE34");
+        assert!(debugger.cpu().view_memory_at(0x2000, 2).is_ok());
+        assert!(debugger.cpu().view_memory_at(0x3000, 0).is_err());
+    }
+}
@@ -1,7 +1,34 @@
+// The saved state doesn't fit the device, like state saved on a machine with a different layout
+#[derive(Debug, PartialEq, Eq)]
+pub struct StateMismatch;
+
 pub trait Device {
     fn read_at_u8(&self, offset: usize) -> Option<u8>;
     fn read_at_u16(&self, offset: usize) -> Option<u16>;
 
+    #[allow(clippy::result_unit_err)]
     fn write_at_u8(&mut self, offset: usize, num: u8) -> Result<(), ()>;
+    #[allow(clippy::result_unit_err)]
     fn write_at_u16(&mut self, offset: usize, num: u16) -> Result<(), ()>;
+
+    // Everything restore_state needs to put the device back the way it is now.
+    // Devices without any state can keep the defaults
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn restore_state(&mut self, _state: &[u8]) -> Result<(), StateMismatch> {
+        Ok(())
+    }
+
+    // Whether the CPU has to be in supervisor mode to access the address
+    fn is_supervisor_only(&self, _offset: usize) -> bool {
+        false
+    }
+
+    // Called by CPU::reset, RAM-like devices keep their contents like real hardware would
+    fn reset(&mut self) {}
+
+    // Called by the CPU after each instruction with the cycles it took, so devices that keep time stay in step with it
+    fn tick(&mut self, _cycles: u64) {}
 }
@@ -0,0 +1,22 @@
+use crate::devices::device::Device;
+
+// Fails every access, mapped by MemoryMapper::map_guard to fence off memory like the end of the stack
+pub struct GuardDevice;
+
+impl Device for GuardDevice {
+    fn read_at_u8(&self, _offset: usize) -> Option<u8> {
+        None
+    }
+
+    fn read_at_u16(&self, _offset: usize) -> Option<u16> {
+        None
+    }
+
+    fn write_at_u8(&mut self, _offset: usize, _num: u8) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn write_at_u16(&mut self, _offset: usize, _num: u16) -> Result<(), ()> {
+        Err(())
+    }
+}
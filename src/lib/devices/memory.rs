@@ -1,6 +1,6 @@
 use data_view::View;
 use crate::create_memory::create_memory;
-use crate::devices::device::Device;
+use crate::devices::device::{Device, StateMismatch};
 
 pub struct Memory {
     internal_mem: Vec<u8>
@@ -36,4 +36,17 @@ impl Device for Memory {
     fn write_at_u16(&mut self, offset: usize, num: u16) -> Result<(), ()> {
         self.internal_mem.write_at::<u16>(offset, num)
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.internal_mem.clone()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), StateMismatch> {
+        if state.len() != self.internal_mem.len() {
+            return Err(StateMismatch);
+        }
+
+        self.internal_mem.copy_from_slice(state);
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 use std::collections::VecDeque;
-use crate::devices::device::Device;
+use crate::devices::device::{Device, StateMismatch};
+use crate::devices::guard::GuardDevice;
 
 struct Region {
     device: Box<dyn Device>,
@@ -8,31 +9,58 @@ struct Region {
     end: usize,
 
     remap: bool,
+    supervisor_only: bool,
 }
 
+impl Region {
+    fn final_address(&self, address: usize) -> usize {
+        if self.remap {
+            address - self.start
+        } else {
+            address
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct MemoryMapper {
     regions: VecDeque<Region>,
 }
 
 impl MemoryMapper {
-    pub fn new() -> Self<> {
+    pub fn new() -> Self {
         Self {
             regions: VecDeque::new(),
         }
     }
 
     pub fn map(&mut self, device: Box<dyn Device>, start: usize, end: usize, remap: bool) {
+        self.map_region(device, start, end, remap, false);
+    }
+
+    // Like map, but user mode code faults when it touches the region
+    pub fn map_supervisor_only(&mut self, device: Box<dyn Device>, start: usize, end: usize, remap: bool) {
+        self.map_region(device, start, end, remap, true);
+    }
+
+    fn map_region(&mut self, device: Box<dyn Device>, start: usize, end: usize, remap: bool, supervisor_only: bool) {
         let region = Region {
             device,
             start,
             end,
             remap,
+            supervisor_only,
         };
         self.regions.push_front(region);
 
         // TODO: add way to remove region from regions list
     }
 
+    // Makes every access between start and end fail, on top of whatever is mapped there
+    pub fn map_guard(&mut self, start: usize, end: usize) {
+        self.map(Box::new(GuardDevice), start, end, false);
+    }
+
     fn find_region(&self, address: usize) -> Option<&Region> {
         self.regions.iter().find(|r| address >= r.start && address <= r.end)
     }
@@ -44,58 +72,80 @@ impl MemoryMapper {
 
 impl Device for MemoryMapper {
     fn read_at_u8(&self, offset: usize) -> Option<u8> {
-        let region = self.find_region(offset)
-            .unwrap();
+        let region = self.find_region(offset)?;
 
-        let final_address;
-        if region.remap {
-            final_address = offset - region.start;
-        } else {
-            final_address = offset;
-        }
+        let final_address = region.final_address(offset);
 
         region.device.read_at_u8(final_address)
     }
 
     fn read_at_u16(&self, offset: usize) -> Option<u16> {
-        let region = self.find_region(offset)
-            .unwrap();
+        let region = self.find_region(offset)?;
 
-        let final_address;
-        if region.remap {
-            final_address = offset - region.start;
-        } else {
-            final_address = offset;
-        }
+        let final_address = region.final_address(offset);
 
         region.device.read_at_u16(final_address)
     }
 
     fn write_at_u8(&mut self, offset: usize, num: u8) -> Result<(), ()> {
         let region = self.find_mut_region(offset)
-            .unwrap();
+            .ok_or(())?;
 
-        let final_address;
-        if region.remap {
-            final_address = offset - region.start;
-        } else {
-            final_address = offset;
-        }
+        let final_address = region.final_address(offset);
 
         region.device.write_at_u8(final_address, num)
     }
 
     fn write_at_u16(&mut self, offset: usize, num: u16) -> Result<(), ()> {
         let region = self.find_mut_region(offset)
-            .unwrap();
+            .ok_or(())?;
 
-        let final_address;
-        if region.remap {
-            final_address = offset - region.start;
-        } else {
-            final_address = offset;
-        }
+        let final_address = region.final_address(offset);
 
         region.device.write_at_u16(final_address, num)
     }
+
+    fn is_supervisor_only(&self, offset: usize) -> bool {
+        self.find_region(offset).is_some_and(|r| r.supervisor_only)
+    }
+
+    fn reset(&mut self) {
+        for region in &mut self.regions {
+            region.device.reset();
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        for region in &mut self.regions {
+            region.device.tick(cycles);
+        }
+    }
+
+    // Each region's state prefixed with its length, in the same order as self.regions
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![];
+        for region in &self.regions {
+            let region_state = region.device.save_state();
+            state.extend((region_state.len() as u32).to_be_bytes());
+            state.extend(region_state);
+        }
+        state
+    }
+
+    fn restore_state(&mut self, mut state: &[u8]) -> Result<(), StateMismatch> {
+        for region in &mut self.regions {
+            let len = state.get(..4).ok_or(StateMismatch)?;
+            let len = u32::from_be_bytes(len.try_into().expect("slice is 4 bytes")) as usize;
+            let region_state = state.get(4..4 + len).ok_or(StateMismatch)?;
+
+            region.device.restore_state(region_state)?;
+            state = &state[4 + len..];
+        }
+
+        if state.is_empty() {
+            Ok(())
+        } else {
+            Err(StateMismatch)
+        }
+    }
 }
@@ -1,4 +1,6 @@
 pub mod device;
+pub mod guard;
 pub mod screen_device;
 pub mod memory_mapper;
 pub mod memory;
+pub mod rom;
@@ -0,0 +1,33 @@
+use data_view::View;
+use crate::devices::device::Device;
+
+// Memory the CPU can read but not write, like the chip firmware is burned into
+pub struct Rom {
+    contents: Vec<u8>,
+}
+
+impl Rom {
+    pub fn from_vec(contents: Vec<u8>) -> Self {
+        Self {
+            contents,
+        }
+    }
+}
+
+impl Device for Rom {
+    fn read_at_u8(&self, offset: usize) -> Option<u8> {
+        self.contents.read_at::<u8>(offset)
+    }
+
+    fn read_at_u16(&self, offset: usize) -> Option<u16> {
+        self.contents.read_at::<u16>(offset)
+    }
+
+    fn write_at_u8(&mut self, _offset: usize, _num: u8) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn write_at_u16(&mut self, _offset: usize, _num: u16) -> Result<(), ()> {
+        Err(())
+    }
+}
@@ -1,11 +1,53 @@
 use console::Term;
-use crate::devices::device::Device;
+use crate::devices::device::{Device, StateMismatch};
 
-pub struct ScreenDevice;
+const COLUMNS: usize = 16;
+const ROWS: usize = 16;
+
+const CLEAR: u16 = 0xFF;
+const BOLD: u16 = 0x01;
+const REGULAR: u16 = 0x02;
+
+pub struct ScreenDevice {
+    // The last word written to each position, kept so the screen can be redrawn after a restore
+    cells: Vec<u16>,
+}
+
+impl Default for ScreenDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl ScreenDevice {
     pub fn new() -> Self {
-        Self
+        Self {
+            cells: vec![0; COLUMNS * ROWS],
+        }
+    }
+
+    fn draw(&self, offset: usize, num: u16) {
+        let command = (num & 0xFF00) >> 8;
+
+        if command == CLEAR {
+            Term::stdout().clear_screen()
+                .unwrap();
+        } else if command == BOLD {
+            set_bold();
+        } else if command == REGULAR {
+            set_regular();
+        }
+
+        let x = offset % COLUMNS;
+        let y = (offset as f64 / COLUMNS as f64).floor() as usize;
+
+        // multiplied by 2 because it looks better
+        Term::stdout().move_cursor_to(x * 2, y)
+            .unwrap();
+
+        let character_value = (num & 0x00FF) as u8;
+
+        print!("{}", character_value as char);
     }
 }
 
@@ -26,32 +68,51 @@ impl Device for ScreenDevice {
         None
     }
 
-    fn write_at_u8(&mut self, _offset: usize, _num: u8) -> Result<(), ()> {
-        Err(())
+    // A character on its own, like the ones block copies write, is drawn regular
+    fn write_at_u8(&mut self, offset: usize, num: u8) -> Result<(), ()> {
+        self.write_at_u16(offset, REGULAR << 8 | num as u16)
     }
 
     fn write_at_u16(&mut self, offset: usize, num: u16) -> Result<(), ()> {
-        let command = (num & 0xFF00) >> 8;
-
-        if command == 0xFF {
-            Term::stdout().clear_screen()
-                .unwrap();
-        } else if command == 0x01 {
-            set_bold();
-        } else if command == 0x02 {
-            set_regular();
+        if (num >> 8) == CLEAR {
+            self.cells.fill(0);
+        }
+        if let Some(cell) = self.cells.get_mut(offset) {
+            *cell = num;
         }
 
-        let x = offset % 16;
-        let y = (offset as f64 / 16.0).floor() as usize;
+        self.draw(offset, num);
 
-        // multiplied by 2 because it looks better
-        Term::stdout().move_cursor_to(x * 2, y)
-            .unwrap();
+        Ok(())
+    }
 
-        let character_value = (num & 0x00FF) as u8;
+    // Only forgets the cells, what's already on the terminal stays until the program clears it
+    fn reset(&mut self) {
+        self.cells.fill(0);
+    }
 
-        print!("{}", character_value as char);
+    fn save_state(&self) -> Vec<u8> {
+        self.cells.iter().flat_map(|c| c.to_be_bytes()).collect()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), StateMismatch> {
+        if state.len() != self.cells.len() * 2 {
+            return Err(StateMismatch);
+        }
+
+        for (cell, bytes) in self.cells.iter_mut().zip(state.chunks_exact(2)) {
+            *cell = u16::from_be_bytes([bytes[0], bytes[1]]);
+        }
+
+        Term::stdout().clear_screen()
+            .unwrap();
+        for (offset, cell) in self.cells.iter().enumerate() {
+            if *cell != 0 {
+                // Don't replay a clear, the screen was cleared above and it would erase the cells drawn before it
+                self.draw(offset, if (cell >> 8) == CLEAR { cell & 0x00FF } else { *cell });
+            }
+        }
+        set_regular();
 
         Ok(())
     }
@@ -0,0 +1,549 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use enum_iterator::{all, cardinality};
+use crate::cpu::{CPU, ExecuteError, StopReason};
+use crate::cpu::supervisor::Trap;
+use crate::cpu::register::Register;
+use crate::cpu::watchpoint::WatchKind;
+use crate::devices::device::Device;
+
+// Signal numbers gdb expects in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+// How many instructions `c` runs between checks for a ctrl-c from gdb
+const INTERRUPT_CHECK_INTERVAL: usize = 1024;
+
+pub trait Connection: Read + Write {
+    // Returns true if gdb sent an interrupt (0x03) while the target was running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = match self.read(&mut byte) {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+pub fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.mayo.core\">\n");
+
+    for (i, register) in all::<Register>().enumerate() {
+        let kind = match register {
+            Register::Ip => "code_ptr",
+            Register::Sp | Register::Fp => "data_ptr",
+            _ => "uint16",
+        };
+        xml.push_str(&format!("    <reg name=\"{}\" bitsize=\"16\" type=\"{}\" regnum=\"{}\"/>\n", register.name(), kind, i));
+    }
+
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+// Parses the `addr,length` arguments shared by the m, M and Z packets
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+enum Packet {
+    Data(String),
+    Interrupt,
+}
+
+pub struct GdbStub<'a, T, C>
+where
+    T: Device,
+    C: Connection
+{
+    cpu: &'a mut CPU<T>,
+    connection: C,
+}
+
+impl<'a, T, C> GdbStub<'a, T, C>
+where
+    T: Device,
+    C: Connection
+{
+    pub fn new(cpu: &'a mut CPU<T>, connection: C) -> Self {
+        Self {
+            cpu,
+            connection,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.connection.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Returns None once gdb closes the connection
+    fn read_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(0x03) => return Ok(Some(Packet::Interrupt)),
+                Some(b'$') => {}
+                // Acks (+/-) and anything outside a packet
+                Some(_) => continue,
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+
+            let mut sum = [0; 2];
+            self.connection.read_exact(&mut sum)?;
+            let sum = std::str::from_utf8(&sum).ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            if sum == Some(checksum(&data)) {
+                self.connection.write_all(b"+")?;
+                return Ok(Some(Packet::Data(String::from_utf8_lossy(&data).into_owned())));
+            }
+
+            self.connection.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.connection.write_all(packet.as_bytes())?;
+        self.connection.flush()
+    }
+
+    fn stop_reply(result: Result<Option<StopReason>, ExecuteError>) -> String {
+        match result {
+            Ok(None) => format!("S{:02x}", SIGTRAP),
+            Ok(Some(StopReason::Halt)) => "W00".to_string(),
+            Ok(Some(StopReason::Breakpoint(_))) => format!("T{:02x}swbreak:;", SIGTRAP),
+            Ok(Some(StopReason::Watchpoint { addr, kind })) => {
+                let name = match kind {
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Write => "watch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+            }
+            Err(ExecuteError::StackOverflow(_) | ExecuteError::StackUnderflow(_) | ExecuteError::MemoryFault(_)
+                | ExecuteError::Trap(Trap::ProtectionFault(_))) => {
+                format!("S{:02x}", SIGSEGV)
+            }
+            Err(ExecuteError::DivideByZero(_)) => format!("S{:02x}", SIGFPE),
+            Err(_) => format!("S{:02x}", SIGILL),
+        }
+    }
+
+    fn single_step(&mut self) -> Result<Option<StopReason>, ExecuteError> {
+        let ip = self.cpu.get_register(Register::Ip);
+        match self.cpu.step() {
+            // Stepping from a breakpoint should execute the instruction under it
+            Ok(Some(StopReason::Breakpoint(address))) if address == ip => self.cpu.step(),
+            result => result,
+        }
+    }
+
+    fn resume(&mut self) -> io::Result<String> {
+        let mut steps: usize = 0;
+        loop {
+            let result = self.cpu.step();
+            if !matches!(result, Ok(None)) {
+                return Ok(Self::stop_reply(result));
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.connection.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    // Addresses are virtual, translated through the current page table like the program's own accesses
+    fn read_memory(&self, address: usize, length: usize) -> String {
+        let mut bytes = vec![];
+        for i in 0..length {
+            let byte = self.cpu.debug_address(address + i)
+                .and_then(|physical| self.cpu.memory().read_at_u8(physical));
+            match byte {
+                Some(byte) => bytes.push(byte),
+                None if bytes.is_empty() => return "E01".to_string(),
+                // gdb accepts a partial read
+                None => break,
+            }
+        }
+        to_hex(&bytes)
+    }
+
+    fn write_memory(&mut self, address: usize, bytes: &[u8]) -> String {
+        for (i, byte) in bytes.iter().enumerate() {
+            let physical = match self.cpu.debug_address(address + i) {
+                Some(physical) => physical,
+                None => return "E01".to_string(),
+            };
+            if self.cpu.memory_mut().write_at_u8(physical, *byte).is_err() {
+                return "E01".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn read_features(&self, args: &str) -> Option<String> {
+        let range = args.strip_prefix("target.xml:")?;
+        let (offset, length) = parse_range(range)?;
+
+        let xml = target_xml();
+
+        let chunk = xml.get(offset.min(xml.len())..(offset + length).min(xml.len()))?;
+        let more = offset + length < xml.len();
+        Some(format!("{}{}", if more { "m" } else { "l" }, chunk))
+    }
+
+    // Changes a breakpoint or watchpoint for the `Z`/`z` packets
+    fn set_point(&mut self, args: &str, insert: bool) -> Option<()> {
+        let (kind, range) = args.split_once(',')?;
+        let (address, length) = parse_range(range)?;
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.cpu.add_breakpoint(address as u16);
+                } else {
+                    self.cpu.remove_breakpoint(address as u16);
+                }
+                return Some(());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return None,
+        };
+
+        let end = address + length.max(1) - 1;
+        if insert {
+            self.cpu.add_watchpoint(address, end, watch_kind);
+        } else {
+            self.cpu.remove_watchpoint(address, end, watch_kind);
+        }
+        Some(())
+    }
+
+    // Returns None for packets we don't support, which gdb expects an empty reply to
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+
+        let reply = match command {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+
+            "g" => {
+                let mut bytes = vec![];
+                for register in all::<Register>() {
+                    bytes.extend(self.cpu.get_register(register).to_be_bytes());
+                }
+                Some(to_hex(&bytes))
+            }
+
+            "G" => from_hex(args).map(|bytes| {
+                // Setting only some of the registers isn't what gdb asked for
+                if bytes.len() != cardinality::<Register>() * 2 {
+                    return "E01".to_string();
+                }
+                for (register, value) in all::<Register>().zip(bytes.chunks_exact(2)) {
+                    self.cpu.set_register(register, u16::from_be_bytes([value[0], value[1]]));
+                }
+                "OK".to_string()
+            }),
+
+            "p" => parse_hex(args).and_then(|n| all::<Register>().nth(n)).map(|register| {
+                to_hex(&self.cpu.get_register(register).to_be_bytes())
+            }),
+
+            "P" => args.split_once('=').and_then(|(n, value)| {
+                let register = all::<Register>().nth(parse_hex(n)?)?;
+                let value = from_hex(value)?;
+                self.cpu.set_register(register, u16::from_be_bytes(value.try_into().ok()?));
+                Some("OK".to_string())
+            }),
+
+            "m" => parse_range(args).map(|(address, length)| self.read_memory(address, length)),
+
+            "M" => args.split_once(':').and_then(|(range, data)| {
+                let (address, _) = parse_range(range)?;
+                Some(self.write_memory(address, &from_hex(data)?))
+            }),
+
+            "s" => Some(Self::stop_reply(self.single_step())),
+
+            "c" => Some(self.resume()?),
+
+            "b" => match args {
+                "s" => Some(match self.cpu.step_back() {
+                    Some(_) => format!("S{:02x}", SIGTRAP),
+                    None => format!("T{:02x}replaylog:begin;", SIGTRAP),
+                }),
+                "c" => Some(match self.cpu.reverse_continue() {
+                    Some(reason) => Self::stop_reply(Ok(Some(reason))),
+                    None => format!("T{:02x}replaylog:begin;", SIGTRAP),
+                }),
+                _ => None,
+            },
+
+            "Z" => Some(self.set_point(args, true).map_or("E01", |_| "OK").to_string()),
+            "z" => Some(self.set_point(args, false).map_or("E01", |_| "OK").to_string()),
+
+            "H" => Some("OK".to_string()),
+
+            "q" => {
+                if packet.starts_with("qSupported") {
+                    Some("PacketSize=4000;qXfer:features:read+;swbreak+;ReverseStep+;ReverseContinue+".to_string())
+                } else if let Some(args) = packet.strip_prefix("qXfer:features:read:") {
+                    Some(self.read_features(args).unwrap_or_else(|| "E00".to_string()))
+                } else if packet == "qAttached" {
+                    Some("1".to_string())
+                } else if packet == "qfThreadInfo" {
+                    Some("m1".to_string())
+                } else if packet == "qsThreadInfo" {
+                    Some("l".to_string())
+                } else if packet == "qC" {
+                    Some("QC1".to_string())
+                } else {
+                    None
+                }
+            }
+
+            _ => None,
+        };
+
+        Ok(reply)
+    }
+
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let packet = match packet {
+                Packet::Data(packet) => packet,
+                Packet::Interrupt => {
+                    self.send(&format!("S{:02x}", SIGINT))?;
+                    continue;
+                }
+            };
+
+            match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                _ => {}
+            }
+
+            let reply = self.handle(&packet)?;
+            self.send(reply.as_deref().unwrap_or(""))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Waits for gdb to connect on `address` and serves it until it detaches
+pub fn listen<T: Device, A: ToSocketAddrs>(cpu: &mut CPU<T>, address: A) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+
+    GdbStub::new(cpu, stream).serve()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use enum_iterator::cardinality;
+    use crate::cpu::CPU;
+    use crate::cpu::instructions::*;
+    use crate::cpu::register::Register;
+    use crate::devices::device::Device;
+    use crate::devices::memory::Memory;
+    use crate::gdb_stub::{checksum, Connection, GdbStub, target_xml};
+
+    struct MockConnection {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConnection {}
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    // Sends each packet to a fresh stub and returns the replies with acks stripped
+    fn session(cpu: &mut CPU<Memory>, packets: &[&str]) -> Vec<String> {
+        let input: String = packets.iter().map(|p| packet(p)).collect();
+        let connection = MockConnection {
+            input: io::Cursor::new(input.into_bytes()),
+            output: vec![],
+        };
+
+        let mut stub = GdbStub::new(cpu, connection);
+        stub.serve().unwrap();
+
+        String::from_utf8(stub.connection.output).unwrap()
+            .split('$')
+            .skip(1)
+            .map(|reply| reply.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn registers_memory_and_breakpoints() {
+        let mut memory = vec![
+            MOV_LIT_REG, 0x12, 0x34, 2,
+            MOV_LIT_REG, 0x56, 0x78, 3,
+            HLT,
+        ];
+        memory.resize(0x100, 0);
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.enable_history(16);
+
+        let replies = session(&mut cpu, &[
+            "?",
+            "m0,4",
+            "Z0,4,1",
+            "c",
+            "p2",
+            "P3=abcd",
+            "M80,2:beef",
+            "m80,2",
+            "s",
+            "c",
+            "bc",
+            "bs",
+            "bs",
+        ]);
+
+        assert_eq!(replies, vec![
+            "S05",
+            "10123402",
+            "OK",
+            "T05swbreak:;",
+            "1234",
+            "OK",
+            "OK",
+            "beef",
+            "S05",
+            "W00",
+            "T05swbreak:;",
+            "S05",
+            "T05replaylog:begin;",
+        ]);
+        // Stepping back over the first instruction undid everything
+        assert_eq!(cpu.get_register(crate::cpu::register::Register::Ip), 0);
+        assert_eq!(cpu.get_register(crate::cpu::register::Register::R1), 0);
+    }
+
+    #[test]
+    fn write_registers() {
+        let mut cpu = CPU::new(Memory::from_num_of_bytes(8));
+        let registers = format!("G{}", "0001".repeat(cardinality::<Register>()));
+
+        let replies = session(&mut cpu, &[&registers, "G0002", "p0"]);
+
+        // A short payload changes nothing
+        assert_eq!(replies, vec!["OK", "E01", "0001"]);
+    }
+
+    #[test]
+    fn virtual_memory() {
+        let mut memory = vec![
+            PTB_LIT, 0x00, 0x01,
+            PGE,
+            HLT,
+        ];
+        memory.resize(0x100, 0);
+        // Page 0 is mapped to itself so the program carries on, page 0x20 to frame 2
+        memory.extend([0x00, 0x00, 0x00, 0x0F]);
+        memory.resize(0x100 + 0x20 * 4, 0);
+        memory.extend([0x00, 0x02, 0x00, 0x07]);
+        memory.resize(0x200, 0);
+        memory.extend([0xBE, 0xEF]);
+        memory.resize(0x300, 0);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.run().unwrap();
+
+        let replies = session(&mut cpu, &["m2000,2", "M2001,1:aa", "m3000,1", "M3000,1:aa"]);
+
+        assert_eq!(replies, vec!["beef", "OK", "E01", "E01"]);
+        assert_eq!(cpu.memory().read_at_u8(0x201), Some(0xAA));
+    }
+
+    #[test]
+    fn target_description() {
+        let mut cpu = CPU::new(Memory::from_num_of_bytes(8));
+        let xml = target_xml();
+        assert!(xml.contains("<reg name=\"fp\" bitsize=\"16\" type=\"data_ptr\" regnum=\"11\"/>"));
+
+        let replies = session(&mut cpu, &["qXfer:features:read:target.xml:0,10"]);
+        // The offset and length are hex
+        assert_eq!(replies, vec![format!("m{}", &xml[..0x10])]);
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use crate::machine::{parse_number, DeviceKind, MachineConfig};
+use crate::object::{Executable, Object, RelocationSize, RelocationTarget, Segment};
+use crate::debug_info::{DebugInfo, LineInfo};
+
+#[derive(Debug)]
+pub enum LinkError {
+    Io(std::io::Error),
+    Script { line: usize, message: String },
+    // A relocation against a symbol no object makes global
+    Undefined { symbol: String, object: String },
+    Duplicate { symbol: String, objects: (String, String) },
+    // No memory left where the section could go
+    NoRoom(String),
+    Overlap(String, String),
+    OutOfRange { object: String, section: String, offset: u16, value: i64 },
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::Io(e) => write!(f, "{}", e),
+            LinkError::Script { line, message } => write!(f, "linker script line {}: {}", line, message),
+            LinkError::Undefined { symbol, object } => write!(f, "{}: `{}` isn't defined by any object", object, symbol),
+            LinkError::Duplicate { symbol, objects } => {
+                write!(f, "`{}` is defined by both {} and {}", symbol, objects.0, objects.1)
+            }
+            LinkError::NoRoom(section) => write!(f, "no room in memory for section {}", section),
+            LinkError::Overlap(a, b) => write!(f, "sections {} and {} overlap", a, b),
+            LinkError::OutOfRange { object, section, offset, value } => {
+                write!(f, "{}: {}+0x{:04X}: 0x{:X} doesn't fit", object, section, offset, value)
+            }
+        }
+    }
+}
+
+// Where sections go: one `<section> [address]` per line. Sections are placed in the order they're listed, each at
+// its address or else after the one before, then sections the script doesn't mention in the order objects have them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkerScript {
+    pub sections: Vec<(String, Option<u16>)>,
+}
+
+impl LinkerScript {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, LinkError> {
+        let text = fs::read_to_string(path)
+            .map_err(LinkError::Io)?;
+        text.parse()
+    }
+}
+
+impl FromStr for LinkerScript {
+    type Err = LinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sections: Vec<(String, Option<u16>)> = vec![];
+
+        for (i, line) in s.lines().enumerate() {
+            let error = |message: String| LinkError::Script { line: i + 1, message };
+
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let mut words = line.split_whitespace();
+            let Some(name) = words.next() else {
+                continue;
+            };
+
+            let address = match words.next() {
+                Some(word) => Some(parse_number(word)
+                    .and_then(|n| u16::try_from(n).ok())
+                    .ok_or_else(|| error(format!("invalid address `{}`", word)))?),
+                None => None,
+            };
+            if let Some(word) = words.next() {
+                return Err(error(format!("unexpected `{}`", word)));
+            }
+            if sections.iter().any(|(n, _)| n == name) {
+                return Err(error(format!("section {} is listed twice", name)));
+            }
+
+            sections.push((name.to_string(), address));
+        }
+
+        Ok(LinkerScript { sections })
+    }
+}
+
+pub struct Linked {
+    // Carries a copy of debug_info
+    pub executable: Executable,
+    // Every label and statement of every object at its final address
+    pub debug_info: DebugInfo,
+}
+
+// The sections with one name from every object, placed one after the other
+struct OutputSection {
+    name: String,
+    fixed: Option<u16>,
+    // (object, section) pairs and their offsets in the output section
+    inputs: Vec<(usize, usize, usize)>,
+    size: usize,
+}
+
+pub struct Linker {
+    objects: Vec<Object>,
+    script: LinkerScript,
+    // Inclusive ranges that sections without an address can be placed in
+    memory: Vec<(usize, usize)>,
+}
+
+impl Default for Linker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self {
+            objects: vec![],
+            script: LinkerScript::default(),
+            memory: vec![(0, 0xFFFF)],
+        }
+    }
+
+    pub fn add_object(&mut self, object: Object) {
+        self.objects.push(object);
+    }
+
+    pub fn set_script(&mut self, script: LinkerScript) {
+        self.script = script;
+    }
+
+    // Only places sections in the machine's memory devices, outside its stack
+    pub fn set_machine(&mut self, config: &MachineConfig) {
+        // Later devices take priority where they overlap, like in MachineBuilder
+        let mut usable = vec![false; 0x10000];
+        for device in &config.devices {
+            let end = device.end.min(0xFFFF);
+            if device.start <= end {
+                usable[device.start..=end].fill(device.kind == DeviceKind::Memory);
+            }
+        }
+        if let Some(stack) = config.stack {
+            usable[stack.limit as usize..=(stack.base as usize + 1).min(0xFFFF)].fill(false);
+        }
+
+        self.memory.clear();
+        let mut start = None;
+        for (address, usable) in usable.iter().chain([&false]).enumerate() {
+            match (start, usable) {
+                (None, true) => start = Some(address),
+                (Some(s), false) => {
+                    self.memory.push((s, address - 1));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn output_sections(&self) -> Vec<OutputSection> {
+        let mut outputs: Vec<OutputSection> = self.script.sections.iter()
+            .map(|(name, address)| OutputSection {
+                name: name.clone(),
+                fixed: *address,
+                inputs: vec![],
+                size: 0,
+            })
+            .collect();
+
+        for (o, object) in self.objects.iter().enumerate() {
+            for (s, section) in object.sections.iter().enumerate() {
+                let output = match outputs.iter().position(|out| out.name == section.name) {
+                    Some(i) => &mut outputs[i],
+                    None => {
+                        outputs.push(OutputSection {
+                            name: section.name.clone(),
+                            fixed: None,
+                            inputs: vec![],
+                            size: 0,
+                        });
+                        outputs.last_mut()
+                            .unwrap()
+                    }
+                };
+                output.inputs.push((o, s, output.size));
+                output.size += section.bytes.len();
+            }
+        }
+
+        outputs
+    }
+
+    // The first address at or after `from` where `size` bytes fit in memory
+    fn fit(&self, from: usize, size: usize) -> Option<usize> {
+        self.memory.iter()
+            .map(|(start, end)| (from.max(*start), *end))
+            .find(|(start, end)| size == 0 || start + size - 1 <= *end)
+            .map(|(start, _)| start)
+    }
+
+    pub fn link(&self) -> Result<Linked, LinkError> {
+        let outputs = self.output_sections();
+
+        // Give every output section an address
+        let mut addresses = vec![];
+        let mut next = self.memory.first().map_or(0, |(start, _)| *start);
+        for output in &outputs {
+            let address = match output.fixed {
+                Some(address) => address as usize,
+                None => self.fit(next, output.size)
+                    .ok_or_else(|| LinkError::NoRoom(output.name.clone()))?,
+            };
+            if address + output.size > 0x10000 {
+                return Err(LinkError::NoRoom(output.name.clone()));
+            }
+            addresses.push(address);
+            next = address + output.size;
+        }
+
+        for (i, a) in outputs.iter().enumerate() {
+            for (j, b) in outputs.iter().enumerate().skip(i + 1) {
+                let (a_start, b_start) = (addresses[i], addresses[j]);
+                if a.size > 0 && b.size > 0 && a_start < b_start + b.size && b_start < a_start + a.size {
+                    return Err(LinkError::Overlap(a.name.clone(), b.name.clone()));
+                }
+            }
+        }
+
+        // Where each section of each object ended up
+        let mut section_addresses: Vec<Vec<usize>> = self.objects.iter()
+            .map(|o| vec![0; o.sections.len()])
+            .collect();
+        for (output, address) in outputs.iter().zip(&addresses) {
+            for (o, s, offset) in &output.inputs {
+                section_addresses[*o][*s] = address + offset;
+            }
+        }
+
+        let mut globals: HashMap<&str, (usize, usize)> = HashMap::new();
+        let mut locals = vec![];
+        let mut debug_info = DebugInfo::new();
+        for (o, object) in self.objects.iter().enumerate() {
+            for symbol in &object.symbols {
+                let address = section_addresses[o][symbol.section] + symbol.offset as usize;
+                // Locals go in after all the globals so a global names an address it shares with a local label
+                if !symbol.global {
+                    locals.push((&symbol.name, address));
+                    continue;
+                }
+                debug_info.symbols.insert(&symbol.name, address as u16);
+
+                if let Some((other, _)) = globals.insert(&symbol.name, (o, address)) {
+                    return Err(LinkError::Duplicate {
+                        symbol: symbol.name.clone(),
+                        objects: (self.objects[other].name.clone(), object.name.clone()),
+                    });
+                }
+            }
+        }
+        for (name, address) in locals {
+            debug_info.symbols.insert(name, address as u16);
+        }
+
+        let mut segments = vec![];
+        for (output, address) in outputs.iter().zip(&addresses) {
+            if output.size == 0 {
+                continue;
+            }
+
+            let mut bytes = Vec::with_capacity(output.size);
+            for (o, s, _) in &output.inputs {
+                let object = &self.objects[*o];
+                let section = &object.sections[*s];
+                let start = bytes.len();
+                bytes.extend(&section.bytes);
+
+                for line in &section.lines {
+                    debug_info.add_line(LineInfo {
+                        address: (address + start) as u16 + line.address,
+                        ..line.clone()
+                    });
+                }
+
+                for relocation in &section.relocations {
+                    let target = match &relocation.target {
+                        RelocationTarget::Section(target) => section_addresses[*o][*target],
+                        RelocationTarget::Symbol(name) => globals.get(name.as_str())
+                            .map(|(_, address)| *address)
+                            .ok_or_else(|| LinkError::Undefined {
+                                symbol: name.clone(),
+                                object: object.name.clone(),
+                            })?,
+                    };
+                    let value = target as i64 + relocation.addend as i64;
+
+                    let out_of_range = || LinkError::OutOfRange {
+                        object: object.name.clone(),
+                        section: section.name.clone(),
+                        offset: relocation.offset,
+                        value,
+                    };
+                    let at = start + relocation.offset as usize;
+                    match relocation.size {
+                        RelocationSize::Byte => {
+                            if !(-0x80..=0xFF).contains(&value) {
+                                return Err(out_of_range());
+                            }
+                            bytes[at] = value as u8;
+                        }
+                        RelocationSize::Word => {
+                            if !(-0x8000..=0xFFFF).contains(&value) {
+                                return Err(out_of_range());
+                            }
+                            bytes[at..at + 2].copy_from_slice(&(value as u16).to_be_bytes());
+                        }
+                    }
+                }
+            }
+
+            segments.push(Segment {
+                address: *address as u16,
+                bytes,
+            });
+        }
+
+        Ok(Linked {
+            executable: Executable {
+                segments,
+                debug_info: Some(debug_info.clone()),
+            },
+            debug_info,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::Assembler;
+    use crate::cpu::instructions::*;
+    use crate::linker::{LinkError, Linker, LinkerScript};
+    use crate::machine::DEFAULT_MACHINE;
+    use crate::object::Object;
+
+    const R1: u8 = 2;
+
+    fn object(name: &str, source: &str) -> Object {
+        let mut assembler = Assembler::new();
+        assembler.add_file(name, source);
+        assembler.assemble_object(name)
+            .unwrap()
+    }
+
+    fn main_object() -> Object {
+        object("main.asm", "
+            .extern print, message
+            .global main
+            main:
+                mov message + 1, r1
+                cal print
+                hlt
+        ")
+    }
+
+    fn print_object() -> Object {
+        object("print.asm", "
+            .global print, message
+            print:
+                mov r1, &screen
+                ret
+            .section .data
+            screen: .word 0
+            message: .asciz \"hi\"
+        ")
+    }
+
+    #[test]
+    fn link() {
+        let mut linker = Linker::new();
+        linker.add_object(main_object());
+        linker.add_object(print_object());
+        linker.set_script("
+            # Code at the start of memory, data at 0x1000
+            .text
+            .data 0x1000
+        ".parse().unwrap());
+        let linked = linker.link().unwrap();
+
+        let segments = &linked.executable.segments;
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].address, 0x0000);
+        assert_eq!(segments[0].bytes, vec![
+            MOV_LIT_REG, 0x10, 0x03, R1,
+            CAL_LIT, 0x00, 0x08,
+            HLT,
+            MOV_REG_MEM, R1, 0x10, 0x00,
+            RET,
+        ]);
+        assert_eq!(segments[1].address, 0x1000);
+        assert_eq!(segments[1].bytes, vec![0, 0, b'h', b'i', 0]);
+        assert_eq!(linked.debug_info.symbols.address_of("print"), Some(0x0008));
+        assert_eq!(linked.debug_info.symbols.address_of("screen"), Some(0x1000));
+    }
+
+    #[test]
+    fn machine_layout() {
+        // Too big to fit below the screen at 0x3000
+        let big = object("big.asm", ".fill 0x2FF0\n.section .data\n.byte 1");
+
+        let mut linker = Linker::new();
+        linker.set_machine(&DEFAULT_MACHINE.parse().unwrap());
+        linker.add_object(big);
+        linker.add_object(main_object());
+        linker.add_object(print_object());
+        let linked = linker.link().unwrap();
+
+        let segments = &linked.executable.segments;
+        assert_eq!(segments[0].address, 0x0000);
+        assert_eq!(segments[0].bytes.len(), 0x2FF0 + 13);
+        assert_eq!(segments[1].address, 0x3100);
+        assert_eq!(linked.debug_info.symbols.address_of("message"), Some(0x3103));
+
+        let mut linker = Linker::new();
+        linker.set_machine(&DEFAULT_MACHINE.parse().unwrap());
+        linker.add_object(object("huge.asm", ".fill 0x5000"));
+        assert!(matches!(linker.link(), Err(LinkError::NoRoom(_))));
+    }
+
+    #[test]
+    fn shared_address_symbols() {
+        // Like a compiled function starting with a loop, which puts a local label at the function's address
+        let mut linker = Linker::new();
+        linker.add_object(object("f.asm", ".global f\nf:\nf.1: jmp &f.1"));
+        let linked = linker.link()
+            .unwrap();
+
+        let symbols = &linked.debug_info.symbols;
+        assert_eq!(symbols.address_of("f"), Some(0));
+        assert_eq!(symbols.address_of("f.1"), Some(0));
+        assert_eq!(symbols.lookup(1), Some(("f", 1)));
+
+        let mut linker = Linker::new();
+        linker.add_object(object("z.asm", ".global z\na:\nz: hlt"));
+        assert_eq!(linker.link().unwrap().debug_info.symbols.format_address(0), "z");
+    }
+
+    #[test]
+    fn errors() {
+        let mut linker = Linker::new();
+        linker.add_object(main_object());
+        match linker.link() {
+            Err(LinkError::Undefined { symbol, object }) => assert_eq!((symbol.as_str(), object.as_str()), ("message", "main.asm")),
+            other => panic!("expected an undefined symbol, got {:?}", other.map(|l| l.executable)),
+        }
+
+        let mut linker = Linker::new();
+        linker.add_object(print_object());
+        linker.add_object(print_object());
+        assert!(matches!(linker.link(), Err(LinkError::Duplicate { .. })));
+
+        let mut linker = Linker::new();
+        linker.add_object(main_object());
+        linker.add_object(print_object());
+        linker.set_script(".text\n.data 0x0004".parse().unwrap());
+        assert!(matches!(linker.link(), Err(LinkError::Overlap(..))));
+
+        let mut linker = Linker::new();
+        linker.add_object(object("far.asm", ".extern far\n.byte far"));
+        linker.add_object(object("far2.asm", ".global far\n.org 0x100\nfar: hlt"));
+        assert!(matches!(linker.link(), Err(LinkError::OutOfRange { .. })));
+
+        assert!(matches!(".text 0x10000".parse::<LinkerScript>(), Err(LinkError::Script { line: 1, .. })));
+        assert!(matches!(".text\n.text".parse::<LinkerScript>(), Err(LinkError::Script { line: 2, .. })));
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use crate::cpu::{CPU, ExecuteError, ResetVector};
+use crate::devices::device::Device;
+use crate::devices::guard::GuardDevice;
+use crate::devices::memory::Memory;
+use crate::devices::memory_mapper::MemoryMapper;
+use crate::devices::rom::Rom;
+use crate::devices::screen_device::ScreenDevice;
+
+pub const DEFAULT_MACHINE: &str = include_str!("../../machines/default.mayo");
+
+#[derive(Debug)]
+pub enum MachineError {
+    Io(std::io::Error),
+    Syntax { line: usize, message: String },
+    Parameter(String),
+    Load(usize),
+    // The reset vector points at memory that can't be read
+    Reset(ExecuteError),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Memory,
+    Screen,
+    Guard,
+    Rom,
+}
+
+impl FromStr for DeviceKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(DeviceKind::Memory),
+            "screen" => Ok(DeviceKind::Screen),
+            "guard" => Ok(DeviceKind::Guard),
+            "rom" => Ok(DeviceKind::Rom),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub kind: DeviceKind,
+    pub start: usize,
+    pub end: usize,
+    pub remap: bool,
+    pub supervisor_only: bool,
+    pub params: HashMap<String, String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StackConfig {
+    pub base: u16,
+    pub limit: u16,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MachineConfig {
+    pub devices: Vec<DeviceConfig>,
+    pub stack: Option<StackConfig>,
+    pub reset: Option<ResetVector>,
+}
+
+pub fn parse_number(text: &str) -> Option<usize> {
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn syntax_error(line: usize, message: String) -> MachineError {
+    MachineError::Syntax { line, message }
+}
+
+impl MachineConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, MachineError> {
+        let text = fs::read_to_string(path)
+            .map_err(MachineError::Io)?;
+        text.parse()
+    }
+
+    fn parse_device(line: usize, text: &str) -> Result<DeviceConfig, MachineError> {
+        let mut words = text.split_whitespace();
+
+        let kind = words.next().unwrap_or_default();
+        let kind = kind.parse::<DeviceKind>()
+            .map_err(|_| syntax_error(line, format!("unknown device kind `{}`", kind)))?;
+
+        let mut address = |name: &str| {
+            let word = words.next()
+                .ok_or_else(|| syntax_error(line, format!("missing {} address", name)))?;
+            parse_number(word)
+                .ok_or_else(|| syntax_error(line, format!("invalid {} address `{}`", name, word)))
+        };
+        let start = address("start")?;
+        let end = address("end")?;
+
+        if end < start {
+            return Err(syntax_error(line, format!("end 0x{:04X} is before start 0x{:04X}", end, start)));
+        }
+
+        let mut remap = false;
+        let mut supervisor_only = false;
+        let mut params = HashMap::new();
+        for word in words {
+            if word == "remap" {
+                remap = true;
+            } else if word == "supervisor" {
+                supervisor_only = true;
+            } else if let Some((key, value)) = word.split_once('=') {
+                params.insert(key.to_string(), value.to_string());
+            } else {
+                return Err(syntax_error(line, format!("unexpected `{}`", word)));
+            }
+        }
+
+        Ok(DeviceConfig {
+            kind,
+            start,
+            end,
+            remap,
+            supervisor_only,
+            params,
+        })
+    }
+
+    fn parse_stack(line: usize, text: &str) -> Result<StackConfig, MachineError> {
+        let mut words = text.split_whitespace().skip(1);
+
+        let mut address = |name: &str| {
+            let word = words.next()
+                .ok_or_else(|| syntax_error(line, format!("missing stack {}", name)))?;
+            parse_number(word)
+                .and_then(|n| u16::try_from(n).ok())
+                .ok_or_else(|| syntax_error(line, format!("invalid stack {} `{}`", name, word)))
+        };
+        let base = address("base")?;
+        let limit = address("limit")?;
+
+        if let Some(word) = words.next() {
+            return Err(syntax_error(line, format!("unexpected `{}`", word)));
+        }
+        if limit > base {
+            return Err(syntax_error(line, format!("stack limit 0x{:04X} is above its base 0x{:04X}", limit, base)));
+        }
+
+        Ok(StackConfig { base, limit })
+    }
+
+    // `reset 0x8000` starts at 0x8000, `reset [0xFFFC]` starts at the address stored at 0xFFFC
+    fn parse_reset(line: usize, text: &str) -> Result<ResetVector, MachineError> {
+        let mut words = text.split_whitespace().skip(1);
+
+        let word = words.next()
+            .ok_or_else(|| syntax_error(line, "missing reset address".to_string()))?;
+        if let Some(word) = words.next() {
+            return Err(syntax_error(line, format!("unexpected `{}`", word)));
+        }
+
+        let (indirect, number) = match word.strip_prefix('[').and_then(|w| w.strip_suffix(']')) {
+            Some(number) => (true, number),
+            None => (false, word),
+        };
+        let address = parse_number(number)
+            .and_then(|n| u16::try_from(n).ok())
+            .ok_or_else(|| syntax_error(line, format!("invalid reset address `{}`", word)))?;
+
+        if indirect {
+            Ok(ResetVector::Indirect(address))
+        } else {
+            Ok(ResetVector::Address(address))
+        }
+    }
+}
+
+impl FromStr for MachineConfig {
+    type Err = MachineError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut devices = vec![];
+        let mut stack = None;
+        let mut reset = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match line.split_whitespace().next() {
+                Some("stack") => {
+                    if stack.is_some() {
+                        return Err(syntax_error(i + 1, "stack is already set".to_string()));
+                    }
+                    stack = Some(Self::parse_stack(i + 1, line)?);
+                }
+                Some("reset") => {
+                    if reset.is_some() {
+                        return Err(syntax_error(i + 1, "reset is already set".to_string()));
+                    }
+                    reset = Some(Self::parse_reset(i + 1, line)?);
+                }
+                _ => devices.push(Self::parse_device(i + 1, line)?),
+            }
+        }
+
+        Ok(MachineConfig { devices, stack, reset })
+    }
+}
+
+impl DeviceConfig {
+    // Without remapping the device sees absolute addresses, so it needs to reach all the way to `end`
+    fn default_size(&self) -> usize {
+        match self.remap {
+            true => self.end - self.start + 1,
+            false => self.end + 1,
+        }
+    }
+
+    fn build(&self) -> Result<Box<dyn Device>, MachineError> {
+        let mut params = self.params.clone();
+        let mut take_number = |key: &str| -> Result<Option<usize>, MachineError> {
+            match params.remove(key) {
+                Some(value) => parse_number(&value)
+                    .map(Some)
+                    .ok_or_else(|| MachineError::Parameter(format!("invalid {} `{}`", key, value))),
+                None => Ok(None),
+            }
+        };
+
+        let device: Box<dyn Device> = match self.kind {
+            DeviceKind::Memory => {
+                let size = take_number("size")?.unwrap_or(self.default_size());
+                Box::new(Memory::from_num_of_bytes(size))
+            }
+            DeviceKind::Screen => Box::new(ScreenDevice::new()),
+            DeviceKind::Guard => Box::new(GuardDevice),
+            DeviceKind::Rom => {
+                let size = take_number("size")?.unwrap_or(self.default_size());
+                let path = params.remove("file")
+                    .ok_or_else(|| MachineError::Parameter("rom needs a file".to_string()))?;
+                let mut contents = fs::read(&path)
+                    .map_err(MachineError::Io)?;
+                if contents.len() > size {
+                    return Err(MachineError::Parameter(format!("{} is bigger than the rom's 0x{:X} bytes", path, size)));
+                }
+                contents.resize(size, 0);
+                Box::new(Rom::from_vec(contents))
+            }
+        };
+
+        if let Some(key) = params.keys().next() {
+            return Err(MachineError::Parameter(format!("unknown parameter `{}` for {:?}", key, self.kind)));
+        }
+
+        Ok(device)
+    }
+}
+
+pub struct MachineBuilder {
+    config: MachineConfig,
+    loads: Vec<(usize, Vec<u8>)>,
+}
+
+impl MachineBuilder {
+    pub fn new(config: MachineConfig) -> Self {
+        Self {
+            config,
+            loads: vec![],
+        }
+    }
+
+    // Copies `bytes` into the machine starting at `address` once its devices are mapped
+    pub fn load(mut self, address: usize, bytes: &[u8]) -> Self {
+        self.loads.push((address, bytes.to_vec()));
+        self
+    }
+
+    pub fn build_memory_mapper(&self) -> Result<MemoryMapper, MachineError> {
+        let mut mm = MemoryMapper::new();
+
+        for device in &self.config.devices {
+            if device.supervisor_only {
+                mm.map_supervisor_only(device.build()?, device.start, device.end, device.remap);
+            } else {
+                mm.map(device.build()?, device.start, device.end, device.remap);
+            }
+        }
+
+        for (address, bytes) in &self.loads {
+            for (i, byte) in bytes.iter().enumerate() {
+                mm.write_at_u8(address + i, *byte)
+                    .map_err(|_| MachineError::Load(address + i))?;
+            }
+        }
+
+        Ok(mm)
+    }
+
+    pub fn build_cpu(&self) -> Result<CPU<MemoryMapper>, MachineError> {
+        let mut cpu = CPU::new(self.build_memory_mapper()?);
+        if let Some(stack) = self.config.stack {
+            cpu.set_stack(stack.base, stack.limit);
+        }
+        cpu.set_reset_vector(self.config.reset.unwrap_or_default());
+        cpu.reset()
+            .map_err(MachineError::Reset)?;
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::ResetVector;
+    use crate::cpu::instructions::*;
+    use crate::cpu::register::Register;
+    use crate::devices::device::Device;
+    use crate::machine::{DEFAULT_MACHINE, DeviceKind, MachineBuilder, MachineConfig, MachineError, StackConfig};
+
+    #[test]
+    fn parse_default_machine() {
+        let config: MachineConfig = DEFAULT_MACHINE.parse().unwrap();
+
+        assert_eq!(config.devices.len(), 2);
+        assert_eq!(config.devices[0].kind, DeviceKind::Memory);
+        assert_eq!(config.devices[0].end, 0xFFFF);
+        assert_eq!(config.devices[0].params.get("size").map(String::as_str), Some("0x10000"));
+        assert_eq!(config.devices[1].kind, DeviceKind::Screen);
+        assert_eq!(config.devices[1].start, 0x3000);
+        assert!(config.devices[1].remap);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = "memory 0 0xFF\nfloppy 0 0xFF".parse::<MachineConfig>().unwrap_err();
+        assert!(matches!(err, MachineError::Syntax { line: 2, .. }));
+
+        let err = "memory 0x10 0x01".parse::<MachineConfig>().unwrap_err();
+        assert!(matches!(err, MachineError::Syntax { line: 1, .. }));
+
+        let config: MachineConfig = "memory 0 0xFF speed=3".parse().unwrap();
+        assert!(MachineBuilder::new(config).build_memory_mapper().is_err());
+    }
+
+    #[test]
+    fn build_and_run() {
+        let config: MachineConfig = "memory 0 0xFF remap\nmemory 0x80 0x8F remap".parse().unwrap();
+
+        let program = [
+            MOV_LIT_REG, 0x12, 0x34, 2,
+            MOV_REG_MEM, 2, 0x00, 0x80,
+            HLT,
+        ];
+        let mut cpu = MachineBuilder::new(config)
+            .load(0, &program)
+            .build_cpu()
+            .unwrap();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.get_register(Register::R1), 0x1234);
+        // The overlay mapped last got the write and the memory under it didn't, each region's state comes after
+        // its length with the last mapped first
+        let state = cpu.memory().save_state();
+        assert_eq!(state[..6], [0x00, 0x00, 0x00, 0x10, 0x12, 0x34]);
+        assert_eq!(state[4 + 0x10..4 + 0x10 + 4], [0x00, 0x00, 0x01, 0x00]);
+        assert_eq!(state[4 + 0x10 + 4 + 0x80..][..2], [0x00, 0x00]);
+
+        // Memory that isn't remapped is sized to reach its end address
+        let mut mm = MachineBuilder::new("memory 0x3000 0x30FF".parse().unwrap())
+            .build_memory_mapper()
+            .unwrap();
+        mm.write_at_u16(0x30FE, 0xBEEF)
+            .unwrap();
+        assert_eq!(mm.read_at_u16(0x30FE), Some(0xBEEF));
+
+        let mm = MachineBuilder::new("memory 0 0xFF remap".parse().unwrap())
+            .load(0x10, &[0xAB])
+            .build_memory_mapper()
+            .unwrap();
+        assert_eq!(mm.read_at_u8(0x10), Some(0xAB));
+    }
+
+    #[test]
+    fn stack_and_guard() {
+        let config: MachineConfig = "memory 0 0xFF\nguard 0x70 0x7F\nstack 0xFE 0x80\nmemory 0x80 0x8F supervisor".parse().unwrap();
+        assert_eq!(config.devices[1].kind, DeviceKind::Guard);
+        assert!(config.devices[2].supervisor_only);
+        assert_eq!(config.stack, Some(StackConfig { base: 0xFE, limit: 0x80 }));
+
+        let cpu = MachineBuilder::new(config).build_cpu().unwrap();
+        assert_eq!(cpu.get_register(Register::Sp), 0xFE);
+        assert_eq!(cpu.stack_bounds(), (0xFE, 0x80));
+        assert_eq!(cpu.memory().read_at_u8(0x70), None);
+        assert!(cpu.memory().is_supervisor_only(0x80));
+        assert!(!cpu.memory().is_supervisor_only(0x90));
+
+        let err = "stack 0x10 0x20".parse::<MachineConfig>().unwrap_err();
+        assert!(matches!(err, MachineError::Syntax { line: 1, .. }));
+        let err = "stack 0xFE 0x80\nstack 0xFE 0x80".parse::<MachineConfig>().unwrap_err();
+        assert!(matches!(err, MachineError::Syntax { line: 2, .. }));
+    }
+
+    #[test]
+    fn boot_from_rom() {
+        let mut firmware = vec![
+            MOV_LIT_REG, 0x12, 0x34, 2,
+            MOV_REG_MEM, 2, 0x00, 0x10,
+            HLT,
+        ];
+        firmware.resize(0xFE, 0);
+        // Reset vector in the last word of the rom
+        firmware.extend([0xFF, 0x00]);
+
+        let path = std::env::temp_dir().join(format!("mayo_rom_test_{}.bin", std::process::id()));
+        std::fs::write(&path, &firmware).unwrap();
+
+        let text = format!("memory 0 0xFF\nrom 0xFF00 0xFFFF remap file={}\nreset [0xFFFE]", path.display());
+        let config: MachineConfig = text.parse().unwrap();
+        assert_eq!(config.reset, Some(ResetVector::Indirect(0xFFFE)));
+
+        let result = MachineBuilder::new(config.clone()).build_cpu();
+        std::fs::remove_file(&path).unwrap();
+        let mut cpu = result.unwrap();
+
+        assert_eq!(cpu.get_register(Register::Ip), 0xFF00);
+        cpu.run().unwrap();
+        assert_eq!(cpu.memory().read_at_u16(0x10), Some(0x1234));
+        assert!(cpu.memory_mut().write_at_u8(0xFF00, 0).is_err());
+
+        let err = "reset 0x10000".parse::<MachineConfig>().unwrap_err();
+        assert!(matches!(err, MachineError::Syntax { line: 1, .. }));
+        let config: MachineConfig = "memory 0 0xFF\nreset [0x1000]".parse().unwrap();
+        assert!(matches!(MachineBuilder::new(config).build_cpu(), Err(MachineError::Reset(_))));
+    }
+}
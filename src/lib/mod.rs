@@ -1,3 +1,14 @@
+pub mod assembler;
+pub mod compiler;
 pub mod create_memory;
+pub mod coverage;
 pub mod cpu;
+pub mod debug_info;
+pub mod debugger;
 pub mod devices;
+pub mod gdb_stub;
+pub mod linker;
+pub mod machine;
+pub mod object;
+pub mod profiler;
+pub mod symbols;
@@ -0,0 +1,419 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use crate::debug_info::{DebugInfo, LineInfo};
+
+const OBJECT_MAGIC: &[u8; 8] = b"MAYOOBJ2";
+const EXECUTABLE_MAGIC: &[u8; 8] = b"MAYOEXE2";
+
+// An assembled file whose sections haven't been given addresses yet, see linker::Linker
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Object {
+    // The file it was assembled from, for error messages
+    pub name: String,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+    // Addresses are offsets in the section
+    pub lines: Vec<LineInfo>,
+}
+
+// A label, at `offset` in section `section` of its object. Only global ones can be used by other objects
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: usize,
+    pub offset: u16,
+    pub global: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RelocationSize {
+    Byte,
+    Word,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelocationTarget {
+    // The address a section of the same object ends up at
+    Section(usize),
+    // A global symbol of any object
+    Symbol(String),
+}
+
+// Bytes at `offset` that the linker fills in with the target's address plus `addend`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u16,
+    pub size: RelocationSize,
+    pub target: RelocationTarget,
+    pub addend: i32,
+}
+
+// A linked program: bytes to load at fixed addresses
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Executable {
+    pub segments: Vec<Segment>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn put_string(bytes: &mut Vec<u8>, text: &str) {
+    bytes.extend((text.len() as u16).to_be_bytes());
+    bytes.extend(text.as_bytes());
+}
+
+fn put_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend((data.len() as u32).to_be_bytes());
+    bytes.extend(data);
+}
+
+// Reads the fields written by the put_ functions, failing with `truncated` if the bytes run out
+struct Reader<'a> {
+    rest: &'a [u8],
+    truncated: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.rest.len() < len {
+            return Err(invalid(self.truncated));
+        }
+        let (taken, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().expect("slice is 2 bytes")))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().expect("slice is 4 bytes")))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| invalid("name isn't UTF-8"))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = OBJECT_MAGIC.to_vec();
+        put_string(&mut bytes, &self.name);
+
+        bytes.extend((self.sections.len() as u16).to_be_bytes());
+        for section in &self.sections {
+            put_string(&mut bytes, &section.name);
+            put_bytes(&mut bytes, &section.bytes);
+
+            bytes.extend((section.relocations.len() as u32).to_be_bytes());
+            for relocation in &section.relocations {
+                bytes.extend(relocation.offset.to_be_bytes());
+                bytes.push(match relocation.size {
+                    RelocationSize::Byte => 1,
+                    RelocationSize::Word => 2,
+                });
+                match &relocation.target {
+                    RelocationTarget::Section(section) => {
+                        bytes.push(0);
+                        bytes.extend((*section as u16).to_be_bytes());
+                    }
+                    RelocationTarget::Symbol(name) => {
+                        bytes.push(1);
+                        put_string(&mut bytes, name);
+                    }
+                }
+                bytes.extend(relocation.addend.to_be_bytes());
+            }
+
+            bytes.extend((section.lines.len() as u32).to_be_bytes());
+            for line in &section.lines {
+                bytes.extend(line.address.to_be_bytes());
+                bytes.extend(line.size.to_be_bytes());
+                put_string(&mut bytes, &line.file);
+                bytes.extend((line.line as u32).to_be_bytes());
+            }
+        }
+
+        bytes.extend((self.symbols.len() as u32).to_be_bytes());
+        for symbol in &self.symbols {
+            put_string(&mut bytes, &symbol.name);
+            bytes.extend((symbol.section as u16).to_be_bytes());
+            bytes.extend(symbol.offset.to_be_bytes());
+            bytes.push(symbol.global as u8);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let rest = bytes.strip_prefix(OBJECT_MAGIC.as_slice())
+            .ok_or_else(|| invalid("not a mayo object"))?;
+        let mut reader = Reader {
+            rest,
+            truncated: "object is truncated",
+        };
+
+        let name = reader.string()?;
+
+        let mut sections = vec![];
+        for _ in 0..reader.u16()? {
+            let name = reader.string()?;
+            let bytes = reader.bytes()?;
+
+            let mut relocations = vec![];
+            for _ in 0..reader.u32()? {
+                let offset = reader.u16()?;
+                let size = match reader.u8()? {
+                    1 => RelocationSize::Byte,
+                    2 => RelocationSize::Word,
+                    _ => return Err(invalid("object has an invalid relocation size")),
+                };
+                let target = match reader.u8()? {
+                    0 => RelocationTarget::Section(reader.u16()? as usize),
+                    1 => RelocationTarget::Symbol(reader.string()?),
+                    _ => return Err(invalid("object has an invalid relocation target")),
+                };
+                let addend = reader.u32()? as i32;
+
+                relocations.push(Relocation { offset, size, target, addend });
+            }
+
+            let mut lines = vec![];
+            for _ in 0..reader.u32()? {
+                lines.push(LineInfo {
+                    address: reader.u16()?,
+                    size: reader.u16()?,
+                    file: reader.string()?,
+                    line: reader.u32()? as usize,
+                });
+            }
+
+            sections.push(Section { name, bytes, relocations, lines });
+        }
+
+        let mut symbols = vec![];
+        for _ in 0..reader.u32()? {
+            symbols.push(Symbol {
+                name: reader.string()?,
+                section: reader.u16()? as usize,
+                offset: reader.u16()?,
+                global: reader.u8()? != 0,
+            });
+        }
+
+        if !reader.rest.is_empty() {
+            return Err(invalid("object has trailing bytes"));
+        }
+
+        let object = Object { name, sections, symbols };
+        let in_section = |section: usize, offset: usize, size: usize| object.sections.get(section)
+            .is_some_and(|s| offset + size <= s.bytes.len());
+        for (i, section) in object.sections.iter().enumerate() {
+            for relocation in &section.relocations {
+                let size = match relocation.size {
+                    RelocationSize::Byte => 1,
+                    RelocationSize::Word => 2,
+                };
+                let target_exists = match relocation.target {
+                    RelocationTarget::Section(target) => target < object.sections.len(),
+                    RelocationTarget::Symbol(_) => true,
+                };
+                if !in_section(i, relocation.offset as usize, size) || !target_exists {
+                    return Err(invalid("object has a relocation outside its sections"));
+                }
+            }
+            if section.lines.iter().any(|l| !in_section(i, l.address as usize, l.size as usize)) {
+                return Err(invalid("object has line info outside its sections"));
+            }
+        }
+        if object.symbols.iter().any(|s| !in_section(s.section, s.offset as usize, 0)) {
+            return Err(invalid("object has a symbol outside its sections"));
+        }
+
+        Ok(object)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+impl Executable {
+    pub fn is_executable(bytes: &[u8]) -> bool {
+        bytes.starts_with(EXECUTABLE_MAGIC)
+    }
+
+    // Everything from address 0 to the end of the last segment with the gaps zeroed, the raw format mayo_bin
+    // also runs
+    pub fn image(&self) -> Vec<u8> {
+        let mut image = vec![];
+        for segment in &self.segments {
+            let start = segment.address as usize;
+            let end = start + segment.bytes.len();
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+            image[start..end].copy_from_slice(&segment.bytes);
+        }
+        image
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = EXECUTABLE_MAGIC.to_vec();
+        bytes.extend((self.segments.len() as u16).to_be_bytes());
+        for segment in &self.segments {
+            bytes.extend(segment.address.to_be_bytes());
+            put_bytes(&mut bytes, &segment.bytes);
+        }
+
+        match &self.debug_info {
+            Some(info) => {
+                bytes.push(1);
+                put_bytes(&mut bytes, info.to_string().as_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let rest = bytes.strip_prefix(EXECUTABLE_MAGIC.as_slice())
+            .ok_or_else(|| invalid("not a mayo executable"))?;
+        let mut reader = Reader {
+            rest,
+            truncated: "executable is truncated",
+        };
+
+        let mut segments = vec![];
+        for _ in 0..reader.u16()? {
+            let address = reader.u16()?;
+            let bytes = reader.bytes()?;
+            if address as usize + bytes.len() > 0x10000 {
+                return Err(invalid("executable segment doesn't fit in memory"));
+            }
+            segments.push(Segment { address, bytes });
+        }
+
+        let debug_info = match reader.u8()? {
+            0 => None,
+            _ => {
+                let text = String::from_utf8(reader.bytes()?)
+                    .map_err(|_| invalid("executable debug info isn't UTF-8"))?;
+                Some(text.parse()
+                    .map_err(|e: String| invalid(&format!("executable debug info is invalid: {}", e)))?)
+            }
+        };
+
+        if !reader.rest.is_empty() {
+            return Err(invalid("executable has trailing bytes"));
+        }
+        Ok(Executable { segments, debug_info })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debug_info::LineInfo;
+    use crate::object::*;
+
+    #[test]
+    fn round_trip() {
+        let object = Object {
+            name: "main.asm".to_string(),
+            sections: vec![
+                Section {
+                    name: ".text".to_string(),
+                    bytes: vec![0x5E, 0x00, 0x00, 0xFF],
+                    relocations: vec![Relocation {
+                        offset: 1,
+                        size: RelocationSize::Word,
+                        target: RelocationTarget::Symbol("print".to_string()),
+                        addend: -2,
+                    }],
+                    lines: vec![LineInfo { address: 0, size: 3, file: "main.asm".to_string(), line: 4 }],
+                },
+                Section {
+                    name: ".data".to_string(),
+                    bytes: vec![0x00],
+                    relocations: vec![Relocation {
+                        offset: 0,
+                        size: RelocationSize::Byte,
+                        target: RelocationTarget::Section(0),
+                        addend: 3,
+                    }],
+                    lines: vec![],
+                },
+            ],
+            symbols: vec![Symbol {
+                name: "main".to_string(),
+                section: 0,
+                offset: 0,
+                global: true,
+            }],
+        };
+
+        let bytes = object.to_bytes();
+        assert_eq!(Object::from_bytes(&bytes).unwrap(), object);
+        assert!(Object::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut outside = object.clone();
+        outside.sections[1].relocations[0].offset = 1;
+        assert!(Object::from_bytes(&outside.to_bytes()).is_err());
+        let mut outside = object.clone();
+        outside.sections[0].lines[0].size = 5;
+        assert!(Object::from_bytes(&outside.to_bytes()).is_err());
+
+        let executable = Executable {
+            segments: vec![
+                Segment { address: 0x0002, bytes: vec![1, 2] },
+                Segment { address: 0x0000, bytes: vec![3] },
+            ],
+            debug_info: Some("symbol 0x0002 main\nline 0x0002 2 main.asm:1".parse().unwrap()),
+        };
+        let bytes = executable.to_bytes();
+        assert!(Executable::is_executable(&bytes));
+        assert_eq!(Executable::from_bytes(&bytes).unwrap(), executable);
+        assert_eq!(executable.image(), vec![3, 0, 1, 2]);
+        assert!(Object::from_bytes(&bytes).is_err());
+    }
+}
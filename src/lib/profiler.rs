@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use crate::cpu::instructions::{CAL_LIT, CAL_REG, RET, SRET};
+use crate::cpu::trace::{TraceEntry, Tracer};
+use crate::symbols::SymbolTable;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddressProfile {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubroutineProfile {
+    pub calls: u64,
+    // Cycles spent in the subroutine's own instructions
+    pub self_cycles: u64,
+    // Cycles spent in the subroutine and everything it called
+    pub total_cycles: u64,
+}
+
+// A Tracer that counts executed instructions per address and attributes their cycles to
+// subroutines by following CAL_LIT/CAL_REG and RET. Trap handlers count as subroutines called
+// when the trap is entered and returned from with SRET
+#[derive(Default)]
+pub struct Profiler {
+    addresses: HashMap<u16, AddressProfile>,
+    subroutines: HashMap<u16, SubroutineProfile>,
+    // Entry addresses of the subroutines currently being executed, outermost first
+    stack: Vec<u16>,
+    // Cycles per distinct call stack, for flamegraphs
+    stacks: HashMap<Vec<u16>, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn addresses(&self) -> &HashMap<u16, AddressProfile> {
+        &self.addresses
+    }
+
+    pub fn subroutines(&self) -> &HashMap<u16, SubroutineProfile> {
+        &self.subroutines
+    }
+
+    fn call(&mut self, address: u16) {
+        self.stack.push(address);
+        self.subroutines.entry(address).or_default().calls += 1;
+    }
+
+    fn name(address: u16, symbols: Option<&SymbolTable>) -> String {
+        match symbols {
+            Some(symbols) => symbols.format_address(address),
+            None => format!("0x{:04X}", address),
+        }
+    }
+
+    pub fn report(&self, symbols: Option<&SymbolTable>) -> String {
+        let mut report = String::new();
+        // Taking an interrupt isn't an instruction at any address, but its cycles are on a stack
+        let total: u64 = self.stacks.values().sum();
+
+        let mut subroutines: Vec<_> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|(address, s)| (std::cmp::Reverse(s.self_cycles), **address));
+
+        report.push_str(&format!("{} cycles in {} instructions\n\n", total, self.addresses.values().map(|a| a.count).sum::<u64>()));
+        report.push_str(&format!("{:>10} {:>7} {:>10} {:>8}  subroutine\n", "self", "self%", "total", "calls"));
+        for (address, s) in subroutines {
+            let percent = if total == 0 { 0.0 } else { s.self_cycles as f64 * 100.0 / total as f64 };
+            report.push_str(&format!("{:>10} {:>6.2}% {:>10} {:>8}  {}\n", s.self_cycles, percent, s.total_cycles, s.calls, Self::name(*address, symbols)));
+        }
+
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by_key(|(address, a)| (std::cmp::Reverse(a.cycles), **address));
+
+        report.push_str(&format!("\n{:>10} {:>10}  address\n", "cycles", "count"));
+        for (address, a) in addresses {
+            report.push_str(&format!("{:>10} {:>10}  {}\n", a.cycles, a.count, Self::name(*address, symbols)));
+        }
+
+        report
+    }
+
+    // One `outer;inner;innermost cycles` line per call stack, the format flamegraph.pl and inferno read
+    pub fn folded(&self, symbols: Option<&SymbolTable>) -> String {
+        let mut lines: Vec<String> = self.stacks.iter()
+            .map(|(stack, cycles)| {
+                let names: Vec<String> = stack.iter().map(|a| Self::name(*a, symbols)).collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+
+        let mut folded = lines.join("\n");
+        folded.push('\n');
+        folded
+    }
+}
+
+impl Tracer for Profiler {
+    fn trace(&mut self, entry: &TraceEntry) {
+        // The first instruction we see is where the program starts
+        if self.stack.is_empty() {
+            self.call(entry.ip);
+        }
+
+        // Entering the handler is all an interrupt step does, so its cycles are the handler's
+        if entry.is_interrupt() {
+            self.call(entry.next_ip);
+        } else {
+            let address = self.addresses.entry(entry.ip).or_default();
+            address.count += !entry.continued as u64;
+            address.cycles += entry.cycles;
+        }
+
+        let current = *self.stack.last().expect("stack isn't empty");
+        self.subroutines.entry(current).or_default().self_cycles += entry.cycles;
+
+        // Count each subroutine once even if it's on the stack several times because of recursion
+        let mut seen = vec![];
+        for subroutine in &self.stack {
+            if !seen.contains(subroutine) {
+                seen.push(*subroutine);
+                self.subroutines.entry(*subroutine).or_default().total_cycles += entry.cycles;
+            }
+        }
+
+        *self.stacks.entry(self.stack.clone()).or_default() += entry.cycles;
+
+        if entry.trap.is_some() {
+            if !entry.is_interrupt() {
+                self.call(entry.next_ip);
+            }
+            return;
+        }
+
+        match entry.opcode {
+            CAL_LIT | CAL_REG => self.call(entry.next_ip),
+            RET | SRET if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::cpu::CPU;
+    use crate::cpu::instructions::*;
+    use crate::devices::memory::Memory;
+    use crate::profiler::Profiler;
+    use crate::symbols::SymbolTable;
+
+    #[test]
+    fn subroutine_hotspots() {
+        let mut memory = vec![
+            PSH_LIT, 0x00, 0x00,
+            CAL_LIT, 0x00, 0x20,
+            PSH_LIT, 0x00, 0x00,
+            CAL_LIT, 0x00, 0x20,
+            HLT,
+        ];
+        memory.resize(0x20, 0);
+        memory.extend([
+            PSH_LIT, 0x00, 0x01,
+            RET,
+        ]);
+        memory.resize(0x100, 0);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_register(crate::cpu::register::Register::Sp, 0xFE);
+        cpu.set_register(crate::cpu::register::Register::Fp, 0xFE);
+
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        cpu.set_tracer(Box::new(profiler.clone()));
+        cpu.run().unwrap();
+
+        let profiler = profiler.borrow();
+        let print = profiler.subroutines()[&0x20];
+        assert_eq!(print.calls, 2);
+        assert_eq!(profiler.addresses()[&0x23].count, 2);
+
+        let start = profiler.subroutines()[&0x00];
+        assert_eq!(start.total_cycles, cpu.cycles());
+        assert_eq!(start.self_cycles + print.self_cycles, cpu.cycles());
+
+        let symbols: SymbolTable = "0x0000 start\n0x0020 push_one".parse().unwrap();
+        let folded = profiler.folded(Some(&symbols));
+        assert_eq!(folded, format!("start {}\nstart;push_one {}\n", start.self_cycles, print.self_cycles));
+        assert!(profiler.report(Some(&symbols)).contains("push_one+0x03"));
+    }
+
+    #[test]
+    fn trap_handlers() {
+        let mut memory = vec![
+            STT_LIT, 0x01, 0x00,
+            STI,
+            SYSCALL, 0x00, 0x02,
+            HLT,
+        ];
+        memory.resize(0x40, 0);
+        // The syscall's handler
+        memory.push(SRET);
+        memory.resize(0x60, 0);
+        // The interrupt's handler
+        memory.push(SRET);
+        memory.resize(0x104, 0);
+        memory.extend([0x00, 0x40, 0x00, 0x60]);
+        memory.resize(0x200, 0);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        cpu.set_register(crate::cpu::register::Register::Sp, 0x1FE);
+        cpu.set_register(crate::cpu::register::Register::Fp, 0x1FE);
+
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        cpu.set_tracer(Box::new(profiler.clone()));
+        cpu.interrupt(1);
+        cpu.run().unwrap();
+
+        let profiler = profiler.borrow();
+        let syscall = profiler.subroutines()[&0x40];
+        let interrupt = profiler.subroutines()[&0x60];
+        assert_eq!(syscall.calls, 1);
+        assert_eq!(interrupt.calls, 1);
+        // The interrupt is taken before the syscall runs, and both handlers return to the start
+        let start = profiler.subroutines()[&0x00];
+        assert_eq!(start.total_cycles, cpu.cycles());
+        assert_eq!(start.self_cycles + syscall.self_cycles + interrupt.self_cycles, cpu.cycles());
+        assert!(interrupt.self_cycles > profiler.addresses()[&0x60].cycles);
+        assert_eq!(profiler.addresses()[&0x04].count, 1);
+        assert!(profiler.report(None).starts_with(&format!("{} cycles in 6 instructions", cpu.cycles())));
+    }
+
+    #[test]
+    fn block_instructions() {
+        let length = (BLOCK_CHUNK_SIZE * 2 + 8) as u8;
+        let mut memory = vec![
+            MOV_LIT_REG, 0x00, 0x80, 2,
+            MOV_LIT_REG, 0x00, length, 3,
+            MSET_REG_REG_REG, 1, 2, 3,
+            HLT,
+        ];
+        memory.resize(0x100, 0);
+
+        let mut cpu = CPU::new(Memory::from_vec(memory));
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        cpu.set_tracer(Box::new(profiler.clone()));
+        cpu.run().unwrap();
+
+        // Every chunk's cycles count, but it's one instruction
+        let profiler = profiler.borrow();
+        assert_eq!(profiler.addresses()[&0x08].count, 1);
+        assert_eq!(cpu.instructions_executed(), 4);
+        assert!(profiler.report(None).starts_with(&format!("{} cycles in 4 instructions", cpu.cycles())));
+    }
+}
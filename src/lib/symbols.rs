@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use crate::machine::parse_number;
+
+// Names for addresses in a guest program, one `<address> <name>` per line when read from a file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    addresses: BTreeMap<String, u16>,
+    // Several labels can share an address, the first one inserted is the one addresses are described with
+    names: BTreeMap<u16, Vec<String>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| e.to_string())?;
+        text.parse()
+    }
+
+    // A name used for more than one address, like local labels from different files, names all of them but
+    // address_of gives the first
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.addresses.entry(name.to_string()).or_insert(address);
+        let names = self.names.entry(address).or_default();
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    // Every symbol in address order, ones at the same address in the order they were inserted
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names.iter().flat_map(|(a, names)| names.iter().map(|n| (*a, n.as_str())))
+    }
+
+    // The closest symbol at or before `address` and how far past it `address` is
+    pub fn lookup(&self, address: u16) -> Option<(&str, u16)> {
+        self.names.range(..=address).next_back()
+            .map(|(a, names)| (names[0].as_str(), address - a))
+    }
+
+    // `name`, `name+0x04`, or just the address if no symbol comes before it
+    pub fn format_address(&self, address: u16) -> String {
+        match self.lookup(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+0x{:02X}", name, offset),
+            None => format!("0x{:04X}", address),
+        }
+    }
+}
+
+impl FromStr for SymbolTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut table = SymbolTable::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (address, name) = line.split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: expected `<address> <name>`", i + 1))?;
+            let address = parse_number(address)
+                .filter(|a| *a <= 0xFFFF)
+                .ok_or_else(|| format!("line {}: invalid address `{}`", i + 1, address))?;
+
+            table.insert(name.trim(), address as u16);
+        }
+
+        Ok(table)
+    }
+}
+
+impl std::fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (address, name) in self.iter() {
+            writeln!(f, "0x{:04X} {}", address, name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::SymbolTable;
+
+    #[test]
+    fn shared_addresses() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("f", 0x10);
+        symbols.insert("f.1", 0x10);
+        symbols.insert("g", 0x20);
+
+        assert_eq!(symbols.address_of("f"), Some(0x10));
+        assert_eq!(symbols.address_of("f.1"), Some(0x10));
+        assert_eq!(symbols.lookup(0x14), Some(("f", 4)));
+        assert_eq!(symbols.format_address(0x10), "f");
+
+        // Files keep every name
+        let text = symbols.to_string();
+        assert_eq!(text, "0x0010 f\n0x0010 f.1\n0x0020 g\n");
+        assert_eq!(text.parse::<SymbolTable>().unwrap(), symbols);
+
+        symbols.insert("g", 0x30);
+        assert_eq!(symbols.address_of("g"), Some(0x20));
+        assert_eq!(symbols.lookup(0x30), Some(("g", 0)));
+    }
+}
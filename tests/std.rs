@@ -0,0 +1,97 @@
+use mayo_lib::assembler::Assembler;
+use mayo_lib::cpu::CPU;
+use mayo_lib::devices::device::Device;
+use mayo_lib::devices::memory::Memory;
+use mayo_lib::linker::Linker;
+use mayo_lib::object::Object;
+
+fn assemble_std() -> Object {
+    let mut assembler = Assembler::new();
+    assembler.add_file("asm/screen.asm", include_str!("../asm/screen.asm"));
+    assembler.add_file("asm/std.asm", include_str!("../asm/std.asm"));
+    assembler.assemble_object("asm/std.asm")
+        .unwrap()
+}
+
+#[test]
+fn prebuilt_object_is_up_to_date() {
+    assert_eq!(assemble_std().to_bytes(), include_bytes!("../asm/std.o"), "asm/std.o is out of date");
+}
+
+#[test]
+fn routines() {
+    let mut assembler = Assembler::new();
+    assembler.add_file("test.asm", "
+        .extern memcpy, memset, strlen, utoa, umulh, udiv, umod, print_at
+
+        %macro call routine, result, a, b, c
+            psh %a
+            psh %b
+            psh %c
+            psh $0003
+            cal %routine
+            mov acc, &results + %result
+        %endmacro
+        %macro call2 routine, result, a, b
+            psh %a
+            psh %b
+            psh $0002
+            cal %routine
+            mov acc, &results + %result
+        %endmacro
+
+        call memcpy, 0, copy, text, 6
+        call memset, 0, copy + 1, 'x', 2
+        psh copy
+        psh $0001
+        cal strlen
+        mov acc, &results
+        call2 utoa, 2, 0x3039, number
+        call2 utoa, 4, 0, zero
+        call2 udiv, 6, 0xC350, 7
+        call2 umod, 8, 0xC350, 7
+        call2 udiv, 10, 0xFFFF, 0x8001
+        call2 umod, 12, 0xFFFF, 0x8001
+        call2 udiv, 14, 1, 0
+        call2 umod, 16, 9, 0
+        call2 umulh, 18, 0xFFFF, 0xFFFF
+        call2 umulh, 20, 0x1234, 0x10
+        call print_at, 22, text, 0x10, 0x01
+        hlt
+
+        .section .data
+        text: .asciz \"hello\"
+        copy: .fill 8, 0xAA
+        number: .fill 8, 0xAA
+        zero: .fill 4, 0xAA
+        results: .fill 24
+    ");
+    let test = assembler.assemble_object("test.asm")
+        .unwrap();
+
+    let mut linker = Linker::new();
+    linker.add_object(test);
+    linker.add_object(assemble_std());
+    let linked = linker.link()
+        .unwrap();
+    let image = linked.executable.image();
+    let address = |name: &str| linked.debug_info.symbols.address_of(name).unwrap() as usize;
+
+    let mut memory = vec![0; 0x10000];
+    memory[..image.len()].copy_from_slice(&image);
+    let mut cpu = CPU::new(Memory::from_vec(memory));
+    cpu.run()
+        .unwrap();
+
+    let memory = cpu.memory();
+    let bytes = |address: usize, len: usize| (0..len).map(|i| memory.read_at_u8(address + i).unwrap()).collect::<Vec<u8>>();
+    let results: Vec<u16> = (0..12).map(|i| memory.read_at_u16(address("results") + i * 2).unwrap()).collect();
+
+    assert_eq!(bytes(address("copy"), 8), b"hxxlo\0\xAA\xAA");
+    assert_eq!(bytes(address("number"), 7), b"12345\0\xAA");
+    assert_eq!(bytes(address("zero"), 3), b"0\0\xAA");
+    assert_eq!(results, [5, 5, 1, 7142, 6, 1, 0x7FFE, 0xFFFF, 9, 0xFFFE, 0x0001, 5]);
+    // Plain memory instead of the screen, so each character's word overlaps the next one's and only the commands and
+    // the last character are left
+    assert_eq!(bytes(0x3010, 7), [0x01, 0x01, 0x01, 0x01, 0x01, 0x6F, 0x00]);
+}